@@ -1,6 +1,10 @@
-use crate::auth::auth_filter;
+use crate::auth::{auth_filter, metrics_filter, metrics_token_filter};
+use crate::config::{Scope, SharedConfig};
 use crate::endpoints;
 use crate::prelude::*;
+use crate::rate_limit::{rate_limit_filter, RateLimiter};
+use crate::readiness::ReadinessCache;
+use crate::request_context::{assign_request_id, request_context, RequestId, X_REQUEST_ID};
 use futures::{FutureExt, TryFutureExt};
 use warp::{
     filters::{
@@ -12,24 +16,36 @@ use warp::{
     Filter,
 };
 
-fn config_filter(config: Arc<Config>) -> impl Fn() -> BoxedFilter<(Arc<Config>,)> {
+fn config_filter(config: SharedConfig) -> impl Fn() -> BoxedFilter<(Arc<Config>,)> {
     move || {
-        let config = Arc::clone(&config);
-        warp::any().map(move || config.clone()).boxed()
+        let config = config.clone();
+        warp::any().map(move || config.current()).boxed()
     }
 }
 
 /// Create a [warp BoxedFilter](warp::filters::BoxedFilter) based on the provided configuration.
-pub fn app(config: Arc<Config>) -> BoxedFilter<(impl warp::Reply,)> {
-    // Authentication if enabled.
-    let auth_filter = config.auth.clone().map(auth_filter);
-    let with_config = config_filter(config);
-
-    let base = if let Some(filter) = auth_filter {
-        warp::any().and(filter).boxed()
-    } else {
-        warp::any().boxed()
+/// Every request uses the configuration that is current when it is received, see
+/// [`SharedConfig`](crate::config::SharedConfig).
+pub fn app(config: impl Into<SharedConfig>) -> BoxedFilter<(impl warp::Reply,)> {
+    let config: SharedConfig = config.into();
+    // Register the metrics, so they are all exposed before their first update.
+    crate::metrics::init();
+    // Authentication if enabled. Each endpoint requires its own scope.
+    let auth_config = config.clone();
+    let authenticated = move |scope| auth_filter(auth_config.clone(), scope);
+    // Rate limits and job quotas of the authenticated client.
+    let limiter = Arc::new(RateLimiter::default());
+    let limits_config = config.clone();
+    let client = move |scope| {
+        rate_limit_filter(
+            Arc::clone(&limiter),
+            limits_config.clone(),
+            authenticated(scope),
+        )
     };
+    let metrics_access = metrics_filter(config.clone());
+    let has_metrics_token = metrics_token_filter(config.clone());
+    let with_config = config_filter(config);
 
     // GET /healthz
     // HEAD /healthz
@@ -38,14 +54,40 @@ pub fn app(config: Arc<Config>) -> BoxedFilter<(impl warp::Reply,)> {
         .and(head().or(get2()).unify())
         .map(|| "OK");
 
+    // GET /readyz
+    let readiness = Arc::new(ReadinessCache::default());
+    let readyz = path("readyz")
+        .and(end())
+        .and(get2())
+        .and(with_config())
+        .and(has_metrics_token)
+        .and_then(move |config, detailed| {
+            endpoints::readyz(Arc::clone(&readiness), config, detailed)
+                .map_err(EndpointError::into_rejection)
+                .boxed()
+                .compat()
+        });
+
+    // GET /metrics
+    let metrics = path("metrics")
+        .and(end())
+        .and(get2())
+        .and(metrics_access)
+        .and_then(|| {
+            crate::metrics::response()
+                .map_err(|cause| EndpointError::InternalServerError { cause }.into_rejection())
+        });
+
     // POST /merge
     let merge = path("merge")
         .and(end())
         .and(post2())
+        .and(client(Scope::Merge))
         .and(json())
         .and(with_config())
-        .and_then(|merge_spec, config| {
-            endpoints::merge(merge_spec, config)
+        .and(request_context())
+        .and_then(|identity, slot, merge_spec, config, context| {
+            endpoints::merge(merge_spec, config, identity, slot, context)
                 .map_err(EndpointError::into_rejection)
                 .boxed()
                 .compat()
@@ -55,10 +97,12 @@ pub fn app(config: Arc<Config>) -> BoxedFilter<(impl warp::Reply,)> {
     let submit = path("submit")
         .and(end())
         .and(post2())
+        .and(client(Scope::Submit))
         .and(json())
         .and(with_config())
-        .and_then(|document_spec, config| {
-            endpoints::submit(document_spec, config)
+        .and(request_context())
+        .and_then(|identity, slot, document_spec, config, context| {
+            endpoints::submit(document_spec, config, identity, slot, context)
                 .map_err(EndpointError::into_rejection)
                 .boxed()
                 .compat()
@@ -68,18 +112,43 @@ pub fn app(config: Arc<Config>) -> BoxedFilter<(impl warp::Reply,)> {
     let preview = path("preview")
         .and(end())
         .and(post2())
+        .and(client(Scope::Preview))
         .and(json())
         .and(with_config())
-        .and_then(|document_spec, config| {
-            endpoints::preview(document_spec, config)
+        .and(request_context())
+        .and_then(|identity, slot, document_spec, config, context| {
+            endpoints::preview(document_spec, config, identity, slot, context)
                 .map_err(EndpointError::into_rejection)
                 .boxed()
                 .compat()
         });
 
-    let routes = merge.or(submit).or(preview);
+    // POST /split
+    let split = path("split")
+        .and(end())
+        .and(post2())
+        .and(client(Scope::Split))
+        .and(json())
+        .and(with_config())
+        .and(request_context())
+        .and_then(|identity, slot, split_spec, config, context| {
+            endpoints::split(split_spec, config, identity, slot, context)
+                .map_err(EndpointError::into_rejection)
+                .boxed()
+                .compat()
+        });
+
+    let routes = merge.or(submit).or(preview).or(split);
+
+    let app = healthz.or(readyz).or(metrics).or(routes).recover(recover);
 
-    healthz.or(base.and(routes)).recover(recover).boxed()
+    // Every response has the ID of its request.
+    assign_request_id()
+        .and(app)
+        .map(|request_id: RequestId, reply| {
+            warp::reply::with_header(reply, X_REQUEST_ID, request_id.to_string())
+        })
+        .boxed()
 }
 
 fn recover(rejection: warp::Rejection) -> Result<Response, warp::Rejection> {
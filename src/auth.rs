@@ -1,9 +1,50 @@
+mod jwt;
+
+use crate::config::{ApiKey, AuthConfig, Scope, SharedConfig, TlsConfig};
 use crate::prelude::*;
+use crate::tls::Connection;
+use slog::{o, Logger};
 use warp::{
-    filters::{header::header, BoxedFilter},
+    filters::{
+        header::{header, optional},
+        BoxedFilter,
+    },
     Filter,
 };
 
+/// The client that made a request.
+#[derive(Debug, Clone, Default)]
+pub struct Identity {
+    /// The name of the API key, if the request was authenticated with one.
+    pub name: Option<String>,
+    /// The `sub` claim, if the request was authenticated with a JWT.
+    pub subject: Option<String>,
+    /// The subject of the client certificate, if the request was authenticated with mutual TLS.
+    pub client_certificate: Option<String>,
+    /// Whether the client has the `trusted_templates` scope.
+    pub trusted_templates: bool,
+}
+
+impl Identity {
+    /// Attach the identity to the logger of a job.
+    pub(crate) fn job_logger(&self, logger: Logger) -> Logger {
+        let logger = match &self.name {
+            Some(name) => logger.new(o!("api_key" => name.clone())),
+            None => logger,
+        };
+
+        let logger = match &self.subject {
+            Some(subject) => logger.new(o!("sub" => subject.clone())),
+            None => logger,
+        };
+
+        match &self.client_certificate {
+            Some(subject) => logger.new(o!("client_cert" => subject.clone())),
+            None => logger,
+        }
+    }
+}
+
 /// Reject with a 403.
 fn reject_forbidden() -> warp::Rejection {
     EndpointError::Forbidden {
@@ -12,6 +53,21 @@ fn reject_forbidden() -> warp::Rejection {
     .into_rejection()
 }
 
+/// Find the key matching `token`. Every key is compared in constant time, and we don't stop at
+/// the first match, so the response time doesn't tell anything about the keys.
+fn find_key<'a>(auth: &'a AuthConfig, token: &str) -> Option<&'a ApiKey> {
+    let hash = openssl::sha::sha256(token.as_bytes());
+    let mut found = None;
+
+    for key in &auth.keys {
+        if openssl::memcmp::eq(&hash, &key.sha256) && found.is_none() {
+            found = Some(key);
+        }
+    }
+
+    found
+}
+
 /// Extract the token from a "Bearer <token>" formatted authorization header.
 fn extract_bearer(header: &str) -> Option<&str> {
     let bearer_re = regex::Regex::new(r"^[Bb]earer (.*)$").unwrap();
@@ -21,21 +77,221 @@ fn extract_bearer(header: &str) -> Option<&str> {
         .map(|capture| capture.as_str())
 }
 
-/// A filter that rejects unauthorized requests based on `secret`.
-pub(crate) fn auth_filter(secret: String) -> BoxedFilter<()> {
-    header("Authorization")
-        .and_then(move |auth_header: String| {
-            let token: Option<&str> = extract_bearer(&auth_header);
+/// Authenticate `token` with a JWT if it looks like one and JWTs are enabled, with an API key
+/// otherwise. Returns the identity of the client and the scopes it has.
+fn authenticate(auth: &AuthConfig, token: &str) -> Result<(Identity, Vec<Scope>), failure::Error> {
+    match &auth.jwt {
+        Some(jwt_config) if jwt::is_jwt(token) => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            let verified = jwt::verify(jwt_config, token, now)?;
+
+            let identity = Identity {
+                name: None,
+                subject: verified.subject,
+                ..Identity::default()
+            };
+
+            Ok((identity, verified.scopes))
+        }
+        _ => {
+            let key = find_key(auth, token).ok_or_else(|| format_err!("Invalid API key"))?;
+
+            let identity = Identity {
+                name: Some(key.name.clone()),
+                ..Identity::default()
+            };
+
+            Ok((identity, key.scopes.clone()))
+        }
+    }
+}
+
+/// Authenticate the Authorization header, and check that the client has `scope`.
+fn check_header(
+    auth: &AuthConfig,
+    auth_header: &str,
+    scope: Scope,
+) -> Result<Identity, warp::Rejection> {
+    let token = extract_bearer(auth_header).ok_or_else(reject_forbidden)?;
+
+    let (identity, scopes) = authenticate(auth, token)
+        .map_err(|cause| EndpointError::Forbidden { cause }.into_rejection())?;
+
+    check_scopes(identity, &scopes, scope)
+}
+
+/// Check that a client with `scopes` has `scope`, and record its other permissions.
+fn check_scopes(
+    mut identity: Identity,
+    scopes: &[Scope],
+    scope: Scope,
+) -> Result<Identity, warp::Rejection> {
+    if !scopes.contains(&scope) {
+        let credentials = match (&identity.name, &identity.client_certificate) {
+            (Some(name), _) => format!("The API key {:?}", name),
+            (None, Some(subject)) => format!("The client certificate {:?}", subject),
+            (None, None) => "The token".to_owned(),
+        };
+
+        return Err(EndpointError::Forbidden {
+            cause: format_err!("{} does not have the {} scope", credentials, scope),
+        }
+        .into_rejection());
+    }
+
+    identity.trusted_templates = scopes.contains(&Scope::TrustedTemplates);
+
+    Ok(identity)
+}
+
+/// How requests are authenticated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AuthMode {
+    /// Every request is let through.
+    Disabled,
+    /// With the Authorization header.
+    Header,
+    /// With the Authorization header or a client certificate.
+    MutualTls,
+}
+
+impl AuthMode {
+    fn of(config: &Config) -> AuthMode {
+        if config.tls.as_ref().map(TlsConfig::mutual).unwrap_or(false) {
+            AuthMode::MutualTls
+        } else if config.auth.is_some() {
+            AuthMode::Header
+        } else {
+            AuthMode::Disabled
+        }
+    }
+}
+
+/// A filter that extracts the current configuration if requests are authenticated in `mode`.
+/// Other requests are rejected as not found, so the rejection of the filter for the actual mode
+/// is the one that ends up in the response.
+fn in_mode(config: SharedConfig, mode: AuthMode) -> BoxedFilter<(Arc<Config>,)> {
+    warp::any()
+        .and_then(move || {
+            let config = config.current();
+
+            if AuthMode::of(&config) == mode {
+                Ok(config)
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .boxed()
+}
+
+/// A filter that rejects requests without a valid API key or JWT, or whose credentials don't
+/// have `scope`. It extracts the identity of the client. When authentication is disabled, every
+/// request is let through. Reloading the configuration can enable or disable authentication, so
+/// the mode is chosen for every request.
+pub(crate) fn auth_filter(config: SharedConfig, scope: Scope) -> BoxedFilter<(Identity,)> {
+    let disabled =
+        in_mode(config.clone(), AuthMode::Disabled).map(|_: Arc<Config>| Identity::default());
+
+    let mutual_tls = in_mode(config.clone(), AuthMode::MutualTls)
+        .and(crate::tls::connection())
+        .and(optional::<String>("Authorization"))
+        .and_then(
+            move |config: Arc<Config>,
+                  connection: Option<Connection>,
+                  auth_header: Option<String>| {
+                mutual_tls_identity(&config, connection, auth_header, scope)
+            },
+        );
+
+    let header = in_mode(config, AuthMode::Header)
+        .and(header("Authorization"))
+        .and_then(move |config: Arc<Config>, auth_header: String| {
+            let auth = config.auth.as_ref().expect("auth is enabled");
+            check_header(auth, &auth_header, scope)
+        });
+
+    disabled.or(mutual_tls).unify().or(header).unify().boxed()
+}
+
+/// A filter for `/metrics`, which is not authenticated with the API keys. It rejects requests
+/// without the metrics token in the Authorization header, if one is configured.
+pub(crate) fn metrics_filter(config: SharedConfig) -> BoxedFilter<()> {
+    warp::any()
+        .and(optional::<String>("Authorization"))
+        .and_then(move |auth_header: Option<String>| {
+            let config = config.current();
 
-            match token {
-                Some(token) if token == secret => Ok(()),
-                _ => Err(reject_forbidden()),
+            if config.metrics_token.is_none() || has_metrics_token(&config, auth_header.as_ref()) {
+                Ok(())
+            } else {
+                Err(reject_forbidden())
             }
         })
         .untuple_one()
         .boxed()
 }
 
+/// A filter that extracts whether the request has the metrics token in its Authorization
+/// header. It is always `false` if no metrics token is configured.
+pub(crate) fn metrics_token_filter(config: SharedConfig) -> BoxedFilter<(bool,)> {
+    warp::any()
+        .and(optional::<String>("Authorization"))
+        .map(move |auth_header: Option<String>| {
+            has_metrics_token(&config.current(), auth_header.as_ref())
+        })
+        .boxed()
+}
+
+/// Whether `auth_header` has the metrics token of `config`, if one is configured.
+fn has_metrics_token(config: &Config, auth_header: Option<&String>) -> bool {
+    let expected = match &config.metrics_token {
+        Some(token) => token,
+        None => return false,
+    };
+
+    let token = match auth_header.and_then(|header| extract_bearer(header)) {
+        Some(token) => token,
+        None => return false,
+    };
+
+    // Compare hashes, so the comparison takes the same time whatever the length of the token.
+    openssl::memcmp::eq(
+        &openssl::sha::sha256(token.as_bytes()),
+        &openssl::sha::sha256(expected.as_bytes()),
+    )
+}
+
+/// With mutual TLS, clients can authenticate with their certificate as well as with the
+/// Authorization header, which wins when both are present. If client certificates are optional
+/// and no keys are configured, clients without a certificate are let through.
+fn mutual_tls_identity(
+    config: &Config,
+    connection: Option<Connection>,
+    auth_header: Option<String>,
+    scope: Scope,
+) -> Result<Identity, warp::Rejection> {
+    let tls = config.tls.as_ref().expect("TLS is enabled");
+    let certificate = connection.and_then(|connection| connection.client_certificate);
+
+    match (&config.auth, auth_header, certificate) {
+        (Some(auth), Some(auth_header), _) => check_header(auth, &auth_header, scope),
+        (_, _, Some(subject)) => {
+            let identity = Identity {
+                client_certificate: Some(subject),
+                ..Identity::default()
+            };
+            check_scopes(identity, &tls.client_scopes, scope)
+        }
+        (None, _, None) => Ok(Identity::default()),
+        (Some(_), None, None) => Err(EndpointError::Forbidden {
+            cause: format_err!("Missing Authorization header or client certificate"),
+        }
+        .into_rejection()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -45,4 +301,19 @@ mod tests {
         assert_eq!("my-secret", extract_bearer("Bearer my-secret").unwrap());
         assert_eq!("my-secret", extract_bearer("bearer my-secret").unwrap());
     }
+
+    #[test]
+    fn keys_are_found_by_hash() {
+        let auth = AuthConfig {
+            keys: vec![
+                ApiKey::from_secret("old".to_owned(), "old-secret", vec![Scope::Submit]),
+                ApiKey::from_secret("new".to_owned(), "new-secret", vec![Scope::Submit]),
+            ],
+            jwt: None,
+        };
+
+        assert_eq!(find_key(&auth, "old-secret").unwrap().name, "old");
+        assert_eq!(find_key(&auth, "new-secret").unwrap().name, "new");
+        assert!(find_key(&auth, "other-secret").is_none());
+    }
 }
@@ -0,0 +1,316 @@
+//! Verification of JWT bearer tokens against the keys from the config.
+//!
+//! Only asymmetric algorithms are accepted: we verify tokens issued elsewhere, and we don't want
+//! to hold a secret that can issue them.
+
+use crate::config::{JwtConfig, JwtKey, Scope};
+use failure::format_err;
+use openssl::hash::MessageDigest;
+use openssl::pkey::Id;
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// The claims we check. The scopes claim is configurable, so it is read from `other`.
+#[derive(Deserialize)]
+struct Claims {
+    exp: Option<u64>,
+    nbf: Option<u64>,
+    aud: Option<Value>,
+    iss: Option<String>,
+    sub: Option<String>,
+    #[serde(flatten)]
+    other: serde_json::Map<String, Value>,
+}
+
+/// A verified token.
+#[derive(Debug, PartialEq)]
+pub(super) struct Verified {
+    /// The `sub` claim.
+    pub subject: Option<String>,
+    /// The endpoints the token can be used for.
+    pub scopes: Vec<Scope>,
+}
+
+/// Whether `token` looks like a JWT rather than an API key: it has three parts, and the first one
+/// is a JSON header with an `alg`. API keys may contain dots too.
+pub(super) fn is_jwt(token: &str) -> bool {
+    if token.matches('.').count() != 2 {
+        return false;
+    }
+
+    let header = token.split('.').next().unwrap_or_default();
+
+    base64::decode_config(header, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|header| serde_json::from_slice::<Header>(&header).ok())
+        .is_some()
+}
+
+/// Verify the signature and the claims of `token`, `now` being the current UNIX time.
+pub(super) fn verify(
+    config: &JwtConfig,
+    token: &str,
+    now: u64,
+) -> Result<Verified, failure::Error> {
+    let mut parts = token.split('.');
+    let (header, payload, signature) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(header), Some(payload), Some(signature)) => (header, payload, signature),
+        _ => return Err(format_err!("Malformed token")),
+    };
+
+    let decode = |part: &str| base64::decode_config(part, base64::URL_SAFE_NO_PAD);
+    let parsed_header: Header = serde_json::from_slice(&decode(header)?)?;
+    let signature = decode(signature)?;
+    let signed = &token.as_bytes()[..header.len() + 1 + payload.len()];
+
+    let (digest, key_type, component_size) = match parsed_header.alg.as_str() {
+        "RS256" => (MessageDigest::sha256(), Id::RSA, None),
+        "RS384" => (MessageDigest::sha384(), Id::RSA, None),
+        "RS512" => (MessageDigest::sha512(), Id::RSA, None),
+        "ES256" => (MessageDigest::sha256(), Id::EC, Some(32)),
+        "ES384" => (MessageDigest::sha384(), Id::EC, Some(48)),
+        other => return Err(format_err!("Unsupported algorithm {:?}", other)),
+    };
+
+    // JWS has raw r || s ECDSA signatures, openssl wants DER.
+    let signature = match component_size {
+        Some(size) if signature.len() == 2 * size => {
+            let r = openssl::bn::BigNum::from_slice(&signature[..size])?;
+            let s = openssl::bn::BigNum::from_slice(&signature[size..])?;
+            openssl::ecdsa::EcdsaSig::from_private_components(r, s)?.to_der()?
+        }
+        Some(_) => return Err(format_err!("Invalid ECDSA signature length")),
+        None => signature,
+    };
+
+    let candidates = config.keys.iter().filter(|key| {
+        key.key.id() == key_type
+            && match (&parsed_header.kid, &key.kid) {
+                (Some(kid), Some(key_kid)) => kid == key_kid,
+                _ => true,
+            }
+    });
+
+    let mut verified = false;
+
+    for key in candidates {
+        if verify_signature(key, digest, signed, &signature)? {
+            verified = true;
+            break;
+        }
+    }
+
+    if !verified {
+        return Err(format_err!("Invalid token signature"));
+    }
+
+    let claims: Claims = serde_json::from_slice(&decode(payload)?)?;
+    check_claims(config, &claims, now)?;
+
+    Ok(Verified {
+        scopes: scopes(claims.other.get(&config.scopes_claim)),
+        subject: claims.sub,
+    })
+}
+
+fn verify_signature(
+    key: &JwtKey,
+    digest: MessageDigest,
+    signed: &[u8],
+    signature: &[u8],
+) -> Result<bool, failure::Error> {
+    let mut verifier = openssl::sign::Verifier::new(digest, &key.key)?;
+    verifier.update(signed)?;
+    // openssl reports malformed signatures as errors, they are just invalid for us.
+    Ok(verifier.verify(signature).unwrap_or(false))
+}
+
+fn check_claims(config: &JwtConfig, claims: &Claims, now: u64) -> Result<(), failure::Error> {
+    match claims.exp {
+        Some(exp) if exp.saturating_add(config.leeway) > now => (),
+        Some(_) => return Err(format_err!("The token has expired")),
+        None => return Err(format_err!("The token has no exp claim")),
+    }
+
+    if let Some(nbf) = claims.nbf {
+        if nbf > now.saturating_add(config.leeway) {
+            return Err(format_err!("The token is not valid yet"));
+        }
+    }
+
+    if let Some(issuer) = &config.issuer {
+        if claims.iss.as_ref() != Some(issuer) {
+            return Err(format_err!("The token has an unexpected issuer"));
+        }
+    }
+
+    if let Some(audience) = &config.audience {
+        let matches = match &claims.aud {
+            Some(Value::String(aud)) => aud == audience,
+            Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+            _ => false,
+        };
+
+        if !matches {
+            return Err(format_err!("The token has an unexpected audience"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the scopes from a space-separated string or an array of strings.
+fn scopes(claim: Option<&Value>) -> Vec<Scope> {
+    let values: Vec<&str> = match claim {
+        Some(Value::String(scopes)) => scopes.split_whitespace().collect(),
+        Some(Value::Array(scopes)) => scopes.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+
+    values
+        .into_iter()
+        .filter_map(|value| value.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use serde_json::json;
+
+    const NOW: u64 = 1_570_000_000;
+
+    fn ec_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn config(key: &PKey<Private>) -> JwtConfig {
+        let public = PKey::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap();
+
+        JwtConfig {
+            keys: vec![JwtKey {
+                kid: Some("key-1".to_owned()),
+                key: public,
+            }],
+            audience: Some("papers".to_owned()),
+            issuer: Some("https://auth.example.com".to_owned()),
+            scopes_claim: "scope".to_owned(),
+            leeway: 60,
+        }
+    }
+
+    /// Sign `claims` with ES256, like an identity provider would.
+    fn sign(key: &PKey<Private>, claims: Value) -> String {
+        let encode = |value: &Value| {
+            base64::encode_config(&serde_json::to_vec(value).unwrap(), base64::URL_SAFE_NO_PAD)
+        };
+        let signed = format!(
+            "{}.{}",
+            encode(&json!({ "alg": "ES256", "kid": "key-1" })),
+            encode(&claims)
+        );
+
+        let mut signer = openssl::sign::Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(signed.as_bytes()).unwrap();
+        let der = signer.sign_to_vec().unwrap();
+
+        let signature = openssl::ecdsa::EcdsaSig::from_der(&der).unwrap();
+        let mut raw = vec![0u8; 64];
+        let r = signature.r().to_vec();
+        let s = signature.s().to_vec();
+        raw[32 - r.len()..32].copy_from_slice(&r);
+        raw[64 - s.len()..].copy_from_slice(&s);
+
+        format!(
+            "{}.{}",
+            signed,
+            base64::encode_config(&raw, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    fn claims() -> Value {
+        json!({
+            "sub": "billing-service",
+            "aud": ["papers", "other"],
+            "iss": "https://auth.example.com",
+            "exp": NOW + 300,
+            "nbf": NOW - 10,
+            "scope": "submit merge openid",
+        })
+    }
+
+    #[test]
+    fn valid_tokens_are_accepted() {
+        let key = ec_key();
+        let token = sign(&key, claims());
+
+        assert!(is_jwt(&token));
+        assert_eq!(
+            verify(&config(&key), &token, NOW).unwrap(),
+            Verified {
+                subject: Some("billing-service".to_owned()),
+                scopes: vec![Scope::Submit, Scope::Merge],
+            }
+        );
+    }
+
+    #[test]
+    fn api_keys_with_dots_are_not_jwts() {
+        assert!(!is_jwt("billing.key.2019"));
+
+        let header = base64::encode_config(br#"{"typ":"JWT"}"#, base64::URL_SAFE_NO_PAD);
+        assert!(!is_jwt(&format!("{}.e30.c2ln", header)));
+    }
+
+    #[test]
+    fn tokens_signed_with_other_keys_are_rejected() {
+        let token = sign(&ec_key(), claims());
+
+        assert!(verify(&config(&ec_key()), &token, NOW).is_err());
+    }
+
+    #[test]
+    fn claims_are_checked() {
+        let key = ec_key();
+        let config = config(&key);
+        let with = |field: &str, value: Value| {
+            let mut claims = claims();
+            claims[field] = value;
+            sign(&key, claims)
+        };
+
+        assert!(verify(&config, &with("exp", json!(NOW - 61)), NOW).is_err());
+        assert!(verify(&config, &with("nbf", json!(NOW + 61)), NOW).is_err());
+        assert!(verify(&config, &with("aud", json!("other")), NOW).is_err());
+        assert!(verify(
+            &config,
+            &with("iss", json!("https://evil.example.com")),
+            NOW
+        )
+        .is_err());
+        assert!(verify(&config, &with("exp", Value::Null), NOW).is_err());
+        assert!(verify(&config, &with("exp", json!(u64::max_value())), NOW).is_ok());
+        assert!(verify(&config, &with("nbf", json!(u64::max_value())), NOW).is_err());
+    }
+
+    #[test]
+    fn symmetric_algorithms_are_rejected() {
+        let key = ec_key();
+        let token = sign(&key, claims());
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let header = base64::encode_config(br#"{"alg":"HS256"}"#, base64::URL_SAFE_NO_PAD);
+        parts[0] = &header;
+
+        assert!(verify(&config(&key), &parts.join("."), NOW).is_err());
+    }
+}
@@ -1,43 +1,135 @@
+mod settings;
+mod shared;
+
+pub use settings::{ConfigErrors, EffectiveSettings};
+pub use shared::SharedConfig;
+
 use crate::human_size::Bytes;
+use crate::utils::logging::{json_drain, LogFormat};
 use rusoto_core::region::Region;
-use slog::{o, warn, Logger};
+use serde::Deserialize;
+use settings::Settings;
+use slog::{o, Drain, Logger};
 use sloggers::types::Severity;
 use sloggers::Build;
+use std::path::Path;
 use std::str::FromStr;
 
-const MAX_ASSET_SIZE_DEFAULT: u32 = 10_000_000;
-const MAX_ASSETS_PER_DOCUMENT_DEFAULT: u32 = 20;
+/// Every setting, by environment variable name. The other keys of the config file are reported
+/// as unknown, even when the settings that would read them are disabled.
+const SETTINGS: &[&str] = &[
+    "PAPERS_ACCESS_KEY_ID",
+    "PAPERS_API_KEYS",
+    "PAPERS_API_KEYS_FILE",
+    "PAPERS_AWS_REGION",
+    "PAPERS_BEARER",
+    "PAPERS_DEBUG_ARCHIVE_BUCKET",
+    "PAPERS_DEBUG_ARCHIVE_EXCLUDE",
+    "PAPERS_DEBUG_ARCHIVE_GZIP",
+    "PAPERS_DEBUG_ARCHIVE_INCLUDE",
+    "PAPERS_DEBUG_ARCHIVE_POLICY",
+    "PAPERS_DEBUG_ARCHIVE_PREFIX",
+    "PAPERS_EGRESS_ALLOWED_HOSTS",
+    "PAPERS_EGRESS_ALLOW_PRIVATE_ADDRESSES",
+    "PAPERS_EGRESS_DENIED_HOSTS",
+    "PAPERS_EGRESS_MAX_REDIRECTS",
+    "PAPERS_EGRESS_SCHEMES",
+    "PAPERS_ICC_PROFILE",
+    "PAPERS_JOB_TIMEOUT",
+    "PAPERS_JWT_AUDIENCE",
+    "PAPERS_JWT_ISSUER",
+    "PAPERS_JWT_JWKS_FILE",
+    "PAPERS_JWT_LEEWAY",
+    "PAPERS_JWT_PUBLIC_KEYS",
+    "PAPERS_JWT_SCOPES_CLAIM",
+    "PAPERS_LOG_FORMAT",
+    "PAPERS_LOG_LEVEL",
+    "PAPERS_MAX_ASSETS_PER_DOCUMENT",
+    "PAPERS_MAX_ASSET_SIZE",
+    "PAPERS_MAX_CONCURRENT_JOBS",
+    "PAPERS_MAX_WORKSPACE_SIZE",
+    "PAPERS_METRICS_TOKEN",
+    "PAPERS_OTLP_ENDPOINT",
+    "PAPERS_PORT",
+    "PAPERS_RATE_LIMIT",
+    "PAPERS_RATE_LIMIT_BURST",
+    "PAPERS_S3_BUCKET",
+    "PAPERS_S3_EXPIRATION_TIME",
+    "PAPERS_SANDBOX",
+    "PAPERS_SANDBOX_BWRAP",
+    "PAPERS_SANDBOX_CPU_SECONDS",
+    "PAPERS_SANDBOX_FILE_SIZE",
+    "PAPERS_SANDBOX_GID",
+    "PAPERS_SANDBOX_MEMORY",
+    "PAPERS_SANDBOX_PROCESSES",
+    "PAPERS_SANDBOX_READ_ONLY_PATHS",
+    "PAPERS_SANDBOX_UID",
+    "PAPERS_SECRET_ACCESS_KEY",
+    "PAPERS_SESSION_TOKEN",
+    "PAPERS_SIGNING_KEYSTORE",
+    "PAPERS_SIGNING_KEYSTORE_PASSWORD",
+    "PAPERS_STEP_TIMEOUT",
+    "PAPERS_TLS_CERT",
+    "PAPERS_TLS_CLIENT_AUTH",
+    "PAPERS_TLS_CLIENT_CA",
+    "PAPERS_TLS_CLIENT_SCOPES",
+    "PAPERS_TLS_KEY",
+    "PAPERS_WORKSPACE_LOG_FORMAT",
+];
 
-fn max_assets_per_document(logger: &Logger) -> u32 {
-    match std::env::var("PAPERS_MAX_ASSETS_PER_DOCUMENT").map(|max| max.parse()) {
-        Ok(Ok(max)) => max,
-        Ok(Err(_)) => {
-            warn!(
-                logger,
-                "Unable to parse PAPERS_MAX_ASSETS_PER_DOCUMENT environment variable"
-            );
-            MAX_ASSETS_PER_DOCUMENT_DEFAULT
-        }
-        _ => MAX_ASSETS_PER_DOCUMENT_DEFAULT,
-    }
-}
+const MAX_ASSET_SIZE_DEFAULT: u64 = 10_000_000;
+const MAX_WORKSPACE_SIZE_DEFAULT: u64 = 1_000_000_000;
+const MAX_ASSETS_PER_DOCUMENT_DEFAULT: u32 = 20;
+const PORT_DEFAULT: u16 = 8080;
+const S3_EXPIRATION_TIME_SECONDS_DEFAULT: u32 = 86400; // one day
+const ICC_PROFILE_DEFAULT: &str = "/usr/share/color/icc/ghostscript/srgb.icc";
+const STEP_TIMEOUT_SECONDS_DEFAULT: u64 = 120;
+const JOB_TIMEOUT_SECONDS_DEFAULT: u64 = 600;
+const JWT_SCOPES_CLAIM_DEFAULT: &str = "scope";
+const JWT_LEEWAY_SECONDS_DEFAULT: u64 = 60;
+const EGRESS_SCHEMES_DEFAULT: &str = "http,https";
+const EGRESS_MAX_REDIRECTS_DEFAULT: usize = 5;
+const SANDBOX_READ_ONLY_PATHS_DEFAULT: &str = "/usr:/lib:/lib64:/bin:/etc/alternatives:/etc/fonts:/etc/texmf:/etc/ImageMagick-6:/etc/ld.so.cache:/var/lib/texmf:/var/cache/fontconfig";
+const SANDBOX_CPU_SECONDS_DEFAULT: u32 = 60;
+const SANDBOX_MEMORY_DEFAULT: u64 = 1_000_000_000;
+const SANDBOX_FILE_SIZE_DEFAULT: u64 = 100_000_000;
+const SANDBOX_PROCESSES_DEFAULT: u32 = 64;
 
 /// Relies on the PAPERS_LOG_LEVEL env variable.
 pub fn build_logger() -> Logger {
-    let minimum_level = if let Ok("debug") = std::env::var("PAPERS_LOG_LEVEL")
-        .as_ref()
-        .map(String::as_str)
-    {
+    let format = std::env::var("PAPERS_LOG_FORMAT")
+        .ok()
+        .and_then(|format| format.parse().ok())
+        .unwrap_or(LogFormat::Text);
+
+    logger(
+        &std::env::var("PAPERS_LOG_LEVEL").unwrap_or_default(),
+        format,
+    )
+}
+
+fn logger(level: &str, format: LogFormat) -> Logger {
+    let minimum_level = if level == "debug" {
         Severity::Debug
     } else {
         Severity::Info
     };
+    let values = o!("version" => env!("CARGO_PKG_VERSION"));
 
-    let drain = sloggers::terminal::TerminalLoggerBuilder::new()
-        .level(minimum_level)
-        .build()
-        .expect("Could not build a terminal logger");
-    slog::Logger::root(drain, o!("version" => env!("CARGO_PKG_VERSION")))
+    match format {
+        LogFormat::Text => {
+            let drain = sloggers::terminal::TerminalLoggerBuilder::new()
+                .level(minimum_level)
+                .build()
+                .expect("Could not build a terminal logger");
+            slog::Logger::root(drain, values)
+        }
+        LogFormat::Json => {
+            let drain = json_drain(std::io::stdout());
+            let drain = slog::LevelFilter::new(drain, minimum_level.as_level()).fuse();
+            slog::Logger::root(drain, values)
+        }
+    }
 }
 
 /// Configuration for the S3 integration.
@@ -52,7 +144,812 @@ pub struct S3Config {
     /// The AWS credentials.
     pub credentials: rusoto_credential::AwsCredentials,
     /// The AWS credentials provider.
-    credentials_provider: rusoto_credential::EnvironmentProvider,
+    credentials_provider: rusoto_credential::StaticProvider,
+}
+
+/// The keystore used to sign documents.
+pub struct SigningConfig {
+    /// The path to the PKCS#12 keystore, with the certificate and the private key.
+    pub keystore: std::path::PathBuf,
+    /// The password of the keystore.
+    password: String,
+}
+
+impl SigningConfig {
+    /// Check that the keystore at `keystore` can be opened with `password`, and that it contains
+    /// a private key and a certificate.
+    pub fn load(
+        keystore: std::path::PathBuf,
+        password: String,
+    ) -> Result<SigningConfig, failure::Error> {
+        use failure::ResultExt;
+
+        let der = std::fs::read(&keystore)
+            .with_context(|_| format!("Could not read the keystore at {:?}", keystore))?;
+        let parsed = openssl::pkcs12::Pkcs12::from_der(&der)
+            .context("The keystore is not a valid PKCS#12 file")?
+            .parse(&password)
+            .context("Could not open the keystore with the provided password")?;
+
+        // Fail early on keystores that would only make signing fail later.
+        parsed.pkey.private_key_to_der()?;
+        parsed.cert.to_der()?;
+
+        Ok(SigningConfig { keystore, password })
+    }
+
+    pub(crate) fn password(&self) -> &str {
+        &self.password
+    }
+}
+
+impl std::fmt::Debug for SigningConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningConfig")
+            .field("keystore", &self.keystore)
+            .field("password", &"[redacted]")
+            .finish()
+    }
+}
+
+/// An endpoint an API key can be allowed to use, or an additional permission.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// `POST /preview`
+    Preview,
+    /// `POST /submit`
+    Submit,
+    /// `POST /merge`
+    Merge,
+    /// `POST /split`
+    Split,
+    /// The TeX sources of the key's jobs are not checked for dangerous primitives. See
+    /// [`latex::check_source`](crate::latex::check_source).
+    #[serde(rename = "trusted_templates")]
+    TrustedTemplates,
+}
+
+impl Scope {
+    /// The scopes of every endpoint, for keys that can use all of them. This doesn't include
+    /// [`TrustedTemplates`](Scope::TrustedTemplates).
+    pub fn all() -> Vec<Scope> {
+        vec![Scope::Preview, Scope::Submit, Scope::Merge, Scope::Split]
+    }
+}
+
+impl FromStr for Scope {
+    type Err = failure::Error;
+
+    fn from_str(src: &str) -> Result<Scope, failure::Error> {
+        match src {
+            "preview" => Ok(Scope::Preview),
+            "submit" => Ok(Scope::Submit),
+            "merge" => Ok(Scope::Merge),
+            "split" => Ok(Scope::Split),
+            "trusted_templates" => Ok(Scope::TrustedTemplates),
+            other => Err(failure::format_err!("Unknown scope {:?}", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Scope::Preview => "preview",
+            Scope::Submit => "submit",
+            Scope::Merge => "merge",
+            Scope::Split => "split",
+            Scope::TrustedTemplates => "trusted_templates",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How many requests a client can make, and how many jobs it can have in flight. Unset limits
+/// are unlimited.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimits {
+    /// The sustained number of requests per minute.
+    pub requests_per_minute: Option<u32>,
+    /// The number of requests that can be made at once, after a quiet period. Defaults to
+    /// `requests_per_minute`.
+    pub burst: Option<u32>,
+    /// The number of requests and jobs that can be in flight at the same time.
+    pub max_concurrent_jobs: Option<u32>,
+}
+
+impl RateLimits {
+    /// Read the global limits from `PAPERS_RATE_LIMIT`, `PAPERS_RATE_LIMIT_BURST` and
+    /// `PAPERS_MAX_CONCURRENT_JOBS`.
+    fn from_settings(settings: &Settings) -> RateLimits {
+        let var = |name: &str| settings.parse(name, "a positive integer");
+
+        let limits = RateLimits {
+            requests_per_minute: var("PAPERS_RATE_LIMIT"),
+            burst: var("PAPERS_RATE_LIMIT_BURST"),
+            max_concurrent_jobs: var("PAPERS_MAX_CONCURRENT_JOBS"),
+        };
+
+        if let Err(err) = limits.check_rates() {
+            settings.error(format!(
+                "PAPERS_RATE_LIMIT and PAPERS_RATE_LIMIT_BURST: {}",
+                err
+            ));
+        }
+
+        limits
+    }
+
+    /// A rate or a burst of 0 would never refill the bucket, clients are blocked with
+    /// `max_concurrent_jobs: 0` instead.
+    fn check_rates(&self) -> Result<(), failure::Error> {
+        if self.requests_per_minute == Some(0) || self.burst == Some(0) {
+            return Err(failure::format_err!(
+                "the rate and the burst should be positive, use a concurrent jobs limit of 0 to block a client"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// These limits, with the unset ones taken from `defaults`.
+    pub fn or(self, defaults: RateLimits) -> RateLimits {
+        RateLimits {
+            requests_per_minute: self.requests_per_minute.or(defaults.requests_per_minute),
+            burst: self.burst.or(defaults.burst),
+            max_concurrent_jobs: self.max_concurrent_jobs.or(defaults.max_concurrent_jobs),
+        }
+    }
+}
+
+/// A key accepted in the Authorization header. Only the SHA-256 hash of the key is kept.
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    /// The name of the key, attached to the logs of the jobs it starts.
+    pub name: String,
+    /// The SHA-256 hash of the key.
+    pub sha256: [u8; 32],
+    /// The endpoints the key can use.
+    pub scopes: Vec<Scope>,
+    /// The limits of the key, falling back to the global ones.
+    pub limits: RateLimits,
+}
+
+impl ApiKey {
+    /// Build a key from the plain text secret.
+    pub fn from_secret(name: String, secret: &str, scopes: Vec<Scope>) -> ApiKey {
+        ApiKey {
+            name,
+            sha256: openssl::sha::sha256(secret.as_bytes()),
+            scopes,
+            limits: RateLimits::default(),
+        }
+    }
+
+    /// Build a key from the hex-encoded SHA-256 hash of the secret.
+    fn from_hex_hash(
+        name: String,
+        hex_hash: &str,
+        scopes: Vec<Scope>,
+    ) -> Result<ApiKey, failure::Error> {
+        let hex_hash = hex_hash.trim();
+        let mut sha256 = [0u8; 32];
+
+        if hex_hash.len() != 64 || !hex_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(failure::format_err!(
+                "The hash of the API key {:?} is not a hex-encoded SHA-256 hash",
+                name
+            ));
+        }
+
+        for (byte, index) in sha256.iter_mut().zip((0..64).step_by(2)) {
+            *byte = u8::from_str_radix(&hex_hash[index..index + 2], 16)?;
+        }
+
+        Ok(ApiKey {
+            name,
+            sha256,
+            scopes,
+            limits: RateLimits::default(),
+        })
+    }
+}
+
+/// An entry of the API keys file.
+#[derive(Deserialize)]
+struct ApiKeyEntry {
+    name: String,
+    sha256: String,
+    scopes: Vec<Scope>,
+    #[serde(default)]
+    limits: RateLimits,
+}
+
+/// Parse the JSON API keys file: an array of `{ "name", "sha256", "scopes", "limits" }`
+/// objects, `limits` being optional.
+fn parse_api_keys_file(src: &str) -> Result<Vec<ApiKey>, failure::Error> {
+    let entries: Vec<ApiKeyEntry> = serde_json::from_str(src)?;
+
+    entries
+        .into_iter()
+        .map(|entry| {
+            entry.limits.check_rates().map_err(|err| {
+                failure::format_err!("Invalid limits for the API key {:?}: {}", entry.name, err)
+            })?;
+
+            let mut key = ApiKey::from_hex_hash(entry.name, &entry.sha256, entry.scopes)?;
+            key.limits = entry.limits;
+            Ok(key)
+        })
+        .collect()
+}
+
+/// Parse the `PAPERS_API_KEYS` list: `name:sha256:scope,scope` entries separated by `;`.
+fn parse_api_keys_list(src: &str) -> Result<Vec<ApiKey>, failure::Error> {
+    src.split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut fields = entry.splitn(3, ':');
+            let (name, hash, scopes) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(name), Some(hash), Some(scopes)) => (name, hash, scopes),
+                _ => {
+                    return Err(failure::format_err!(
+                        "API keys should look like name:sha256:scope,scope, got {:?}",
+                        entry
+                    ))
+                }
+            };
+
+            let scopes = scopes
+                .split(',')
+                .map(|scope| scope.trim().parse())
+                .collect::<Result<Vec<Scope>, _>>()?;
+
+            ApiKey::from_hex_hash(name.trim().to_owned(), hash, scopes)
+        })
+        .collect()
+}
+
+/// The API keys accepted by the service. Several keys can be valid at the same time, to rotate
+/// them without downtime.
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    /// The accepted keys.
+    pub keys: Vec<ApiKey>,
+    /// The verification of JWT bearer tokens, if enabled.
+    pub jwt: Option<JwtConfig>,
+}
+
+impl AuthConfig {
+    /// Read the keys from `PAPERS_API_KEYS_FILE`, `PAPERS_API_KEYS` and the legacy
+    /// `PAPERS_BEARER`, and the JWT settings. Returns `None` if none of them is set, which
+    /// disables authentication.
+    fn from_settings(settings: &Settings) -> Option<AuthConfig> {
+        let mut keys = Vec::new();
+
+        if let Some(path) = settings.get("PAPERS_API_KEYS_FILE") {
+            match std::fs::read_to_string(&path) {
+                Ok(src) => match parse_api_keys_file(&src) {
+                    Ok(file_keys) => keys.extend(file_keys),
+                    Err(err) => settings.error(format!("Invalid API keys file {}: {}", path, err)),
+                },
+                Err(err) => settings.error(format!("Could not read {}: {}", path, err)),
+            }
+        }
+
+        if let Some(list) = settings.get("PAPERS_API_KEYS") {
+            match parse_api_keys_list(&list) {
+                Ok(list_keys) => keys.extend(list_keys),
+                Err(err) => settings.error(format!("Invalid PAPERS_API_KEYS: {}", err)),
+            }
+        }
+
+        if let Some(secret) = settings.get("PAPERS_BEARER") {
+            keys.push(ApiKey::from_secret(
+                "default".to_owned(),
+                &secret,
+                Scope::all(),
+            ));
+        }
+
+        let jwt = JwtConfig::from_settings(settings);
+
+        if keys.is_empty() && jwt.is_none() {
+            None
+        } else {
+            Some(AuthConfig { keys, jwt })
+        }
+    }
+}
+
+/// A public key that JWT signatures are verified with.
+#[derive(Clone)]
+pub struct JwtKey {
+    /// The key id, matched against the `kid` header of the tokens.
+    pub kid: Option<String>,
+    /// The RSA or EC public key.
+    pub key: openssl::pkey::PKey<openssl::pkey::Public>,
+}
+
+impl std::fmt::Debug for JwtKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtKey")
+            .field("kid", &self.kid)
+            .field("type", &self.key.id())
+            .finish()
+    }
+}
+
+/// A key of a JWKS document. Only the fields for RSA and EC public keys are read.
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+/// Decode a base64url-encoded JWK parameter into a big number.
+fn jwk_number(
+    value: &Option<String>,
+    parameter: &str,
+) -> Result<openssl::bn::BigNum, failure::Error> {
+    let value = value
+        .as_ref()
+        .ok_or_else(|| failure::format_err!("The JWK has no {:?} parameter", parameter))?;
+    let bytes = base64::decode_config(value, base64::URL_SAFE_NO_PAD)?;
+    Ok(openssl::bn::BigNum::from_slice(&bytes)?)
+}
+
+/// Parse the signature keys of a JWKS document. Encryption keys are skipped.
+fn parse_jwks(src: &str) -> Result<Vec<JwtKey>, failure::Error> {
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+
+    let jwks: Jwks = serde_json::from_str(src)?;
+    let mut keys = Vec::with_capacity(jwks.keys.len());
+
+    for jwk in jwks.keys {
+        if jwk
+            .key_use
+            .as_ref()
+            .map(|key_use| key_use != "sig")
+            .unwrap_or(false)
+        {
+            continue;
+        }
+
+        let key = match (jwk.kty.as_str(), jwk.crv.as_ref().map(String::as_str)) {
+            ("RSA", _) => PKey::from_rsa(Rsa::from_public_components(
+                jwk_number(&jwk.n, "n")?,
+                jwk_number(&jwk.e, "e")?,
+            )?)?,
+            ("EC", Some(crv)) => {
+                let nid = match crv {
+                    "P-256" => Nid::X9_62_PRIME256V1,
+                    "P-384" => Nid::SECP384R1,
+                    other => return Err(failure::format_err!("Unsupported curve {:?}", other)),
+                };
+                let group = EcGroup::from_curve_name(nid)?;
+                PKey::from_ec_key(EcKey::from_public_key_affine_coordinates(
+                    &group,
+                    &jwk_number(&jwk.x, "x")?,
+                    &jwk_number(&jwk.y, "y")?,
+                )?)?
+            }
+            (kty, _) => return Err(failure::format_err!("Unsupported key type {:?}", kty)),
+        };
+
+        keys.push(JwtKey { kid: jwk.kid, key });
+    }
+
+    Ok(keys)
+}
+
+/// How JWT bearer tokens are verified.
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    /// The keys the signatures are verified with.
+    pub keys: Vec<JwtKey>,
+    /// The required `aud` claim, if any.
+    pub audience: Option<String>,
+    /// The required `iss` claim, if any.
+    pub issuer: Option<String>,
+    /// The claim listing the endpoints the token can use, as a space-separated string or an
+    /// array of strings. Values that are not scopes are ignored.
+    pub scopes_claim: String,
+    /// The clock skew tolerated when checking `exp` and `nbf`, in seconds.
+    pub leeway: u64,
+}
+
+impl JwtConfig {
+    /// Read the JWT settings from the `PAPERS_JWT_*` environment variables. Returns `None` if
+    /// neither `PAPERS_JWT_JWKS_FILE` nor `PAPERS_JWT_PUBLIC_KEYS` is set.
+    fn from_settings(settings: &Settings) -> Option<JwtConfig> {
+        let mut keys = Vec::new();
+
+        if let Some(path) = settings.get("PAPERS_JWT_JWKS_FILE") {
+            match std::fs::read_to_string(&path) {
+                Ok(src) => match parse_jwks(&src) {
+                    Ok(jwks) => keys.extend(jwks),
+                    Err(err) => settings.error(format!("Invalid JWKS {}: {}", path, err)),
+                },
+                Err(err) => settings.error(format!("Could not read {}: {}", path, err)),
+            }
+        }
+
+        if let Some(paths) = settings.get("PAPERS_JWT_PUBLIC_KEYS") {
+            for path in std::env::split_paths(&paths) {
+                match std::fs::read(&path) {
+                    Ok(pem) => match openssl::pkey::PKey::public_key_from_pem(&pem) {
+                        Ok(key) => keys.push(JwtKey { kid: None, key }),
+                        Err(err) => {
+                            settings.error(format!("Invalid public key {:?}: {}", path, err))
+                        }
+                    },
+                    Err(err) => settings.error(format!("Could not read {:?}: {}", path, err)),
+                }
+            }
+        }
+
+        if keys.is_empty() {
+            return None;
+        }
+
+        Some(JwtConfig {
+            keys,
+            audience: settings.get("PAPERS_JWT_AUDIENCE"),
+            issuer: settings.get("PAPERS_JWT_ISSUER"),
+            scopes_claim: settings.get_or("PAPERS_JWT_SCOPES_CLAIM", JWT_SCOPES_CLAIM_DEFAULT),
+            leeway: settings.parse_or(
+                "PAPERS_JWT_LEEWAY",
+                "a duration in seconds",
+                JWT_LEEWAY_SECONDS_DEFAULT,
+            ),
+        })
+    }
+}
+
+/// Which URLs the service may send requests to, for templates, assets and callbacks. See
+/// [`utils::egress`](crate::utils::egress).
+#[derive(Debug, Clone)]
+pub struct EgressConfig {
+    /// The URL schemes allowed, like `https`.
+    pub allowed_schemes: Vec<String>,
+    /// Host patterns, where `*` matches any characters. All hosts are allowed if it is empty.
+    pub allowed_hosts: Vec<String>,
+    /// Host patterns that are never allowed, even if they are in `allowed_hosts`.
+    pub denied_hosts: Vec<String>,
+    /// Whether hosts may resolve to private, loopback and link-local addresses.
+    pub allow_private_addresses: bool,
+    /// The maximum number of redirects followed by a request.
+    pub max_redirects: usize,
+}
+
+impl EgressConfig {
+    /// Read the policy from the `PAPERS_EGRESS_*` environment variables.
+    fn from_settings(settings: &Settings) -> EgressConfig {
+        let list = |var: &str, default: &str| -> Vec<String> {
+            settings
+                .get_or(var, default)
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_owned)
+                .collect()
+        };
+
+        EgressConfig {
+            allowed_schemes: list("PAPERS_EGRESS_SCHEMES", EGRESS_SCHEMES_DEFAULT),
+            allowed_hosts: list("PAPERS_EGRESS_ALLOWED_HOSTS", ""),
+            denied_hosts: list("PAPERS_EGRESS_DENIED_HOSTS", ""),
+            allow_private_addresses: settings.parse_or(
+                "PAPERS_EGRESS_ALLOW_PRIVATE_ADDRESSES",
+                "true or false",
+                false,
+            ),
+            max_redirects: settings.parse_or(
+                "PAPERS_EGRESS_MAX_REDIRECTS",
+                "a number",
+                EGRESS_MAX_REDIRECTS_DEFAULT,
+            ),
+        }
+    }
+}
+
+/// TLS termination. See [`tls`](crate::tls).
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// The PEM certificate chain of the server.
+    pub cert: std::path::PathBuf,
+    /// The PEM private key of the server, PKCS#8 or RSA.
+    pub key: std::path::PathBuf,
+    /// The PEM bundle of the CAs client certificates are verified against. Client certificates
+    /// are not requested if this is `None`.
+    pub client_ca: Option<std::path::PathBuf>,
+    /// Whether clients may connect without a certificate, and authenticate otherwise.
+    pub client_auth_optional: bool,
+    /// The scopes of clients authenticated with a certificate.
+    pub client_scopes: Vec<Scope>,
+}
+
+impl TlsConfig {
+    /// Read the TLS configuration from the `PAPERS_TLS_*` environment variables. Returns `None`
+    /// if `PAPERS_TLS_CERT` is unset, which serves plain HTTP.
+    fn from_settings(settings: &Settings) -> Option<TlsConfig> {
+        let cert = settings.get("PAPERS_TLS_CERT")?;
+        let key = settings.get("PAPERS_TLS_KEY").unwrap_or_else(|| {
+            settings.error("PAPERS_TLS_KEY should be set when PAPERS_TLS_CERT is".to_owned());
+            String::new()
+        });
+
+        let client_auth_optional = match settings
+            .get("PAPERS_TLS_CLIENT_AUTH")
+            .as_ref()
+            .map(String::as_str)
+        {
+            Some("required") | None => false,
+            Some("optional") => true,
+            Some(other) => {
+                settings.error(format!(
+                    "PAPERS_TLS_CLIENT_AUTH should be \"required\" or \"optional\", got {:?}",
+                    other
+                ));
+                false
+            }
+        };
+
+        let client_scopes = match settings.get("PAPERS_TLS_CLIENT_SCOPES") {
+            Some(scopes) => scopes
+                .split(',')
+                .map(|scope| scope.trim().parse())
+                .collect::<Result<Vec<Scope>, _>>()
+                .unwrap_or_else(|err| {
+                    settings.error(format!("Invalid PAPERS_TLS_CLIENT_SCOPES: {}", err));
+                    Vec::new()
+                }),
+            None => Scope::all(),
+        };
+
+        Some(TlsConfig {
+            cert: cert.into(),
+            key: key.into(),
+            client_ca: settings.get("PAPERS_TLS_CLIENT_CA").map(Into::into),
+            client_auth_optional,
+            client_scopes,
+        })
+    }
+
+    /// Whether clients are asked for a certificate.
+    pub fn mutual(&self) -> bool {
+        self.client_ca.is_some()
+    }
+}
+
+/// The restricted environment xelatex, `convert` and `pdfunite` run in.
+#[derive(Debug)]
+pub struct SandboxConfig {
+    /// The bubblewrap executable.
+    pub bwrap: std::path::PathBuf,
+    /// The system paths mounted read-only in the sandbox, like the TeX tree. Missing paths are
+    /// skipped.
+    pub read_only_paths: Vec<std::path::PathBuf>,
+    /// The user the sandboxed processes run as. The service needs to run as root to change it.
+    pub uid: Option<u32>,
+    /// The group the sandboxed processes run as.
+    pub gid: Option<u32>,
+    /// The resource limits of the sandboxed processes.
+    pub limits: ResourceLimits,
+}
+
+/// Resource limits applied to every sandboxed process.
+#[derive(Debug)]
+pub struct ResourceLimits {
+    /// CPU time, in seconds.
+    pub cpu_seconds: u32,
+    /// Address space, in bytes.
+    pub memory: u64,
+    /// Size of the files the process can write, in bytes.
+    pub file_size: u64,
+    /// Number of processes of the sandbox user.
+    pub processes: u32,
+}
+
+impl SandboxConfig {
+    /// Read the sandbox configuration from the `PAPERS_SANDBOX*` environment variables.
+    /// Returns `None` if `PAPERS_SANDBOX` is unset or `none`.
+    fn from_settings(settings: &Settings) -> Option<SandboxConfig> {
+        match settings.get("PAPERS_SANDBOX").as_ref().map(String::as_str) {
+            Some("bubblewrap") => (),
+            Some("none") | None => return None,
+            Some(other) => {
+                settings.error(format!(
+                    "PAPERS_SANDBOX should be \"bubblewrap\" or \"none\", got {:?}",
+                    other
+                ));
+                return None;
+            }
+        }
+
+        let number =
+            |var: &str, default: u32| -> u32 { settings.parse_or(var, "a number", default) };
+
+        let size = |var: &str, default: u64| -> u64 {
+            settings
+                .parse_or(var, "a size, like 100M", Bytes(default))
+                .0
+        };
+
+        let id = |var: &str| -> Option<u32> { settings.parse(var, "a numeric id") };
+
+        Some(SandboxConfig {
+            bwrap: settings.get_or("PAPERS_SANDBOX_BWRAP", "bwrap").into(),
+            read_only_paths: std::env::split_paths(&settings.get_or(
+                "PAPERS_SANDBOX_READ_ONLY_PATHS",
+                SANDBOX_READ_ONLY_PATHS_DEFAULT,
+            ))
+            .collect(),
+            uid: id("PAPERS_SANDBOX_UID"),
+            gid: id("PAPERS_SANDBOX_GID"),
+            limits: ResourceLimits {
+                cpu_seconds: number("PAPERS_SANDBOX_CPU_SECONDS", SANDBOX_CPU_SECONDS_DEFAULT),
+                memory: size("PAPERS_SANDBOX_MEMORY", SANDBOX_MEMORY_DEFAULT),
+                file_size: size("PAPERS_SANDBOX_FILE_SIZE", SANDBOX_FILE_SIZE_DEFAULT),
+                processes: number("PAPERS_SANDBOX_PROCESSES", SANDBOX_PROCESSES_DEFAULT),
+            },
+        })
+    }
+}
+
+/// Which workspaces are kept as debug archives when their job is done.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetentionPolicy {
+    /// Every workspace.
+    Always,
+    /// The workspaces of jobs that failed, including the jobs whose callback failed.
+    OnFailure,
+    /// No workspace.
+    Never,
+}
+
+impl RetentionPolicy {
+    /// Whether the workspace of a job is kept, depending on whether the job `failed`.
+    pub fn keeps(self, failed: bool) -> bool {
+        match self {
+            RetentionPolicy::Always => true,
+            RetentionPolicy::OnFailure => failed,
+            RetentionPolicy::Never => false,
+        }
+    }
+}
+
+impl FromStr for RetentionPolicy {
+    type Err = ();
+
+    fn from_str(src: &str) -> Result<RetentionPolicy, ()> {
+        match src {
+            "always" => Ok(RetentionPolicy::Always),
+            "on_failure" => Ok(RetentionPolicy::OnFailure),
+            "never" => Ok(RetentionPolicy::Never),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for RetentionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RetentionPolicy::Always => write!(f, "always"),
+            RetentionPolicy::OnFailure => write!(f, "on_failure"),
+            RetentionPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// The debug archives of workspaces, uploaded to S3 when jobs are done. See
+/// [`upload_workspace`](crate::utils::s3::upload_workspace).
+#[derive(Debug, Clone)]
+pub struct DebugArchiveConfig {
+    /// Which workspaces are archived.
+    pub policy: RetentionPolicy,
+    /// Patterns of the paths in the workspace that are archived, like `*.tex`. Every file is
+    /// archived if it is empty.
+    pub include: Vec<glob::Pattern>,
+    /// Patterns of the paths that are never archived, even if they are in `include`.
+    pub exclude: Vec<glob::Pattern>,
+    /// Whether archives are compressed with gzip.
+    pub gzip: bool,
+    /// The bucket of the archives, the bucket of the documents if this is `None`.
+    pub bucket: Option<String>,
+    /// The prefix of the keys of the archives, like `debug/`.
+    pub prefix: String,
+}
+
+impl Default for DebugArchiveConfig {
+    fn default() -> DebugArchiveConfig {
+        DebugArchiveConfig {
+            policy: RetentionPolicy::Always,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            gzip: false,
+            bucket: None,
+            prefix: String::new(),
+        }
+    }
+}
+
+impl DebugArchiveConfig {
+    /// Read the archive configuration from the `PAPERS_DEBUG_ARCHIVE_*` environment variables.
+    fn from_settings(settings: &Settings) -> DebugArchiveConfig {
+        let patterns = |var: &str| -> Vec<glob::Pattern> {
+            settings
+                .get_or(var, "")
+                .split(',')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .filter_map(|pattern| {
+                    glob::Pattern::new(pattern)
+                        .map_err(|err| {
+                            settings.error(format!(
+                                "{} has an invalid pattern {:?}: {}",
+                                var, pattern, err
+                            ))
+                        })
+                        .ok()
+                })
+                .collect()
+        };
+
+        DebugArchiveConfig {
+            policy: settings.parse_or(
+                "PAPERS_DEBUG_ARCHIVE_POLICY",
+                "\"always\", \"on_failure\" or \"never\"",
+                RetentionPolicy::Always,
+            ),
+            include: patterns("PAPERS_DEBUG_ARCHIVE_INCLUDE"),
+            exclude: patterns("PAPERS_DEBUG_ARCHIVE_EXCLUDE"),
+            gzip: settings.parse_or("PAPERS_DEBUG_ARCHIVE_GZIP", "true or false", false),
+            bucket: settings.get("PAPERS_DEBUG_ARCHIVE_BUCKET"),
+            prefix: settings.get_or("PAPERS_DEBUG_ARCHIVE_PREFIX", ""),
+        }
+    }
+
+    /// Whether the file at `path`, relative to the workspace, is archived.
+    pub fn includes(&self, path: &Path) -> bool {
+        let matches =
+            |patterns: &[glob::Pattern]| patterns.iter().any(|pattern| pattern.matches_path(path));
+
+        (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude)
+    }
+
+    /// The file name of the archives.
+    pub fn filename(&self) -> &'static str {
+        if self.gzip {
+            "workspace.tar.gz"
+        } else {
+            "workspace.tar"
+        }
+    }
+
+    /// The S3 key of the archive of the job in the S3 directory `dir_name`.
+    pub fn key(&self, dir_name: &str) -> String {
+        format!("{}{}/{}", self.prefix, dir_name, self.filename())
+    }
 }
 
 impl S3Config {
@@ -68,17 +965,46 @@ impl S3Config {
 /// Please refer to the README for more details about configuration
 #[derive(Debug)]
 pub struct Config {
-    /// A long secret that is used in the Authorization header to authenticate a request against
-    /// papers
-    pub auth: Option<String>,
+    /// The API keys that are used in the Authorization header to authenticate requests against
+    /// papers. Authentication is disabled if this is `None`.
+    pub auth: Option<AuthConfig>,
     /// Limits the number of assets allowed for a given DocumentSpec
     pub max_assets_per_document: u32,
     /// Limits the size of the assets downloaded by the service, including templates
-    pub max_asset_size: u32,
+    pub max_asset_size: u64,
+    /// Limits the disk space used by a job, for downloads, intermediate files and outputs
+    pub max_workspace_size: u64,
     /// The root logger for the application
     pub logger: Logger,
+    /// The format of the `logs.txt` file of each job
+    pub workspace_log_format: LogFormat,
+    /// Which workspaces are uploaded to S3 for debugging, and what they contain
+    pub debug_archives: DebugArchiveConfig,
+    /// The bearer token `/metrics` requires, which is open if this is `None`. The API keys don't
+    /// give access to the metrics.
+    pub metrics_token: Option<String>,
+    /// The OTLP/HTTP endpoint the spans of jobs are exported to, if tracing is enabled
+    pub otlp_endpoint: Option<String>,
+    /// The port the server listens on
+    pub port: u16,
     /// The S3 configuration
     pub s3: S3Config,
+    /// The ICC profile embedded as the output intent of PDF/A documents
+    pub icc_profile: std::path::PathBuf,
+    /// The keystore for digital signatures, if signing is enabled
+    pub signing: Option<SigningConfig>,
+    /// The sandbox for external tools, if sandboxing is enabled
+    pub sandbox: Option<SandboxConfig>,
+    /// The policy for outgoing requests to client-provided URLs
+    pub egress: EgressConfig,
+    /// The wall-clock time limit of every external process run by a job
+    pub step_timeout: std::time::Duration,
+    /// The wall-clock time limit of a whole job, from the first download to the callback
+    pub job_timeout: std::time::Duration,
+    /// The default limits of every client, API keys can override them
+    pub rate_limits: RateLimits,
+    /// TLS termination, if enabled
+    pub tls: Option<TlsConfig>,
 }
 
 impl Config {
@@ -86,77 +1012,287 @@ impl Config {
     pub fn for_tests() -> Config {
         Config {
             auth: None,
+            debug_archives: DebugArchiveConfig::default(),
+            // The test servers run on localhost.
+            egress: EgressConfig {
+                allowed_schemes: vec!["http".into(), "https".into()],
+                allowed_hosts: Vec::new(),
+                denied_hosts: Vec::new(),
+                allow_private_addresses: true,
+                max_redirects: EGRESS_MAX_REDIRECTS_DEFAULT,
+            },
+            icc_profile: ICC_PROFILE_DEFAULT.into(),
             logger: build_logger(),
             max_asset_size: MAX_ASSET_SIZE_DEFAULT,
+            max_workspace_size: MAX_WORKSPACE_SIZE_DEFAULT,
             max_assets_per_document: MAX_ASSETS_PER_DOCUMENT_DEFAULT,
+            metrics_token: None,
+            otlp_endpoint: None,
+            port: PORT_DEFAULT,
+            rate_limits: RateLimits::default(),
             s3: S3Config {
                 bucket: "walrus".into(),
                 credentials: rusoto_credential::AwsCredentials::new("a", "b", None, None),
-                credentials_provider: rusoto_credential::EnvironmentProvider::default(),
+                credentials_provider: rusoto_credential::StaticProvider::new_minimal(
+                    "a".into(),
+                    "b".into(),
+                ),
                 expiration_time: 3600,
                 region: rusoto_core::region::Region::Custom {
                     endpoint: "http://s3.localhost".into(),
                     name: "local_s3".into(),
                 },
             },
+            job_timeout: std::time::Duration::from_secs(JOB_TIMEOUT_SECONDS_DEFAULT),
+            sandbox: None,
+            signing: None,
+            step_timeout: std::time::Duration::from_secs(STEP_TIMEOUT_SECONDS_DEFAULT),
+            tls: None,
+            workspace_log_format: LogFormat::Text,
         }
     }
 
     /// The normal way to construct a `Config`, reading from environment variables.
+    ///
+    /// Panics with every problem found if the configuration is invalid, see
+    /// [`load`](Config::load) to handle them.
     pub fn from_env() -> Config {
-        use futures01::Future;
-        use rusoto_credential::ProvideAwsCredentials;
+        Config::load(None).unwrap_or_else(|errors| panic!("{}", errors))
+    }
+
+    /// Read the configuration from the TOML file at `file`, if any, and from environment
+    /// variables, which override the settings of the file. Returns every problem found if the
+    /// configuration is invalid.
+    pub fn load(file: Option<&Path>) -> Result<Config, ConfigErrors> {
+        Config::load_effective(file).map(|(config, _)| config)
+    }
+
+    /// Same as [`load`](Config::load), also returning the settings the configuration was built
+    /// from, for `papers config check`.
+    pub fn load_effective(
+        file: Option<&Path>,
+    ) -> Result<(Config, EffectiveSettings), ConfigErrors> {
+        let settings = Settings::new(file);
+
+        let max_asset_size = settings
+            .parse_or(
+                "PAPERS_MAX_ASSET_SIZE",
+                "a size, like 10M",
+                Bytes(MAX_ASSET_SIZE_DEFAULT),
+            )
+            .0;
+        let max_workspace_size = settings
+            .parse_or(
+                "PAPERS_MAX_WORKSPACE_SIZE",
+                "a size, like 1G",
+                Bytes(MAX_WORKSPACE_SIZE_DEFAULT),
+            )
+            .0;
+
+        if max_asset_size > max_workspace_size {
+            settings.error(format!(
+                "PAPERS_MAX_ASSET_SIZE ({}) should not be larger than PAPERS_MAX_WORKSPACE_SIZE ({})",
+                Bytes(max_asset_size),
+                Bytes(max_workspace_size)
+            ));
+        }
+
+        let auth = AuthConfig::from_settings(&settings);
+
+        let icc_profile = settings
+            .get_or("PAPERS_ICC_PROFILE", ICC_PROFILE_DEFAULT)
+            .into();
+
+        let log_format =
+            settings.parse_or("PAPERS_LOG_FORMAT", "\"text\" or \"json\"", LogFormat::Text);
+        let workspace_log_format = settings.parse_or(
+            "PAPERS_WORKSPACE_LOG_FORMAT",
+            "\"text\" or \"json\"",
+            log_format,
+        );
+        let logger = logger(&settings.get_or("PAPERS_LOG_LEVEL", "info"), log_format);
+        let max_assets_per_document = settings.parse_or(
+            "PAPERS_MAX_ASSETS_PER_DOCUMENT",
+            "a number",
+            MAX_ASSETS_PER_DOCUMENT_DEFAULT,
+        );
+        let metrics_token = settings.get("PAPERS_METRICS_TOKEN");
+        let otlp_endpoint = settings.get("PAPERS_OTLP_ENDPOINT").filter(|endpoint| {
+            let valid = reqwest::Url::parse(endpoint)
+                .map(|url| url.scheme() == "http" || url.scheme() == "https")
+                .unwrap_or(false);
 
-        let max_asset_size = std::env::var("PAPERS_MAX_ASSET_SIZE")
-            .map_err(|_| ())
-            .and_then(|s| Bytes::from_str(&s))
-            .map(|bytes| bytes.0)
-            .unwrap_or(MAX_ASSET_SIZE_DEFAULT);
+            if !valid {
+                settings.error(format!(
+                    "PAPERS_OTLP_ENDPOINT should be an http or https URL, got {:?}",
+                    endpoint
+                ));
+            }
 
-        let auth = std::env::var("PAPERS_BEARER").ok();
+            valid
+        });
+        let port = settings.parse_or("PAPERS_PORT", "a port number", PORT_DEFAULT);
 
-        let logger = build_logger();
-        let max_assets_per_document = max_assets_per_document(&logger);
+        let region = settings.required("PAPERS_AWS_REGION").and_then(|region| {
+            match region.parse::<Region>() {
+                Ok(region) => Some(region),
+                Err(_) => {
+                    settings.error(format!(
+                        "PAPERS_AWS_REGION is not a valid AWS region: {:?}",
+                        region
+                    ));
+                    None
+                }
+            }
+        });
 
-        let aws_region_string = std::env::var("PAPERS_AWS_REGION")
-            .expect("The PAPERS_AWS_REGION environment variable was not provided");
+        let expiration_time = settings.parse_or(
+            "PAPERS_S3_EXPIRATION_TIME",
+            "a duration in seconds",
+            S3_EXPIRATION_TIME_SECONDS_DEFAULT,
+        );
 
-        let expiration_time: u32 = std::env::var("PAPERS_S3_EXPIRATION_TIME")
-            .unwrap_or_else(|_| "86400".to_string()) // one day
-            .parse()
-            .expect("PAPERS_S3_EXPIRATION_TIME should be a duration in seconds");
+        let bucket = settings.required("PAPERS_S3_BUCKET");
+        let access_key_id = settings.required("PAPERS_ACCESS_KEY_ID");
+        let secret_access_key = settings.required("PAPERS_SECRET_ACCESS_KEY");
+        let session_token = settings.get("PAPERS_SESSION_TOKEN");
 
-        let credentials_provider = rusoto_credential::EnvironmentProvider::with_prefix("PAPERS");
+        let signing = settings
+            .get("PAPERS_SIGNING_KEYSTORE")
+            .and_then(|keystore| {
+                let password = settings
+                    .get("PAPERS_SIGNING_KEYSTORE_PASSWORD")
+                    .unwrap_or_default();
+                SigningConfig::load(keystore.into(), password)
+                    .map_err(|err| {
+                        settings.error(format!(
+                            "The signing keystore could not be loaded: {}",
+                            crate::prelude::display_error(&err)
+                        ))
+                    })
+                    .ok()
+            });
 
-        let credentials = credentials_provider
-            .credentials()
-            .wait()
-            .expect("error reading AWS credentials from environment");
+        let debug_archives = DebugArchiveConfig::from_settings(&settings);
+        let egress = EgressConfig::from_settings(&settings);
+        let rate_limits = RateLimits::from_settings(&settings);
+        let sandbox = SandboxConfig::from_settings(&settings);
+        let tls = TlsConfig::from_settings(&settings);
+
+        let step_timeout = std::time::Duration::from_secs(settings.parse_or(
+            "PAPERS_STEP_TIMEOUT",
+            "a duration in seconds",
+            STEP_TIMEOUT_SECONDS_DEFAULT,
+        ));
+
+        let job_timeout = std::time::Duration::from_secs(settings.parse_or(
+            "PAPERS_JOB_TIMEOUT",
+            "a duration in seconds",
+            JOB_TIMEOUT_SECONDS_DEFAULT,
+        ));
+
+        let effective = settings.finish(SETTINGS)?;
+
+        // Every required setting is there if there was no error.
+        let credentials_provider = rusoto_credential::StaticProvider::new(
+            access_key_id.unwrap_or_default(),
+            secret_access_key.unwrap_or_default(),
+            session_token,
+            None,
+        );
+        let credentials = rusoto_credential::AwsCredentials::new(
+            credentials_provider.get_aws_access_key_id(),
+            credentials_provider.get_aws_secret_access_key(),
+            credentials_provider.get_token().clone(),
+            None,
+        );
 
         let s3 = S3Config {
-            bucket: std::env::var("PAPERS_S3_BUCKET")
-                .expect("The PAPERS_S3_BUCKET environment variable was not provided"),
+            bucket: bucket.unwrap_or_default(),
             credentials,
             credentials_provider,
-            region: aws_region_string
-                .parse()
-                .expect("The provided AWS region is not valid"),
+            region: region.unwrap_or_default(),
             expiration_time,
         };
 
-        Config {
+        let config = Config {
             auth,
+            debug_archives,
+            egress,
+            icc_profile,
+            job_timeout,
             logger,
             max_asset_size,
             max_assets_per_document,
+            max_workspace_size,
+            metrics_token,
+            otlp_endpoint,
+            port,
+            rate_limits,
             s3,
-        }
+            sandbox,
+            signing,
+            step_timeout,
+            tls,
+            workspace_log_format,
+        };
+
+        Ok((config, effective))
     }
 
-    /// Return a new `Config` with the specified auth secret.
+    /// Return a new `Config` with the specified auth secret, valid for every endpoint.
     pub fn with_auth(self, auth: String) -> Config {
+        self.with_api_keys(vec![ApiKey::from_secret(
+            "default".to_owned(),
+            &auth,
+            Scope::all(),
+        )])
+    }
+
+    /// Return a new `Config` that accepts `keys`.
+    pub fn with_api_keys(self, keys: Vec<ApiKey>) -> Config {
+        Config {
+            auth: Some(AuthConfig { keys, jwt: None }),
+            ..self
+        }
+    }
+
+    /// Set the bearer token of `/metrics` and return `self`.
+    pub fn with_metrics_token(self, metrics_token: String) -> Config {
+        Config {
+            metrics_token: Some(metrics_token),
+            ..self
+        }
+    }
+
+    /// Set the default `rate_limits` of every client and return `self`.
+    pub fn with_rate_limits(self, rate_limits: RateLimits) -> Config {
+        Config {
+            rate_limits,
+            ..self
+        }
+    }
+
+    /// Set `max_workspace_size` and return `self`.
+    pub fn with_max_workspace_size(self, max_workspace_size: u64) -> Config {
+        Config {
+            max_workspace_size,
+            ..self
+        }
+    }
+
+    /// Set `debug_archives` and return `self`.
+    pub fn with_debug_archives(self, debug_archives: DebugArchiveConfig) -> Config {
         Config {
-            auth: Some(auth),
+            debug_archives,
+            ..self
+        }
+    }
+
+    /// Set `job_timeout` and return `self`.
+    pub fn with_job_timeout(self, job_timeout: std::time::Duration) -> Config {
+        Config {
+            job_timeout,
             ..self
         }
     }
@@ -169,3 +1305,144 @@ impl Config {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+    #[test]
+    fn api_keys_list_parses() {
+        let keys = parse_api_keys_list(&format!(
+            "billing:{}:submit,merge; reports:{}:preview",
+            HASH,
+            HASH.to_uppercase()
+        ))
+        .unwrap();
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].name, "billing");
+        assert_eq!(keys[0].scopes, vec![Scope::Submit, Scope::Merge]);
+        assert_eq!(keys[0].sha256, openssl::sha::sha256(b"secret"));
+        assert_eq!(keys[1].sha256, keys[0].sha256);
+        assert_eq!(keys[1].scopes, vec![Scope::Preview]);
+    }
+
+    #[test]
+    fn api_keys_list_rejects_invalid_entries() {
+        assert!(parse_api_keys_list("billing:abc:submit").is_err());
+        assert!(parse_api_keys_list(&format!("billing:{}:print", HASH)).is_err());
+        assert!(parse_api_keys_list("billing").is_err());
+    }
+
+    #[test]
+    fn api_keys_file_parses() {
+        let keys = parse_api_keys_file(&format!(
+            r#"[{{ "name": "billing", "sha256": "{}", "scopes": ["split"] }}]"#,
+            HASH
+        ))
+        .unwrap();
+
+        assert_eq!(keys[0].name, "billing");
+        assert_eq!(keys[0].scopes, vec![Scope::Split]);
+        assert_eq!(keys[0].limits, RateLimits::default());
+    }
+
+    #[test]
+    fn api_keys_file_parses_limits() {
+        let keys = parse_api_keys_file(&format!(
+            r#"[{{
+                "name": "billing",
+                "sha256": "{}",
+                "scopes": ["merge"],
+                "limits": {{ "requests_per_minute": 30, "max_concurrent_jobs": 2 }}
+            }}]"#,
+            HASH
+        ))
+        .unwrap();
+
+        let defaults = RateLimits {
+            requests_per_minute: Some(600),
+            burst: Some(10),
+            max_concurrent_jobs: None,
+        };
+
+        assert_eq!(
+            keys[0].limits.or(defaults),
+            RateLimits {
+                requests_per_minute: Some(30),
+                burst: Some(10),
+                max_concurrent_jobs: Some(2),
+            }
+        );
+    }
+
+    #[test]
+    fn api_keys_file_rejects_zero_rates() {
+        for limits in &[r#"{ "requests_per_minute": 0 }"#, r#"{ "burst": 0 }"#] {
+            let keys = parse_api_keys_file(&format!(
+                r#"[{{ "name": "billing", "sha256": "{}", "scopes": ["merge"], "limits": {} }}]"#,
+                HASH, limits
+            ));
+
+            assert!(keys.is_err(), "{}", limits);
+        }
+    }
+
+    #[test]
+    fn every_setting_is_known() {
+        let names = regex::Regex::new(r#""(PAPERS_[A-Z0-9_]+)""#).unwrap();
+
+        for name in names.captures_iter(include_str!("config.rs")) {
+            assert!(
+                SETTINGS.contains(&&name[1]),
+                "{} is not in SETTINGS",
+                &name[1]
+            );
+        }
+    }
+
+    #[test]
+    fn settings_of_disabled_features_are_known() {
+        let file = mktemp::Temp::new_file().unwrap();
+        std::fs::write(&file, "sandbox = \"none\"\nsandbox_uid = 1000\n").unwrap();
+
+        let settings = Settings::new(Some(file.as_ref()));
+
+        assert!(SandboxConfig::from_settings(&settings).is_none());
+        assert!(settings.finish(SETTINGS).is_ok());
+    }
+
+    #[test]
+    fn debug_archives_filter_paths() {
+        let pattern = |pattern| glob::Pattern::new(pattern).unwrap();
+        let archives = DebugArchiveConfig {
+            include: vec![pattern("*.tex"), pattern("logs.txt")],
+            exclude: vec![pattern("assets/*")],
+            gzip: true,
+            prefix: "debug/".to_owned(),
+            ..DebugArchiveConfig::default()
+        };
+
+        assert!(archives.includes(Path::new("logs.txt")));
+        assert!(archives.includes(Path::new("cover/cover.tex")));
+        assert!(!archives.includes(Path::new("assets/chapter.tex")));
+        assert!(!archives.includes(Path::new("report.pdf")));
+        assert!(DebugArchiveConfig::default().includes(Path::new("report.pdf")));
+
+        assert_eq!(
+            archives.key("20191018T120000.000Z-checkout-42"),
+            "debug/20191018T120000.000Z-checkout-42/workspace.tar.gz"
+        );
+    }
+
+    #[test]
+    fn retention_policies_parse() {
+        assert_eq!("on_failure".parse(), Ok(RetentionPolicy::OnFailure));
+        assert!("sometimes".parse::<RetentionPolicy>().is_err());
+        assert!(RetentionPolicy::OnFailure.keeps(true));
+        assert!(!RetentionPolicy::OnFailure.keeps(false));
+        assert!(!RetentionPolicy::Never.keeps(true));
+    }
+}
@@ -0,0 +1,355 @@
+//! The raw settings the [`Config`](super::Config) is built from: environment variables, which
+//! override the keys of an optional TOML file. Problems are collected instead of panicking, so
+//! they can all be reported at once.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+
+/// The prefix of the environment variables. The keys of the config file are the names of the
+/// variables without it, in lowercase.
+const PREFIX: &str = "PAPERS_";
+
+/// Settings whose values are never printed.
+const SECRET_MARKERS: &[&str] = &["SECRET", "PASSWORD", "BEARER", "TOKEN"];
+
+/// Where the value of a setting comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    /// An environment variable.
+    Env,
+    /// The config file.
+    File,
+    /// The default value.
+    Default,
+}
+
+/// The settings the config was built from, with secrets redacted. Its `Display` implementation
+/// prints them as a config file, with the source of each value.
+#[derive(Debug, Default)]
+pub struct EffectiveSettings(BTreeMap<String, (String, Source)>);
+
+impl std::fmt::Display for EffectiveSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, (value, source)) in &self.0 {
+            let value = if is_secret(name) {
+                "[redacted]"
+            } else {
+                value.as_str()
+            };
+            let source = match source {
+                Source::Env => "env",
+                Source::File => "file",
+                Source::Default => "default",
+            };
+
+            writeln!(f, "{} = {:?} # {}", file_key(name), value, source)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Every problem found in the configuration.
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl std::fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+
+        for error in &self.0 {
+            writeln!(f, "- {}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl failure::Fail for ConfigErrors {}
+
+pub(super) struct Settings {
+    /// The settings of the config file, by environment variable name.
+    file: HashMap<String, String>,
+    /// The settings that were read, and their values.
+    effective: RefCell<BTreeMap<String, (String, Source)>>,
+    errors: RefCell<Vec<String>>,
+}
+
+impl Settings {
+    /// Read the config file at `path`, if any.
+    pub(super) fn new(path: Option<&Path>) -> Settings {
+        let settings = Settings {
+            file: HashMap::new(),
+            effective: RefCell::new(BTreeMap::new()),
+            errors: RefCell::new(Vec::new()),
+        };
+
+        let path = match path {
+            Some(path) => path,
+            None => return settings,
+        };
+
+        match read_file(path) {
+            Ok(file) => Settings { file, ..settings },
+            Err(err) => {
+                settings.error(format!(
+                    "Could not read the config file {:?}: {}",
+                    path, err
+                ));
+                settings
+            }
+        }
+    }
+
+    /// The value of the `name` environment variable, or of its key in the config file.
+    pub(super) fn get(&self, name: &str) -> Option<String> {
+        let (value, source) = match std::env::var(name) {
+            Ok(value) => (value, Source::Env),
+            Err(_) => (self.file.get(name)?.clone(), Source::File),
+        };
+
+        self.record(name, &value, source);
+        Some(value)
+    }
+
+    /// Same as [`get`](Settings::get), with a default value.
+    pub(super) fn get_or(&self, name: &str, default: &str) -> String {
+        self.get(name).unwrap_or_else(|| {
+            self.record(name, default, Source::Default);
+            default.to_owned()
+        })
+    }
+
+    /// Same as [`get`](Settings::get), for settings without a default.
+    pub(super) fn required(&self, name: &str) -> Option<String> {
+        let value = self.get(name);
+
+        if value.is_none() {
+            self.error(format!("{} is required", name));
+        }
+
+        value
+    }
+
+    /// Parse a setting, described as `expected` in the error if it is invalid.
+    pub(super) fn parse<T: FromStr>(&self, name: &str, expected: &str) -> Option<T> {
+        let value = self.get(name)?;
+
+        match value.trim().parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.error(format!("{} should be {}, got {:?}", name, expected, value));
+                None
+            }
+        }
+    }
+
+    /// Same as [`parse`](Settings::parse), with a default value.
+    pub(super) fn parse_or<T>(&self, name: &str, expected: &str, default: T) -> T
+    where
+        T: FromStr + std::fmt::Display,
+    {
+        match self.parse(name, expected) {
+            Some(parsed) => parsed,
+            None => {
+                if !self.effective.borrow().contains_key(name) {
+                    self.record(name, &default.to_string(), Source::Default);
+                }
+                default
+            }
+        }
+    }
+
+    /// Record a problem.
+    pub(super) fn error(&self, message: String) {
+        self.errors.borrow_mut().push(message);
+    }
+
+    /// The effective settings, or every problem found. Keys of the config file that are not
+    /// among the `known` settings are unknown settings, most likely typos.
+    pub(super) fn finish(self, known: &[&str]) -> Result<EffectiveSettings, ConfigErrors> {
+        let effective = self.effective.into_inner();
+        let mut errors = self.errors.into_inner();
+
+        let mut unknown: Vec<&String> = self
+            .file
+            .keys()
+            .filter(|name| !known.contains(&name.as_str()))
+            .collect();
+        unknown.sort();
+
+        for name in unknown {
+            errors.push(format!(
+                "Unknown setting {:?} in the config file",
+                file_key(name)
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(EffectiveSettings(effective))
+        } else {
+            Err(ConfigErrors(errors))
+        }
+    }
+
+    fn record(&self, name: &str, value: &str, source: Source) {
+        self.effective
+            .borrow_mut()
+            .insert(name.to_owned(), (value.to_owned(), source));
+    }
+}
+
+/// Read a TOML config file, by environment variable name.
+fn read_file(path: &Path) -> Result<HashMap<String, String>, failure::Error> {
+    let src = std::fs::read_to_string(path)?;
+    parse_file(&src)
+}
+
+fn parse_file(src: &str) -> Result<HashMap<String, String>, failure::Error> {
+    let table: toml::value::Table = toml::from_str(src)?;
+
+    table
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Array(values) => values
+                    .into_iter()
+                    .map(|value| match value {
+                        toml::Value::String(value) => value,
+                        other => other.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(","),
+                toml::Value::Table(_) => {
+                    return Err(failure::format_err!(
+                        "{:?} should be a value, not a table",
+                        key
+                    ))
+                }
+                other => other.to_string(),
+            };
+
+            Ok((format!("{}{}", PREFIX, key.to_uppercase()), value))
+        })
+        .collect()
+}
+
+/// The key of the config file for the `name` environment variable.
+fn file_key(name: &str) -> String {
+    name.trim_start_matches(PREFIX).to_lowercase()
+}
+
+fn is_secret(name: &str) -> bool {
+    SECRET_MARKERS.iter().any(|marker| name.contains(marker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KNOWN: &[&str] = &[
+        "PAPERS_TEST_SETTINGS_BUCKET",
+        "PAPERS_TEST_SETTINGS_LEVEL",
+        "PAPERS_TEST_SETTINGS_MAX_REDIRECTS",
+        "PAPERS_TEST_SETTINGS_REGION",
+        "PAPERS_TEST_SETTINGS_SCHEMES",
+        "PAPERS_TEST_SETTINGS_SECRET_ACCESS_KEY",
+        "PAPERS_TEST_SETTINGS_UNUSED",
+    ];
+
+    fn settings(src: &str) -> Settings {
+        Settings {
+            file: parse_file(src).unwrap(),
+            effective: RefCell::new(BTreeMap::new()),
+            errors: RefCell::new(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn file_keys_are_environment_variable_names() {
+        let settings = settings(
+            r#"
+                test_settings_bucket = "walrus"
+                test_settings_max_redirects = 3
+                test_settings_schemes = ["http", "https"]
+            "#,
+        );
+
+        assert_eq!(
+            settings
+                .get("PAPERS_TEST_SETTINGS_BUCKET")
+                .as_ref()
+                .map(String::as_str),
+            Some("walrus")
+        );
+        assert_eq!(
+            settings.parse_or("PAPERS_TEST_SETTINGS_MAX_REDIRECTS", "a number", 5),
+            3
+        );
+        assert_eq!(
+            settings.get_or("PAPERS_TEST_SETTINGS_SCHEMES", "https"),
+            "http,https"
+        );
+        assert!(settings.finish(KNOWN).is_ok());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let settings = settings(
+            r#"
+                test_settings_max_redirects = "many"
+                test_settings_typo = true
+            "#,
+        );
+
+        assert_eq!(
+            settings.parse_or("PAPERS_TEST_SETTINGS_MAX_REDIRECTS", "a number", 5),
+            5
+        );
+        settings.required("PAPERS_TEST_SETTINGS_BUCKET");
+
+        assert_eq!(
+            settings.finish(KNOWN).unwrap_err().0,
+            vec![
+                "PAPERS_TEST_SETTINGS_MAX_REDIRECTS should be a number, got \"many\"",
+                "PAPERS_TEST_SETTINGS_BUCKET is required",
+                "Unknown setting \"test_settings_typo\" in the config file",
+            ]
+        );
+    }
+
+    #[test]
+    fn known_settings_are_accepted_even_if_unread() {
+        let settings = settings(
+            r#"
+                test_settings_unused = "leftover"
+            "#,
+        );
+
+        assert!(settings.finish(KNOWN).is_ok());
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let settings = settings(
+            r#"
+                test_settings_secret_access_key = "hunter2"
+                test_settings_region = "eu-central-1"
+            "#,
+        );
+
+        settings.get("PAPERS_TEST_SETTINGS_SECRET_ACCESS_KEY");
+        settings.get("PAPERS_TEST_SETTINGS_REGION");
+        settings.get_or("PAPERS_TEST_SETTINGS_LEVEL", "info");
+
+        assert_eq!(
+            settings.finish(KNOWN).unwrap().to_string(),
+            "test_settings_level = \"info\" # default\n\
+             test_settings_region = \"eu-central-1\" # file\n\
+             test_settings_secret_access_key = \"[redacted]\" # file\n"
+        );
+    }
+}
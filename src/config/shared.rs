@@ -0,0 +1,89 @@
+//! The configuration of the running server, swapped when it is reloaded.
+
+use super::Config;
+use futures01::{Future, Stream};
+use slog::{error, info};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// How often the config file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A handle to the current configuration. Requests take a snapshot of it when they are received,
+/// so the jobs they start keep the configuration they started with after a reload.
+#[derive(Debug, Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    /// The current configuration.
+    pub fn current(&self) -> Arc<Config> {
+        Arc::clone(&self.0.read().expect("poisoned lock"))
+    }
+
+    /// Switch new requests over to `config`.
+    pub fn swap(&self, config: Config) {
+        *self.0.write().expect("poisoned lock") = Arc::new(config);
+    }
+
+    /// Load the configuration again on SIGHUP and when the config file at `file` changes, and
+    /// swap it if it is valid. Invalid configurations are logged, and the current one is kept.
+    ///
+    /// The port and TLS settings of the server can't change without a restart.
+    pub fn reload_on_change(self, file: Option<PathBuf>) -> impl Future<Item = (), Error = ()> {
+        let paths = file.iter().cloned().collect();
+        let error_config = self.clone();
+
+        crate::utils::watch::changes(paths, WATCH_INTERVAL)
+            .for_each(move |()| {
+                let logger = self.current().logger.clone();
+
+                match Config::load(file.as_ref().map(PathBuf::as_path)) {
+                    Ok(config) => {
+                        self.swap(config);
+                        info!(logger, "Reloaded the configuration.");
+                    }
+                    Err(errors) => {
+                        for problem in errors.0 {
+                            error!(logger, "Could not reload the configuration: {}", problem);
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .map_err(move |err| {
+                error!(
+                    error_config.current().logger,
+                    "Stopped watching the configuration: {}", err
+                )
+            })
+    }
+}
+
+impl From<Arc<Config>> for SharedConfig {
+    fn from(config: Arc<Config>) -> SharedConfig {
+        SharedConfig(Arc::new(RwLock::new(config)))
+    }
+}
+
+impl From<Config> for SharedConfig {
+    fn from(config: Config) -> SharedConfig {
+        SharedConfig::from(Arc::new(config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_are_not_affected_by_swaps() {
+        let shared = SharedConfig::from(Config::for_tests());
+        let snapshot = shared.current();
+
+        shared.swap(Config::for_tests().with_max_assets_per_document(1));
+
+        assert_eq!(shared.current().max_assets_per_document, 1);
+        assert_ne!(snapshot.max_assets_per_document, 1);
+    }
+}
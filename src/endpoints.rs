@@ -1,7 +1,11 @@
 mod merge;
 mod preview;
+mod readyz;
+mod split;
 mod submit;
 
 pub(crate) use merge::merge;
 pub(crate) use preview::preview;
+pub(crate) use readyz::readyz;
+pub(crate) use split::split;
 pub(crate) use submit::submit;
@@ -1,16 +1,22 @@
 use crate::papers::Merger;
+use crate::auth::Identity;
 use crate::prelude::*;
+use crate::rate_limit::JobSlot;
+use crate::request_context::RequestContext;
 use futures::{FutureExt, TryFutureExt};
 
-pub(crate) async fn merge(merge_spec: MergeSpec, config: Arc<Config>) -> Result<Response, EndpointError> {
-    merge_spec.validate()?;
+pub(crate) async fn merge(merge_spec: MergeSpec, config: Arc<Config>, identity: Identity, slot: JobSlot, context: RequestContext) -> Result<Response, EndpointError> {
+    merge_spec.validate(&config)?;
 
-    tokio::executor::spawn(
-        Merger::new(config, merge_spec)?
-            .merge_documents()
-            .boxed()
-            .compat(),
-    );
+    let job = Merger::new(config, merge_spec, &identity, context)?
+        .merge_documents()
+        // The job quota of the client is released when the job is done.
+        .map(move |result| {
+            drop(slot);
+            result
+        });
+
+    tokio::executor::spawn(crate::metrics::track_job("merge", job).boxed().compat());
 
     Ok(empty_response())
 }
@@ -1,10 +1,13 @@
+use crate::auth::Identity;
 use crate::prelude::*;
 use crate::papers::Renderer;
+use crate::rate_limit::JobSlot;
+use crate::request_context::RequestContext;
 
-pub(crate) async fn preview(document_spec: DocumentSpec, config: Arc<Config>) -> Result<Response, EndpointError> {
+pub(crate) async fn preview(document_spec: DocumentSpec, config: Arc<Config>, identity: Identity, _slot: JobSlot, context: RequestContext) -> Result<Response, EndpointError> {
     document_spec.validate(&config)?;
 
-    let mut renderer = Renderer::new(config, document_spec)?;
+    let mut renderer = Renderer::new(config, document_spec, &identity, context)?;
     let populated_template = renderer.preview().await?;
 
     Ok(http::Response::new(populated_template.into()))
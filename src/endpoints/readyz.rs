@@ -0,0 +1,22 @@
+use crate::prelude::*;
+use crate::readiness::ReadinessCache;
+
+pub(crate) async fn readyz(
+    cache: Arc<ReadinessCache>,
+    config: Arc<Config>,
+    detailed: bool,
+) -> Result<Response, EndpointError> {
+    let readiness = cache.check(&config).await;
+
+    let mut response = if detailed {
+        json_response(&*readiness)?
+    } else {
+        json_response(&readiness.summary())?
+    };
+
+    if !readiness.ready {
+        *response.status_mut() = http::StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    Ok(response)
+}
@@ -0,0 +1,22 @@
+use crate::papers::Splitter;
+use crate::auth::Identity;
+use crate::prelude::*;
+use crate::rate_limit::JobSlot;
+use crate::request_context::RequestContext;
+use futures::{FutureExt, TryFutureExt};
+
+pub(crate) async fn split(split_spec: SplitSpec, config: Arc<Config>, identity: Identity, slot: JobSlot, context: RequestContext) -> Result<Response, EndpointError> {
+    split_spec.validate()?;
+
+    let job = Splitter::new(config, split_spec, &identity, context)?
+        .split_document()
+        // The job quota of the client is released when the job is done.
+        .map(move |result| {
+            drop(slot);
+            result
+        });
+
+    tokio::executor::spawn(crate::metrics::track_job("split", job).boxed().compat());
+
+    Ok(empty_response())
+}
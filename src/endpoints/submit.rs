@@ -1,17 +1,23 @@
+use crate::auth::Identity;
 use crate::papers::DocumentSpec;
 use crate::prelude::*;
 use crate::papers::Renderer;
+use crate::rate_limit::JobSlot;
+use crate::request_context::RequestContext;
 use futures::{FutureExt, TryFutureExt};
 
-pub(crate) async fn submit(document_spec: DocumentSpec, config: Arc<Config>) -> Result<Response, EndpointError> {
+pub(crate) async fn submit(document_spec: DocumentSpec, config: Arc<Config>, identity: Identity, slot: JobSlot, context: RequestContext) -> Result<Response, EndpointError> {
     document_spec.validate(&config)?;
 
-    tokio::executor::spawn(
-        Renderer::new(config, document_spec)?
-            .render()
-            .boxed()
-            .compat(),
-    );
+    let job = Renderer::new(config, document_spec, &identity, context)?
+        .render()
+        // The job quota of the client is released when the job is done.
+        .map(move |result| {
+            drop(slot);
+            result
+        });
+
+    tokio::executor::spawn(crate::metrics::track_job("submit", job).boxed().compat());
 
     Ok(empty_response())
 }
@@ -2,29 +2,36 @@ use regex::Regex;
 use std::str::FromStr;
 
 /// Represents a size as a number of bytes
-#[derive(Clone, Copy, Debug)]
-pub struct Bytes(pub u32);
-
-fn scale(quantity: u32, unit: &str) -> u32 {
-    match unit {
-        "K" => quantity * 1_000,
-        "M" => quantity * 1_000_000,
-        "G" => quantity * 1_000_000_000,
-        _ => unreachable!(),
-    }
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bytes(pub u64);
+
+/// The units sizes can be written with, from the largest. `K`, `M` and `G` are powers of 1000,
+/// `KiB`, `MiB` and `GiB` powers of 1024.
+const UNITS: &[(&str, u64)] = &[
+    ("GiB", 1 << 30),
+    ("G", 1_000_000_000),
+    ("MiB", 1 << 20),
+    ("M", 1_000_000),
+    ("KiB", 1 << 10),
+    ("K", 1_000),
+];
+
+fn scale(quantity: u64, unit: &str) -> Option<u64> {
+    let (_, factor) = UNITS.iter().find(|(name, _)| *name == unit)?;
+    quantity.checked_mul(*factor)
 }
 
 impl FromStr for Bytes {
     type Err = ();
 
     fn from_str(src: &str) -> Result<Bytes, ()> {
-        let human_readable: Regex = Regex::new(r"^\s*(\d+)([GKM])?\s*$").unwrap();
+        let human_readable: Regex = Regex::new(r"^\s*(\d+)([GKM](?:iB)?)?\s*$").unwrap();
 
         match human_readable.captures(src) {
             Some(captures) => {
-                let quantity: u32 = captures.get(1).unwrap().as_str().parse().unwrap();
+                let quantity: u64 = captures.get(1).unwrap().as_str().parse().map_err(|_| ())?;
                 match captures.get(2) {
-                    Some(unit) => Ok(Bytes(scale(quantity, unit.as_str()))),
+                    Some(unit) => scale(quantity, unit.as_str()).map(Bytes).ok_or(()),
                     None => Ok(Bytes(quantity)),
                 }
             }
@@ -33,6 +40,20 @@ impl FromStr for Bytes {
     }
 }
 
+/// Sizes are written with the largest unit that divides them, so they can be parsed back.
+impl std::fmt::Display for Bytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unit = UNITS
+            .iter()
+            .find(|(_, factor)| self.0 != 0 && self.0 % factor == 0);
+
+        match unit {
+            Some((name, factor)) => write!(f, "{}{}", self.0 / factor, name),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -48,14 +69,29 @@ mod tests {
         assert("10", Ok(10));
         assert("33", Ok(33));
         assert("1G", Ok(1_000_000_000));
+        assert("5G", Ok(5_000_000_000));
+        assert("4KiB", Ok(4096));
+        assert("2MiB", Ok(2_097_152));
+        assert("8GiB", Ok(8_589_934_592));
+        assert("1Gi", Err(()));
+        assert("99999999999999999999", Err(()));
+        assert("99999999999G", Err(()));
+    }
+
+    #[test]
+    fn test_bytes_display() {
+        assert_eq!(Bytes(10_000_000).to_string(), "10M");
+        assert_eq!(Bytes(1 << 30).to_string(), "1GiB");
+        assert_eq!(Bytes(1234).to_string(), "1234");
+        assert_eq!(Bytes(0).to_string(), "0");
     }
 
     #[derive(Clone, Debug)]
-    struct Unit(char);
+    struct Unit(&'static str);
 
     impl Arbitrary for Unit {
         fn arbitrary<G: Gen>(gen: &mut G) -> Unit {
-            let choices = ['K', 'M', 'G'];
+            let choices = ["K", "M", "G", "KiB", "MiB", "GiB"];
             Unit(choices[(gen.size() / usize::max_value()) as usize])
         }
     }
@@ -69,14 +105,18 @@ mod tests {
 
         }
 
-        fn human_size_without_unit_roundtrips(quantity: u32) -> bool {
+        fn human_size_without_unit_roundtrips(quantity: u64) -> bool {
             let src = format!("{}", quantity);
             Bytes::from_str(&src).unwrap().0 == quantity
         }
 
         fn human_size_with_unit_roundtrips(quantity: u32, unit: Unit) -> bool {
-            let src = format!("{}{}", quantity, unit.0.to_string());
-            Bytes::from_str(&src).unwrap().0 == scale(quantity, &unit.0.to_string())
+            let src = format!("{}{}", quantity, unit.0);
+            Bytes::from_str(&src).unwrap().0 == scale(u64::from(quantity), unit.0).unwrap()
+        }
+
+        fn human_size_display_roundtrips(quantity: u64) -> bool {
+            Bytes::from_str(&Bytes(quantity).to_string()) == Ok(Bytes(quantity))
         }
     }
 }
@@ -1,3 +1,4 @@
+use lazy_static::lazy_static;
 use regex::Regex;
 use serde_json::Value;
 
@@ -37,6 +38,197 @@ fn transform_strings(json: Value, callback: &dyn Fn(&str) -> String) -> Value {
     }
 }
 
+/// A TeX source that uses primitives which could run commands, write files, read files outside
+/// of the workspace, or hide such primitives.
+#[derive(Debug)]
+pub struct ForbiddenPrimitives {
+    /// The name of the offending file.
+    pub file: String,
+    /// What was found, with line numbers.
+    pub findings: Vec<String>,
+}
+
+impl std::fmt::Display for ForbiddenPrimitives {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Security error: {} uses forbidden TeX primitives: {}.",
+            self.file,
+            self.findings.join("; ")
+        )
+    }
+}
+
+impl failure::Fail for ForbiddenPrimitives {}
+
+/// The patterns we reject, with their description.
+const FORBIDDEN_PATTERNS: &[(&str, &str)] = &[
+    (r"\\write\s*18", "\\write18 (shell escape)"),
+    (r"\\immediate\s*\\write", "\\immediate\\write"),
+    (r"\\openout\b", "\\openout"),
+    (r"\\(?:directlua|latelua|luaexec|luadirect)\b", "Lua code"),
+    (r"\\catcode\b", "\\catcode changes"),
+    (r"\^\^[0-9a-fA-F]{2}", "^^ character escapes"),
+    (
+        r"\\csname\s*(?:write|immediate|openout|input|directlua)",
+        "\\csname constructed primitives",
+    ),
+    (
+        r"\\let\s*(?:\\[A-Za-z@]+|\\csname[^\\]*\\endcsname)\s*=?\s*\\(?:write|immediate|openout|openin|input|include|InputIfFileExists|directlua|latelua|luaexec|luadirect|catcode|csname)\b",
+        "aliases of forbidden primitives",
+    ),
+    (
+        r"\\(?:[gex]?def|(?:re)?newcommand\*?|providecommand\*?)\s*\{?\s*(?:\\[A-Za-z@]+|\\csname[^\\]*\\endcsname)\s*\}?[^{]*\{\s*\\(?:(?:write|immediate|openout|openin|directlua|latelua|luaexec|luadirect|catcode|csname)\b|(?:input|include)\s*\})",
+        "aliases of forbidden primitives",
+    ),
+];
+
+lazy_static! {
+    static ref FORBIDDEN_REGEXES: Vec<(Regex, &'static str)> = FORBIDDEN_PATTERNS
+        .iter()
+        .map(|(pattern, description)| (Regex::new(pattern).unwrap(), *description))
+        .collect();
+    static ref INPUT_REGEX: Regex =
+        Regex::new(r"\\(?:input|include|InputIfFileExists|openin)\b\s*\{?\s*([^\s}]*)").unwrap();
+}
+
+/// Scan `source` for primitives that a template should not need: shell escape, writing files,
+/// reading files outside of the working directory, Lua, and catcode tricks or aliases that could
+/// hide any of these. Reading the files for which `unscanned` is true is also rejected, since
+/// they are not checked.
+///
+/// Like TeX, the scan ignores comments, and treats line breaks as spaces, so primitives split
+/// across lines are found too. Findings are reported on the line where they start.
+pub fn check_source(
+    file: &str,
+    source: &str,
+    unscanned: &dyn Fn(&str) -> bool,
+) -> Result<(), ForbiddenPrimitives> {
+    let (text, line_starts) = normalize_source(source);
+    let line_of = |offset: usize| {
+        line_starts
+            .iter()
+            .rposition(|start| *start <= offset)
+            .unwrap_or_default()
+            + 1
+    };
+
+    let mut findings = Vec::new();
+
+    for (pattern, description) in FORBIDDEN_REGEXES.iter() {
+        for found in pattern.find_iter(&text) {
+            findings.push((line_of(found.start()), description.to_string()));
+        }
+    }
+
+    for captures in INPUT_REGEX.captures_iter(&text) {
+        let line = line_of(captures.get(0).map_or(0, |input| input.start()));
+        let path = &captures[1];
+
+        if is_outside_path(path) {
+            findings.push((line, format!("\\input of {:?}", path)));
+        } else if unscanned(path.trim_matches('"')) {
+            findings.push((line, format!("\\input of the unchecked file {:?}", path)));
+        }
+    }
+
+    // The sort is stable, so the findings of a line keep the order of the patterns.
+    findings.sort_by_key(|(line, _)| *line);
+    findings.dedup();
+
+    if findings.is_empty() {
+        Ok(())
+    } else {
+        Err(ForbiddenPrimitives {
+            file: file.to_owned(),
+            findings: findings
+                .into_iter()
+                .map(|(line, finding)| format!("line {}: {}", line, finding))
+                .collect(),
+        })
+    }
+}
+
+/// Read `source` the way TeX does: comments are removed along with their line break, the other
+/// line breaks become spaces, and the spaces at the start of lines are skipped. Runs of spaces
+/// are collapsed into one. Returns the text and the offset of each line in it.
+fn normalize_source(source: &str) -> (String, Vec<usize>) {
+    let mut text = String::with_capacity(source.len());
+    let mut line_starts = Vec::new();
+
+    for line in source.lines() {
+        let stripped = strip_comment(line);
+        line_starts.push(text.len());
+
+        for character in stripped.trim_start().chars() {
+            if character.is_whitespace() {
+                if !text.ends_with(' ') {
+                    text.push(' ');
+                }
+            } else {
+                text.push(character);
+            }
+        }
+
+        if stripped.len() == line.len() && !text.ends_with(' ') {
+            text.push(' ');
+        }
+    }
+
+    (text, line_starts)
+}
+
+/// Whether `content` starts with the signature of a binary format assets commonly have: images,
+/// PDFs and fonts. These files are not scanned for TeX primitives.
+pub fn is_binary(content: &[u8]) -> bool {
+    const SIGNATURES: &[&[u8]] = &[
+        b"\x89PNG\r\n\x1a\n",
+        b"\xff\xd8\xff",
+        b"GIF87a",
+        b"GIF89a",
+        b"%PDF-",
+        b"II*\x00",
+        b"MM\x00*",
+        b"OTTO",
+        b"\x00\x01\x00\x00",
+        b"wOFF",
+        b"wOF2",
+    ];
+
+    SIGNATURES
+        .iter()
+        .any(|signature| content.starts_with(signature))
+}
+
+/// Remove the comment at the end of `line`, if any. `\%` is an escaped percent sign.
+fn strip_comment(line: &str) -> &str {
+    let mut escaped = false;
+
+    for (index, character) in line.char_indices() {
+        match character {
+            '%' if !escaped => return &line[..index],
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+
+    line
+}
+
+/// Whether `path` is absolute, relative to the home directory, or goes up the directory tree.
+fn is_outside_path(path: &str) -> bool {
+    let path = path.trim_matches('"');
+    let bytes = path.as_bytes();
+    let windows_drive = bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+
+    path.starts_with('/')
+        || path.starts_with('~')
+        || windows_drive
+        || path
+            .split(|c| c == '/' || c == '\\')
+            .any(|part| part == "..")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -109,6 +301,117 @@ mod tests {
         assert_eq!(escape_tex(original), expected);
     }
 
+    #[test]
+    fn check_source_accepts_ordinary_templates() {
+        let source = r"\documentclass{article}
+\usepackage{graphicx}
+\input{sections/intro}
+\includegraphics{logo.png}
+% \write18{rm -rf /} is only a comment
+Discount: 30\% \immediate is fine on its own
+";
+
+        assert!(check_source("template.tex", source, &|_| false).is_ok());
+    }
+
+    #[test]
+    fn check_source_rejects_dangerous_primitives() {
+        let source = r"\immediate\write18{curl example.com}
+\newwrite\out \openout\out=notes.txt
+\input{/etc/passwd}
+\input ../../secrets
+\directlua{os.execute('id')}
+\catcode`\Z=0
+^^5cwrite18{id}
+\input{images/../../../etc/hosts}
+";
+
+        let error = check_source("evil.sty", source, &|_| false).unwrap_err();
+
+        assert_eq!(error.file, "evil.sty");
+        assert_eq!(
+            error.findings,
+            vec![
+                "line 1: \\write18 (shell escape)",
+                "line 1: \\immediate\\write",
+                "line 2: \\openout",
+                "line 3: \\input of \"/etc/passwd\"",
+                "line 4: \\input of \"../../secrets\"",
+                "line 5: Lua code",
+                "line 6: \\catcode changes",
+                "line 7: ^^ character escapes",
+                "line 8: \\input of \"images/../../../etc/hosts\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_source_rejects_aliases_of_dangerous_primitives() {
+        let source = r"\let\w=\write \immediate\w18{id}
+\global\let\o\openout
+\def\w{\write}
+\newcommand{\i}{\input}
+\newcommand{\chapter}[1]{\input{chapters/#1}}
+";
+
+        let error = check_source("evil.tex", source, &|_| false).unwrap_err();
+
+        assert_eq!(
+            error.findings,
+            vec![
+                "line 1: aliases of forbidden primitives",
+                "line 2: \\openout",
+                "line 2: aliases of forbidden primitives",
+                "line 3: aliases of forbidden primitives",
+                "line 4: aliases of forbidden primitives",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_source_rejects_primitives_split_across_lines() {
+        let source = r"\immediate\write
+18{id}
+\let\w=
+  \write
+\open%
+out\x=notes.txt
+\expandafter\let\csname w\endcsname\write
+\expandafter\def\csname i\endcsname{\input}
+";
+
+        let error = check_source("evil.tex", source, &|_| false).unwrap_err();
+
+        assert_eq!(
+            error.findings,
+            vec![
+                "line 1: \\write18 (shell escape)",
+                "line 1: \\immediate\\write",
+                "line 3: aliases of forbidden primitives",
+                "line 5: \\openout",
+                "line 7: aliases of forbidden primitives",
+                "line 8: aliases of forbidden primitives",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_source_rejects_inputs_of_unchecked_files() {
+        let source = r"\input{logo.png}
+\input{chapter}
+";
+        let unscanned = |path: &str| path == "logo.png";
+
+        let error = check_source("template.tex", source, &unscanned).unwrap_err();
+
+        assert_eq!(
+            error.findings,
+            vec!["line 1: \\input of the unchecked file \"logo.png\""]
+        );
+        assert!(is_binary(b"\x89PNG\r\n\x1a\n\x00\x00"));
+        assert!(!is_binary(b"\\immediate\\write18{id}"));
+    }
+
     quickcheck! {
         fn escape_tex_and_unescape_tex_roundtrip(input: String) -> bool {
             input == unescape_tex_string(&escape_tex_string(&input))
@@ -21,10 +21,16 @@ mod human_size;
 mod latex;
 /// Papers local.
 pub mod local_server;
+mod metrics;
 /// Core logic for the asynchronous jobs.
 pub mod papers;
 /// Prelude.
 mod prelude;
+mod rate_limit;
+mod readiness;
+mod request_context;
+/// TLS termination.
+pub mod tls;
 /// Utility modules.
 pub mod utils;
 
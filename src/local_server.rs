@@ -55,6 +55,7 @@ pub fn render_locally() {
         template_url: PapersUri("unreachable".parse().unwrap()),
         variables,
         no_escape_tex: std::default::Default::default(),
+        post_processing: std::default::Default::default(),
     };
 
     let exit_status = render(document_spec);
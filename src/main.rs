@@ -1,7 +1,9 @@
 #![deny(warnings)]
 
 use dotenv::dotenv;
-use std::sync::Arc;
+use futures01::future::{lazy, Either};
+use papers::config::SharedConfig;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -12,27 +14,46 @@ enum Command {
     Local,
     #[structopt(name = "version", help = "Prints the current version of Papers")]
     Version,
+    #[structopt(name = "config", help = "Inspect the configuration")]
+    Config(ConfigCommand),
     #[structopt(name = "help")]
     Help,
 }
 
+#[derive(StructOpt, Debug)]
+enum ConfigCommand {
+    #[structopt(
+        name = "check",
+        help = "Validate the configuration and print the effective settings, with secrets redacted"
+    )]
+    Check,
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(
     name = "papers",
     about = "A Latex template to PDF generation web service written in Rust."
 )]
 struct Cli {
+    /// A TOML config file. Environment variables override its settings.
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
     #[structopt(subcommand)]
     command: Option<Command>,
 }
 
+/// Load the configuration, or print every problem found and exit.
+fn load_config(file: Option<&Path>) -> (papers::Config, papers::config::EffectiveSettings) {
+    papers::Config::load_effective(file).unwrap_or_else(|errors| {
+        eprint!("{}", errors);
+        std::process::exit(1)
+    })
+}
+
 fn main() -> Result<(), failure::Error> {
     dotenv().ok();
     pretty_env_logger::init();
 
-    let port = std::env::var("PAPERS_PORT").unwrap_or_else(|_| "8080".to_string());
-    let port: std::net::SocketAddr = ([0, 0, 0, 0], port.parse()?).into();
-
     let sentry_dsn = std::env::var("SENTRY_DSN").unwrap_or_else(|_| "".to_string());
     let _guard = sentry::init(sentry_dsn);
     sentry::integrations::panic::register_panic_handler();
@@ -40,9 +61,29 @@ fn main() -> Result<(), failure::Error> {
     let opts = Cli::from_args();
     match opts.command {
         Some(Command::Server) | None => {
-            warp::serve(papers::app(Arc::new(papers::Config::from_env()))).run(port)
+            let (config, _) = load_config(opts.config.as_ref().map(PathBuf::as_path));
+            let port: std::net::SocketAddr = ([0, 0, 0, 0], config.port).into();
+            let tls = config.tls.is_some();
+            let config = SharedConfig::from(config);
+            let reload = config.clone().reload_on_change(opts.config);
+
+            let server = if tls {
+                Either::A(papers::tls::serve(config, port)?)
+            } else {
+                slog::info!(config.current().logger, "Server started on http://{}", port);
+                Either::B(warp::serve(papers::app(config)).bind(port))
+            };
+
+            tokio::run(lazy(move || {
+                tokio::spawn(reload);
+                server
+            }));
         }
         Some(Command::Local) => papers::local_server::render_locally(),
+        Some(Command::Config(ConfigCommand::Check)) => {
+            let (_, effective) = load_config(opts.config.as_ref().map(PathBuf::as_path));
+            print!("{}", effective);
+        }
         Some(Command::Version) => println!(env!("CARGO_PKG_VERSION")),
         Some(Command::Help) => Cli::clap().print_help().unwrap(),
     }
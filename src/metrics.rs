@@ -0,0 +1,236 @@
+//! Prometheus metrics, served on `GET /metrics`.
+//!
+//! The metrics are global, so they survive configuration reloads and cover every job of the
+//! process.
+
+use crate::prelude::*;
+use futures::Future;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramTimer, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+/// The buckets of the step durations, in seconds. Steps take from milliseconds, like most
+/// uploads, to minutes for large LaTeX documents.
+const STEP_DURATION_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+lazy_static! {
+    static ref JOBS_SUBMITTED: IntCounterVec = register_int_counter_vec!(
+        "papers_jobs_submitted_total",
+        "Jobs accepted, by endpoint.",
+        &["endpoint"]
+    )
+    .unwrap();
+    static ref JOBS_SUCCEEDED: IntCounterVec = register_int_counter_vec!(
+        "papers_jobs_succeeded_total",
+        "Jobs reported as successful to their callback URL, by endpoint.",
+        &["endpoint"]
+    )
+    .unwrap();
+    static ref JOBS_FAILED: IntCounterVec = register_int_counter_vec!(
+        "papers_jobs_failed_total",
+        "Jobs that failed or timed out, by endpoint.",
+        &["endpoint"]
+    )
+    .unwrap();
+    static ref JOBS_QUEUED: IntGauge = register_int_gauge!(
+        "papers_jobs_queued",
+        "Jobs accepted and waiting for the runtime to start them."
+    )
+    .unwrap();
+    static ref JOBS_ACTIVE: IntGauge =
+        register_int_gauge!("papers_jobs_active", "Jobs running.").unwrap();
+    static ref STEP_DURATION: HistogramVec = register_histogram_vec!(
+        "papers_step_duration_seconds",
+        "Duration of the steps of jobs, by step.",
+        &["step"],
+        STEP_DURATION_BUCKETS.to_vec()
+    )
+    .unwrap();
+    static ref DOWNLOADED_BYTES: IntCounter = register_int_counter!(
+        "papers_downloaded_bytes_total",
+        "Bytes downloaded for templates, assets and documents."
+    )
+    .unwrap();
+    static ref UPLOADED_BYTES: IntCounter = register_int_counter!(
+        "papers_uploaded_bytes_total",
+        "Bytes uploaded to S3, including debug archives."
+    )
+    .unwrap();
+    static ref CALLBACK_RESPONSES: IntCounterVec = register_int_counter_vec!(
+        "papers_callback_responses_total",
+        "Responses of callback URLs, by status class. Requests that got no response are counted as \"error\".",
+        &["class"]
+    )
+    .unwrap();
+}
+
+/// Register every metric. They are registered when first used otherwise, so `/metrics` would
+/// miss the ones that were never updated, like `papers_jobs_active` before the first job.
+pub(crate) fn init() {
+    lazy_static::initialize(&JOBS_SUBMITTED);
+    lazy_static::initialize(&JOBS_SUCCEEDED);
+    lazy_static::initialize(&JOBS_FAILED);
+    lazy_static::initialize(&JOBS_QUEUED);
+    lazy_static::initialize(&JOBS_ACTIVE);
+    lazy_static::initialize(&STEP_DURATION);
+    lazy_static::initialize(&DOWNLOADED_BYTES);
+    lazy_static::initialize(&UPLOADED_BYTES);
+    lazy_static::initialize(&CALLBACK_RESPONSES);
+}
+
+/// A step of a job, timed in `papers_step_duration_seconds`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Step {
+    /// Downloading a template, an asset or a document.
+    Download,
+    /// Rendering a Tera template.
+    RenderTemplate,
+    /// Running xelatex.
+    Latex,
+    /// Merging PDFs with pdfunite.
+    Merge,
+    /// Uploading a document or a debug archive to S3.
+    S3Upload,
+    /// Posting the summary to the callback URL.
+    Callback,
+}
+
+impl Step {
+    fn label(self) -> &'static str {
+        match self {
+            Step::Download => "download",
+            Step::RenderTemplate => "render_template",
+            Step::Latex => "latex",
+            Step::Merge => "merge",
+            Step::S3Upload => "s3_upload",
+            Step::Callback => "callback",
+        }
+    }
+}
+
+/// Start timing `step`. The duration is recorded when the timer is dropped, whether the step
+/// succeeded or not.
+pub(crate) fn step_timer(step: Step) -> HistogramTimer {
+    STEP_DURATION
+        .with_label_values(&[step.label()])
+        .start_timer()
+}
+
+/// Increments a gauge while it is alive.
+struct GaugeGuard(&'static IntGauge);
+
+impl GaugeGuard {
+    fn new(gauge: &'static IntGauge) -> GaugeGuard {
+        gauge.inc();
+        GaugeGuard(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Count `job` as submitted to `endpoint`. It is counted as queued until it is first polled,
+/// then as active until it is done or dropped.
+pub(crate) fn track_job<F: Future>(
+    endpoint: &'static str,
+    job: F,
+) -> impl Future<Output = F::Output> {
+    JOBS_SUBMITTED.with_label_values(&[endpoint]).inc();
+    let queued = GaugeGuard::new(&JOBS_QUEUED);
+
+    async move {
+        drop(queued);
+        let _active = GaugeGuard::new(&JOBS_ACTIVE);
+        job.await
+    }
+}
+
+/// Count a job of `endpoint` as succeeded or failed.
+pub(crate) fn job_finished<T, E>(endpoint: &'static str, result: &Result<T, E>) {
+    let counter = if result.is_ok() {
+        &*JOBS_SUCCEEDED
+    } else {
+        &*JOBS_FAILED
+    };
+
+    counter.with_label_values(&[endpoint]).inc();
+}
+
+/// Count `bytes` downloaded bytes.
+pub(crate) fn downloaded(bytes: u64) {
+    DOWNLOADED_BYTES.inc_by(bytes as i64);
+}
+
+/// Count `bytes` uploaded bytes.
+pub(crate) fn uploaded(bytes: u64) {
+    UPLOADED_BYTES.inc_by(bytes as i64);
+}
+
+/// Count a callback response with `status`, or a callback that got no response.
+pub(crate) fn callback_response(status: Option<http::StatusCode>) {
+    let class = match status.map(|status| status.as_u16() / 100) {
+        Some(1) => "1xx",
+        Some(2) => "2xx",
+        Some(3) => "3xx",
+        Some(4) => "4xx",
+        Some(5) => "5xx",
+        _ => "error",
+    };
+
+    CALLBACK_RESPONSES.with_label_values(&[class]).inc();
+}
+
+/// The metrics in the Prometheus text format.
+pub(crate) fn response() -> Result<Response, failure::Error> {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder.encode(&prometheus::gather(), &mut body)?;
+
+    let mut response = http::Response::new(body.into());
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::header::HeaderValue::from_static(prometheus::TEXT_FORMAT),
+    );
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_registered_before_their_first_use() {
+        init();
+
+        let names: Vec<String> = prometheus::gather()
+            .iter()
+            .map(|family| family.get_name().to_owned())
+            .collect();
+
+        assert!(names.contains(&"papers_jobs_active".to_owned()));
+        assert!(names.contains(&"papers_downloaded_bytes_total".to_owned()));
+    }
+
+    #[test]
+    fn metrics_are_exposed_in_the_text_format() {
+        job_finished::<(), ()>("split", &Ok(()));
+        callback_response(Some(http::StatusCode::BAD_GATEWAY));
+        drop(step_timer(Step::Latex));
+
+        let response = response().unwrap();
+        let body = futures01::Stream::concat2(response.into_body());
+        let body = futures01::Future::wait(body).unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(body.contains("papers_jobs_succeeded_total{endpoint=\"split\"}"));
+        assert!(body.contains("papers_callback_responses_total{class=\"5xx\"}"));
+        assert!(body.contains("papers_step_duration_seconds_count{step=\"latex\"}"));
+    }
+}
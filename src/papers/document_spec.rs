@@ -1,5 +1,6 @@
 use crate::latex::escape_tex;
 use crate::papers::uri::PapersUri;
+use crate::papers::PostProcessing;
 use crate::prelude::*;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
@@ -21,6 +22,8 @@ pub struct DocumentSpec {
     pub variables: serde_json::Value,
     #[serde(default = "return_false")]
     pub no_escape_tex: bool,
+    #[serde(flatten)]
+    pub post_processing: PostProcessing,
 }
 
 impl DocumentSpec {
@@ -40,6 +43,8 @@ impl DocumentSpec {
             });
         }
 
+        self.post_processing.validate(config)?;
+
         Ok(())
     }
 
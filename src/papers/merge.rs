@@ -1,13 +1,29 @@
-use crate::papers::{MergeSpec, Workspace};
+use crate::auth::Identity;
+use crate::metrics::Step;
+use crate::papers::renderer::{run_latex, write_rendered_template};
+use crate::papers::{post_process, CoverSpec, MergeSpec, Workspace};
 use crate::prelude::*;
+use crate::request_context::RequestContext;
+use crate::utils::http::extract_filename_from_uri;
+use crate::utils::pdf::page_count;
+use crate::utils::timeout::with_timeout;
+use serde_json::json;
 use std::future::Future;
-use futures::compat::*;
-use futures::{FutureExt, StreamExt, TryFutureExt};
+use futures::{FutureExt, StreamExt};
 use slog::{debug, error, Logger};
 use std::pin::Pin;
 use std::path::*;
 use std::process::Command;
-use tokio_process::CommandExt;
+use std::time::Duration;
+
+/// The name of the cover template inside the Tera instance.
+const COVER_TEMPLATE_NAME: &str = "cover";
+
+/// The file name of the rendered cover template. The cover PDF is produced next to it.
+const COVER_TEX_FILENAME: &str = "papers-cover.tex";
+
+/// How many times the cover is rendered at most while waiting for its page count to settle.
+const MAX_COVER_PASSES: usize = 3;
 
 pub struct Merger {
     /// The blueprint for the merged document.
@@ -19,9 +35,14 @@ pub struct Merger {
 }
 
 impl Merger {
-    pub fn new(config: Arc<Config>, merge_spec: MergeSpec) -> Result<Self, failure::Error> {
+    pub fn new(
+        config: Arc<Config>,
+        merge_spec: MergeSpec,
+        identity: &Identity,
+        context: RequestContext,
+    ) -> Result<Self, failure::Error> {
         let logger = config.logger.clone();
-        let workspace = Workspace::new(logger, config)?;
+        let workspace = Workspace::new(logger, config, identity, context)?;
 
         let output_path = workspace.temp_dir_path().join(&merge_spec.output_filename);
 
@@ -39,31 +60,40 @@ impl Merger {
     ///
     /// - Downloads the documents to merge
     /// - Converts those that are not PDFs to PDF
+    /// - Renders the cover page, if the `MergeSpec` has one
     /// - Merges the PDFs
+    /// - Applies the post-processing from the `MergeSpec` (watermarks, stamps...)
     /// - Uploads the result to S3
     /// - Reports to the `callback_url` from the `MergeSpec` with the error or presigned url of
     /// the generated document.
-    /// - Uploads the debugging output to S3 as a tar file.
+    /// - Uploads the debugging output to S3 as a tar file, if the retention policy keeps it.
     ///
     /// This method takes ownership because it is meant to be used to create futures to be
     /// spawned in the background.
     pub async fn merge_documents(self) -> Result<(), ()> {
-        self.merge_documents_inner()
-            .or_else(|err| self.report_failure(err))
-            .await
-            .ok();
+        let job_timeout = self.workspace.config().job_timeout;
+
+        let result = with_timeout(self.merge_documents_inner(), job_timeout, "the job").await;
+        crate::metrics::job_finished("merge", &result);
+        let error = result.as_ref().err().map(display_error);
+
+        if let Err(err) = result {
+            self.report_failure(err).await.ok();
+        }
 
         self.workspace
-            .upload_workspace()
+            .upload_workspace(error.is_some())
             .await
             .map_err(|err| {
                 error!(
                     self.workspace.logger(),
-                    "Error uploading workspace.tar: {:?}.", err
+                    "Error uploading the workspace archive: {:?}.", err
                 )
             })
             .ok();
 
+        self.workspace.export_trace("merge", error);
+
         Ok(())
     }
 
@@ -76,15 +106,42 @@ impl Merger {
 
         // Convert
         let converted_paths = self
-            .convert_assets_to_pdf(asset_paths)
+            .workspace
+            .traced("convert", self.convert_assets_to_pdf(asset_paths))
             .await
             .context("Error converting asset file to PDF.")?;
 
+        // Cover
+        let converted_paths = match &self.merge_spec.cover {
+            Some(cover) => {
+                let cover_path = self
+                    .workspace
+                    .traced("render_cover", self.render_cover(cover, &converted_paths))
+                    .await
+                    .context("Error rendering the cover page.")?;
+                std::iter::once(cover_path).chain(converted_paths).collect()
+            }
+            None => converted_paths,
+        };
+
         // Merge
-        self.merge_pdf(converted_paths)
+        self.workspace
+            .traced("merge", self.merge_pdf(converted_paths))
             .await
             .context("Error merging the PDFs.")?;
 
+        // Watermarks, stamps etc.
+        self.workspace
+            .traced(
+                "post_process",
+                post_process(
+                    &self.workspace,
+                    &self.merge_spec.post_processing,
+                    &self.output_path,
+                ),
+            )
+            .await?;
+
         // Upload the merged PDF
         let presigned_url = self
             .workspace
@@ -109,11 +166,13 @@ impl Merger {
 
         for path in asset_paths.into_iter() {
             let logger = self.workspace.logger().clone();
+            let command = self.workspace.command("convert");
+            let timeout = self.workspace.config().step_timeout;
             let to_pdf = move |path: PathBuf| -> Pin<Box<dyn Future<Output=Result<PathBuf, failure::Error>> + Send>> {
                 match path.extension() {
                     Some(extension) if extension == "pdf" => futures::future::ready(Ok(path)).boxed(),
                     None => futures::future::ready(Ok(path)).boxed(),
-                    Some(_) => image_to_pdf(logger, path.clone()).boxed(),
+                    Some(_) => image_to_pdf(logger, command, path.clone(), timeout).boxed(),
                 }
             };
             futures.push(to_pdf(path));
@@ -123,6 +182,69 @@ impl Merger {
         converted_paths.into_iter().collect()
     }
 
+    /// Render the cover page with the page map of the converted documents, and return the path
+    /// to the cover PDF.
+    async fn render_cover<'a>(
+        &'a self,
+        cover: &'a CoverSpec,
+        converted_paths: &'a [PathBuf],
+    ) -> Result<PathBuf, failure::Error> {
+        let template_path = self
+            .workspace
+            .download_file_with_prefix(&cover.template_url.0, "cover".to_owned())
+            .await?;
+
+        let mut tera = crate::utils::templating::make_tera();
+        tera.add_template_file(&template_path, Some(COVER_TEMPLATE_NAME))
+            .map_err(|err| format_err!("failed to add cover template: {:?}", err))?;
+
+        let timeout = self.workspace.config().step_timeout;
+        let mut documents = Vec::with_capacity(converted_paths.len());
+
+        for (uri, path) in self.merge_spec.asset_urls().zip(converted_paths) {
+            let filename = extract_filename_from_uri(uri).unwrap_or_default().to_owned();
+            documents.push((filename, page_count(path, timeout).await?));
+        }
+
+        let tex_path = self.workspace.temp_dir_path().join(COVER_TEX_FILENAME);
+        let pdf_path = tex_path.with_extension("pdf");
+
+        // The start pages depend on the length of the cover itself, so we assume a single page
+        // and render again if the cover turns out to be longer.
+        let mut cover_pages = 1;
+
+        for _ in 0..MAX_COVER_PASSES {
+            let variables = cover.variables(page_map(&documents, cover_pages));
+            let timer = crate::metrics::step_timer(Step::RenderTemplate);
+            let rendered_template = tera
+                .render(COVER_TEMPLATE_NAME, &variables)
+                .map_err(|err| format_err!("Rendering error: {}.", err))?;
+            drop(timer);
+
+            write_rendered_template(&tex_path, rendered_template).await?;
+            self.workspace.check_tex_sources(&[&tex_path])?;
+            run_latex(&self.workspace, &tex_path).await?;
+
+            let rendered_pages = page_count(&pdf_path, timeout).await?;
+
+            debug!(
+                self.workspace.logger(),
+                "Rendered a cover of {} page(s), expected {}.", rendered_pages, cover_pages
+            );
+
+            if rendered_pages == cover_pages {
+                return Ok(pdf_path);
+            }
+
+            cover_pages = rendered_pages;
+        }
+
+        Err(format_err!(
+            "The cover page count did not settle after {} renders.",
+            MAX_COVER_PASSES
+        ))
+    }
+
     /// Download the assets to merge, and returns the paths to the downloaded files, preserving
     /// the order of the assets.
     ///
@@ -163,14 +285,16 @@ impl Merger {
     }
 
     async fn merge_pdf(&self, converted_paths: Vec<PathBuf>) -> Result<(), failure::Error> {
-        let output = Command::new("pdfunite")
-            .current_dir(&self.workspace.temp_dir_path())
+        let _timer = crate::metrics::step_timer(Step::Merge);
+        let mut command = self.workspace.command("pdfunite");
+        command
             .args(converted_paths)
-            .arg(&self.merge_spec.output_filename)
-            .output_async()
-            .compat()
-            .await
-            .context("Error merging PDFs")?;
+            .arg(&self.merge_spec.output_filename);
+
+        let output =
+            crate::utils::process::output(command, "pdfunite", self.workspace.config().step_timeout)
+                .await
+                .context("Error merging PDFs")?;
 
         let stdout_and_err = crate::utils::process::whole_output(&output).expect("output is utf8");
 
@@ -186,24 +310,51 @@ impl Merger {
             ));
         };
 
-        Ok(())
+        self.workspace.check_disk_usage()
     }
 }
 
-/// Convert an image to an A4 pdf using imagemagick's `convert` command.
+/// Build the `papers` variables for the cover template from the file names and page counts of
+/// the merged documents.
+fn page_map(documents: &[(String, u32)], cover_pages: u32) -> serde_json::Value {
+    let mut next_page = cover_pages + 1;
+
+    let documents: Vec<serde_json::Value> = documents
+        .iter()
+        .map(|(filename, pages)| {
+            let entry = json!({
+                "filename": filename,
+                "start_page": next_page,
+                "pages": pages,
+            });
+            next_page += pages;
+            entry
+        })
+        .collect();
+
+    json!({
+        "documents": documents,
+        "cover_pages": cover_pages,
+        "total_pages": next_page - 1,
+    })
+}
+
+/// Convert an image to an A4 pdf using imagemagick's `convert` command. `command` is the
+/// `convert` command from the workspace, without arguments.
 ///
 /// Sample command:
 ///
 /// `convert sc.png -resize 1190x1684 -gravity center -background white -extent 1190x1684 sc.pdf`
 async fn image_to_pdf(
     logger: Logger,
+    mut command: Command,
     original_file_path: PathBuf,
+    timeout: Duration,
 ) -> Result<PathBuf, failure::Error> {
     // "/tmp/something.jpeg" -> "something"
     let stem = original_file_path.file_stem().expect("Invalid path");
     let final_path = original_file_path.with_file_name(format!("{}.pdf", stem.to_string_lossy()));
-    let output = Command::new("convert")
-        .current_dir(&original_file_path.parent().expect("Invalid path"))
+    command
         .arg(original_file_path)
         .arg("-resize")
         .arg("595x842")
@@ -217,9 +368,9 @@ async fn image_to_pdf(
         .arg("72")
         .arg("-page")
         .arg("A4")
-        .arg(&final_path)
-        .output_async()
-        .compat()
+        .arg(&final_path);
+
+    let output = crate::utils::process::output(command, "convert", timeout)
         .await
         .context("Error while converting image to pdf")?;
 
@@ -233,3 +384,26 @@ async fn image_to_pdf(
         Err(format_err!("Merge failed. Output:\n{}", stdout_and_err))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::page_map;
+    use serde_json::json;
+
+    #[test]
+    fn page_map_offsets_documents_by_the_cover() {
+        let documents = vec![("a.pdf".to_owned(), 3), ("b.png".to_owned(), 1)];
+
+        assert_eq!(
+            page_map(&documents, 2),
+            json!({
+                "documents": [
+                    { "filename": "a.pdf", "start_page": 3, "pages": 3 },
+                    { "filename": "b.png", "start_page": 6, "pages": 1 },
+                ],
+                "cover_pages": 2,
+                "total_pages": 6,
+            })
+        );
+    }
+}
@@ -1,7 +1,10 @@
+use crate::latex::escape_tex;
 use crate::papers::uri::PapersUri;
+use crate::papers::PostProcessing;
 use crate::prelude::*;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct MergeSpec {
@@ -10,6 +13,56 @@ pub struct MergeSpec {
     callback_url: PapersUri,
     #[serde(default = "default_output_filename")]
     pub output_filename: String,
+    /// An optional cover page, rendered from a Tera + LaTeX template and prepended to the merged
+    /// documents.
+    #[serde(default)]
+    pub cover: Option<CoverSpec>,
+    #[serde(flatten)]
+    pub post_processing: PostProcessing,
+}
+
+/// The template and variables for the cover page of a merge.
+///
+/// On top of `variables`, the template receives a `papers` object with the page map of the merged
+/// document:
+///
+/// - `papers.documents`: an array of `{ "filename", "start_page", "pages" }` objects, in merge
+///   order. `start_page` is 1-based and accounts for the cover pages.
+/// - `papers.cover_pages`: the number of pages of the cover itself.
+/// - `papers.total_pages`: the number of pages of the final document.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CoverSpec {
+    pub template_url: PapersUri,
+    #[serde(default = "default_value")]
+    pub variables: serde_json::Value,
+    #[serde(default)]
+    pub no_escape_tex: bool,
+}
+
+impl CoverSpec {
+    /// The variables for the cover template, with the `papers` page map merged in. The page map
+    /// is always escaped, since `no_escape_tex` only applies to the variables of the client and
+    /// the file names come from the asset URLs.
+    pub fn variables(&self, page_map: serde_json::Value) -> serde_json::Value {
+        let page_map = escape_tex(page_map);
+
+        let mut variables = if self.no_escape_tex {
+            self.variables.clone()
+        } else {
+            escape_tex(self.variables.clone())
+        };
+
+        if let Some(object) = variables.as_object_mut() {
+            object.insert("papers".to_owned(), page_map);
+            variables
+        } else {
+            json!({ "papers": page_map })
+        }
+    }
+}
+
+fn default_value() -> serde_json::Value {
+    json!({})
 }
 
 fn default_assets() -> Vec<PapersUri> {
@@ -32,12 +85,22 @@ impl MergeSpec {
     /// Validate that the specification is consistent, and that it can be expected to succeed.
     ///
     /// The error is intended for consumption by the client of the service.
-    pub fn validate(&self) -> Result<(), EndpointError> {
+    pub fn validate(&self, config: &Config) -> Result<(), EndpointError> {
         // Trying to merge 0 documents will not succeed
         if self.assets_urls.is_empty() {
             return Err(self.assets_count_error());
         }
 
+        if let Some(cover) = &self.cover {
+            if !cover.variables.is_object() {
+                return Err(EndpointError::UnprocessableEntity {
+                    cause: format_err!("The cover variables must be a JSON object."),
+                });
+            }
+        }
+
+        self.post_processing.validate(config)?;
+
         Ok(())
     }
 
@@ -71,7 +134,7 @@ mod tests {
 
         let serialized: MergeSpec = serde_json::from_value(wrong_spec_json).unwrap();
 
-        if let Err(EndpointError::UnprocessableEntity { cause: msg }) = serialized.validate() {
+        if let Err(EndpointError::UnprocessableEntity { cause: msg }) = serialized.validate(&Config::for_tests()) {
             assert_eq!(
                 msg.to_string(),
                 "Cannot merge with an empty asset_urls array."
@@ -81,4 +144,74 @@ mod tests {
 
         panic!("did not validate that asset_urls is not empty");
     }
+
+    #[test]
+    fn cover_variables_include_the_page_map() {
+        let spec: MergeSpec = serde_json::from_value(json!({
+            "assets_urls": ["https://example.com/a.pdf"],
+            "callback_url": "https://example.com/callback",
+            "cover": {
+                "template_url": "https://example.com/cover.tex.tera",
+                "variables": { "title": "Q3 & Q4" },
+            },
+        }))
+        .unwrap();
+
+        let page_map = json!({
+            "documents": [{ "filename": "a_b.pdf", "start_page": 2, "pages": 3 }],
+            "cover_pages": 1,
+            "total_pages": 4,
+        });
+
+        assert_eq!(
+            spec.cover.unwrap().variables(page_map),
+            json!({
+                "title": "Q3 \\& Q4",
+                "papers": {
+                    "documents": [{ "filename": "a\\_b.pdf", "start_page": 2, "pages": 3 }],
+                    "cover_pages": 1,
+                    "total_pages": 4,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn merge_spec_validate_cover_variables() {
+        let spec: MergeSpec = serde_json::from_value(json!({
+            "assets_urls": ["https://example.com/a.pdf"],
+            "callback_url": "https://example.com/callback",
+            "cover": {
+                "template_url": "https://example.com/cover.tex.tera",
+                "variables": ["not", "an", "object"],
+            },
+        }))
+        .unwrap();
+
+        assert!(spec.validate(&Config::for_tests()).is_err());
+    }
+
+    #[test]
+    fn cover_page_map_is_escaped_without_escaping() {
+        let cover: CoverSpec = serde_json::from_value(json!({
+            "template_url": "https://example.com/cover.tex.tera",
+            "variables": { "title": "\\textbf{Q3}" },
+            "no_escape_tex": true,
+        }))
+        .unwrap();
+
+        let page_map = json!({
+            "documents": [{ "filename": "50%_a&b.pdf", "start_page": 2, "pages": 3 }],
+        });
+
+        assert_eq!(
+            cover.variables(page_map),
+            json!({
+                "title": "\\textbf{Q3}",
+                "papers": {
+                    "documents": [{ "filename": "50\\%\\_a\\&b.pdf", "start_page": 2, "pages": 3 }],
+                },
+            })
+        );
+    }
 }
@@ -1,15 +1,21 @@
 mod document_spec;
 mod merge;
 mod merge_spec;
+mod post_processing;
 mod renderer;
+mod split;
+mod split_spec;
 mod summary;
 mod uri;
 mod workspace;
 
 pub(crate) use self::document_spec::DocumentSpec;
 pub(crate) use self::merge::Merger;
-pub(crate) use self::merge_spec::MergeSpec;
+pub(crate) use self::merge_spec::{CoverSpec, MergeSpec};
+pub(crate) use self::post_processing::{post_process, PostProcessing};
 pub(crate) use self::renderer::Renderer;
+pub(crate) use self::split::Splitter;
+pub(crate) use self::split_spec::{SplitBy, SplitSpec};
 pub(crate) use self::summary::Summary;
 pub(crate) use self::uri::PapersUri;
 pub(crate) use self::workspace::Workspace;
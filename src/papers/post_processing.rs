@@ -0,0 +1,713 @@
+//! Transformations applied to the final PDF of a job, after rendering or merging and before it
+//! is uploaded to S3.
+
+mod encryption;
+mod ghostscript;
+mod metadata;
+mod signature;
+mod stamp;
+
+use crate::papers::{PapersUri, Workspace};
+use crate::prelude::*;
+use serde::{Deserialize, Serialize};
+use slog::{debug, info};
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+/// The post-processing options shared by [`DocumentSpec`](crate::papers::DocumentSpec) and
+/// [`MergeSpec`](crate::papers::MergeSpec). They are flattened into the specs, so the fields
+/// appear at the top level of the request body.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct PostProcessing {
+    /// A text or image watermark stamped on every page.
+    #[serde(default)]
+    pub watermark: Option<Watermark>,
+    /// A line of text at the top of every page. `{page}` and `{pages}` are replaced with the
+    /// page number and the page count.
+    #[serde(default)]
+    pub header: Option<String>,
+    /// Same as `header`, at the bottom of every page.
+    #[serde(default)]
+    pub footer: Option<String>,
+    /// Continuous Bates numbering in the bottom right corner of every page.
+    #[serde(default)]
+    pub bates: Option<Bates>,
+    /// The document information dictionary, XMP metadata and language of the document. The
+    /// metadata left behind by xelatex or pdfunite is stripped if this is missing.
+    #[serde(default)]
+    pub metadata: Metadata,
+    /// Convert the document to an archival PDF/A flavour.
+    #[serde(default)]
+    pub archival: Option<Archival>,
+    /// Password protection and permissions.
+    #[serde(default)]
+    pub encryption: Option<Encryption>,
+    /// Reduce the size of the document and linearize it for fast web view.
+    #[serde(default)]
+    pub optimize: Option<Optimize>,
+    /// Digitally sign the document with the keystore from the configuration.
+    #[serde(default)]
+    pub sign: Option<Sign>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Watermark {
+    /// The text of the watermark. Exactly one of `text` and `image_url` must be set.
+    #[serde(default)]
+    pub text: Option<String>,
+    /// An image to use as the watermark.
+    #[serde(default)]
+    pub image_url: Option<PapersUri>,
+    /// Between 0 (invisible) and 1 (opaque).
+    #[serde(default = "default_opacity")]
+    pub opacity: f64,
+    /// Counter-clockwise rotation in degrees.
+    #[serde(default = "default_angle")]
+    pub angle: f64,
+}
+
+/// Values for the document information dictionary and the XMP packet.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct Metadata {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub subject: Option<String>,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// A BCP 47 language tag, like `de-DE`. It is also written to the document catalog.
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Keep the metadata left behind by xelatex or pdfunite for the fields that are not set,
+    /// instead of stripping it.
+    #[serde(default)]
+    pub keep_unset: bool,
+}
+
+/// The supported PDF/A conformance levels.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Archival {
+    #[serde(rename = "pdfa-2b")]
+    PdfA2b,
+    #[serde(rename = "pdfa-3b")]
+    PdfA3b,
+}
+
+/// The size optimization profiles, from smallest to highest quality.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Optimize {
+    /// 72 dpi images.
+    Screen,
+    /// 150 dpi images.
+    Ebook,
+    /// 300 dpi images.
+    Print,
+}
+
+/// AES-256 encryption of the document.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Encryption {
+    /// The password needed to open the document.
+    pub user_password: Password,
+    /// The password needed to change the permissions. A random one is generated if it is
+    /// missing.
+    #[serde(default)]
+    pub owner_password: Option<Password>,
+    #[serde(default)]
+    pub permissions: Permissions,
+}
+
+/// What readers who opened the document with the user password may do with it.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Permissions {
+    #[serde(default = "return_true")]
+    pub print: bool,
+    #[serde(default = "return_true")]
+    pub copy: bool,
+    #[serde(default = "return_true")]
+    pub modify: bool,
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Permissions {
+            print: true,
+            copy: true,
+            modify: true,
+        }
+    }
+}
+
+/// A secret from a spec. It is redacted from the [`Debug`](std::fmt::Debug) output and when the
+/// spec is serialized, so it can't end up in the logs.
+#[derive(Deserialize)]
+#[serde(transparent)]
+pub struct Password(pub String);
+
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl Serialize for Password {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("[redacted]")
+    }
+}
+
+/// A PAdES signature.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Sign {
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub location: Option<String>,
+    /// Where to draw the signature. It is invisible if this is missing.
+    #[serde(default)]
+    pub visible: Option<SignatureBox>,
+}
+
+/// The placement of a visible signature, in points from the bottom left corner of the page.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SignatureBox {
+    /// 1-based. Negative numbers count from the end, `-1` is the last page.
+    #[serde(default = "default_signature_page")]
+    pub page: i32,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl SignatureBox {
+    /// Check that the page exists and that the corners of the box can be computed.
+    fn validate(&self) -> Result<(), EndpointError> {
+        if self.page == 0 {
+            return Err(EndpointError::UnprocessableEntity {
+                cause: format_err!("Signature pages start at 1."),
+            });
+        }
+
+        if self.x.checked_add(self.width).is_none() || self.y.checked_add(self.height).is_none() {
+            return Err(EndpointError::UnprocessableEntity {
+                cause: format_err!("The signature box is too large."),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Bates {
+    #[serde(default)]
+    pub prefix: String,
+    /// The number of the first page.
+    #[serde(default = "default_bates_start")]
+    pub start: u64,
+    /// The numbers are zero-padded to this many digits.
+    #[serde(default = "default_bates_digits")]
+    pub digits: usize,
+}
+
+impl Bates {
+    /// The Bates number of the page at `index` (0-based). `start` is validated to leave room for
+    /// any page count, see [`PostProcessing::validate`](PostProcessing::validate).
+    pub fn number(&self, index: usize) -> String {
+        format!(
+            "{}{:0width$}",
+            self.prefix,
+            self.start + index as u64,
+            width = self.digits
+        )
+    }
+}
+
+fn return_true() -> bool {
+    true
+}
+
+fn default_signature_page() -> i32 {
+    1
+}
+
+fn default_opacity() -> f64 {
+    0.3
+}
+
+fn default_angle() -> f64 {
+    45.0
+}
+
+fn default_bates_start() -> u64 {
+    1
+}
+
+fn default_bates_digits() -> usize {
+    6
+}
+
+impl PostProcessing {
+    /// Validate that the options are consistent.
+    ///
+    /// The error is intended for consumption by the client of the service.
+    pub fn validate(&self, config: &Config) -> Result<(), EndpointError> {
+        if let Some(watermark) = &self.watermark {
+            if watermark.text.is_some() == watermark.image_url.is_some() {
+                return Err(EndpointError::UnprocessableEntity {
+                    cause: format_err!("A watermark needs exactly one of `text` and `image_url`."),
+                });
+            }
+
+            if !(0.0..=1.0).contains(&watermark.opacity) {
+                return Err(EndpointError::UnprocessableEntity {
+                    cause: format_err!("The watermark opacity must be between 0 and 1."),
+                });
+            }
+        }
+
+        if let Some(bates) = &self.bates {
+            // Page counts are `u32`s, so this leaves room for the number of every page.
+            if bates.start.checked_add(u64::from(u32::MAX)).is_none() {
+                return Err(EndpointError::UnprocessableEntity {
+                    cause: format_err!("The Bates start number is too large."),
+                });
+            }
+        }
+
+        if let Some(language) = &self.metadata.language {
+            let valid = !language.is_empty()
+                && language
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-');
+
+            if !valid {
+                return Err(EndpointError::UnprocessableEntity {
+                    cause: format_err!("The language must be a language tag like `de-DE`."),
+                });
+            }
+        }
+
+        if let Some(encryption) = &self.encryption {
+            if self.archival.is_some() {
+                return Err(EndpointError::UnprocessableEntity {
+                    cause: format_err!("PDF/A documents cannot be encrypted."),
+                });
+            }
+
+            let passwords = std::iter::once(&encryption.user_password)
+                .chain(encryption.owner_password.as_ref());
+
+            for password in passwords {
+                // qpdf reads its arguments file line by line.
+                if password.0.contains(|c| c == '\n' || c == '\r') {
+                    return Err(EndpointError::UnprocessableEntity {
+                        cause: format_err!("Passwords cannot contain line breaks."),
+                    });
+                }
+            }
+        }
+
+        if let Some(sign) = &self.sign {
+            if config.signing.is_none() {
+                return Err(EndpointError::UnprocessableEntity {
+                    cause: format_err!("Signing is not enabled on this server."),
+                });
+            }
+
+            if self.encryption.is_some() {
+                return Err(EndpointError::UnprocessableEntity {
+                    cause: format_err!("Encrypted documents cannot be signed."),
+                });
+            }
+
+            if let Some(signature_box) = &sign.visible {
+                signature_box.validate()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn has_stamps(&self) -> bool {
+        self.watermark.is_some()
+            || self.header.is_some()
+            || self.footer.is_some()
+            || self.bates.is_some()
+    }
+}
+
+/// Apply the requested post-processing to the PDF at `pdf_path`, in place.
+pub(crate) async fn post_process<'a>(
+    workspace: &'a Workspace,
+    options: &'a PostProcessing,
+    pdf_path: &'a Path,
+) -> Result<(), failure::Error> {
+    let timeout = workspace.config().step_timeout;
+
+    if options.has_stamps() {
+        debug!(workspace.logger(), "Stamping {:?}.", pdf_path);
+        stamp::stamp(workspace, options, pdf_path)
+            .await
+            .context("Error stamping the document.")?;
+    }
+
+    let size_before_optimization = if options.optimize.is_some() {
+        Some(std::fs::metadata(pdf_path)?.len())
+    } else {
+        None
+    };
+
+    // Ghostscript rewrites the whole document, so this has to come after the steps that modify
+    // its content.
+    if options.archival.is_some() || options.optimize.is_some() {
+        debug!(
+            workspace.logger(),
+            "Rewriting {:?} with Ghostscript (archival: {:?}, optimize: {:?}).",
+            pdf_path,
+            options.archival,
+            options.optimize
+        );
+        ghostscript::rewrite(workspace, options.archival, options.optimize, pdf_path)
+            .await
+            .context("Error rewriting the document with Ghostscript.")?;
+        workspace.check_disk_usage()?;
+    }
+
+    // Ghostscript writes its own producer and rebuilds the XMP packet, so the metadata is
+    // written after it.
+    debug!(workspace.logger(), "Writing metadata to {:?}.", pdf_path);
+    metadata::write_metadata(workspace, &options.metadata, options.archival, pdf_path)
+        .await
+        .context("Error writing the document metadata.")?;
+
+    // Linearization has to be done by the last step that writes the document.
+    let linearize = options.optimize.is_some();
+
+    if let Some(encryption) = &options.encryption {
+        debug!(workspace.logger(), "Encrypting {:?}.", pdf_path);
+        encryption::encrypt(encryption, linearize, pdf_path, timeout)
+            .await
+            .context("Error encrypting the document.")?;
+    } else if linearize {
+        let qpdf = workspace.command("qpdf");
+        qpdf_in_place(qpdf, pdf_path, vec!["--linearize".into()], timeout)
+            .await
+            .context("Error linearizing the document.")?;
+    }
+
+    if let Some(size_before_optimization) = size_before_optimization {
+        info!(
+            workspace.logger(),
+            "Optimized {:?} from {} to {} bytes.",
+            pdf_path,
+            size_before_optimization,
+            std::fs::metadata(pdf_path)?.len()
+        );
+    }
+
+    // Signing must stay last: any rewrite of the document after it invalidates the signature.
+    if let Some(sign) = &options.sign {
+        let signing = workspace
+            .config()
+            .signing
+            .as_ref()
+            .ok_or_else(|| format_err!("Signing is not enabled on this server."))?;
+
+        debug!(workspace.logger(), "Signing {:?}.", pdf_path);
+        signature::sign(signing, sign, pdf_path, timeout)
+            .await
+            .context("Error signing the document.")?;
+    }
+
+    Ok(())
+}
+
+/// Run `qpdf <args> <pdf_path> <output>` with the qpdf `command`, and replace the PDF at
+/// `pdf_path` with the output.
+async fn qpdf_in_place(
+    mut command: Command,
+    pdf_path: &Path,
+    args: Vec<OsString>,
+    timeout: Duration,
+) -> Result<(), failure::Error> {
+    let output_path = pdf_path.with_extension("qpdf.pdf");
+
+    command.args(args).arg(pdf_path).arg(&output_path);
+
+    crate::utils::process::run(command, "qpdf", timeout).await?;
+
+    std::fs::rename(&output_path, pdf_path).context("Error replacing the processed document")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Identity;
+    use crate::request_context::{RequestContext, RequestId};
+    use futures::{FutureExt, TryFutureExt};
+    use serde_json::{json, Value};
+
+    /// Apply `options` to a copy of the test document, and read its metadata back with exiftool,
+    /// with the tags named after their group like `XMP-dc:Title`.
+    fn post_processed_metadata(options: PostProcessing) -> Value {
+        let config = Arc::new(Config::for_tests());
+        let context = RequestContext {
+            request_id: RequestId::generate(),
+            trace_parent: None,
+        };
+        let workspace =
+            Workspace::new(config.logger.clone(), config, &Identity::default(), context).unwrap();
+        let pdf_path = workspace.temp_dir_path().join("document.pdf");
+        std::fs::copy("tests/assets/doc.pdf", &pdf_path).unwrap();
+
+        // The future borrows the workspace, which rules out the multi-threaded runtime.
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        runtime
+            .block_on(
+                post_process(&workspace, &options, &pdf_path)
+                    .boxed()
+                    .compat(),
+            )
+            .unwrap();
+
+        let output = Command::new("exiftool")
+            .arg("-json")
+            .arg("-G1")
+            .arg(&pdf_path)
+            .output()
+            .unwrap();
+        let mut files: Vec<Value> = serde_json::from_slice(&output.stdout).unwrap();
+        files.remove(0)
+    }
+
+    fn invoice_metadata() -> Value {
+        json!({
+            "title": "Invoice 2019-10",
+            "keywords": ["invoice", "october"],
+            "language": "de-DE",
+        })
+    }
+
+    #[test]
+    fn bates_numbers_are_padded_and_continuous() {
+        let bates = Bates {
+            prefix: "ACME".to_owned(),
+            start: 998,
+            digits: 6,
+        };
+
+        assert_eq!(bates.number(0), "ACME000998");
+        assert_eq!(bates.number(3), "ACME001001");
+    }
+
+    #[test]
+    fn bates_numbers_cannot_overflow() {
+        let options: PostProcessing = serde_json::from_value(json!({
+            "bates": { "start": u64::max_value() - 10 },
+        }))
+        .unwrap();
+        assert!(options.validate(&Config::for_tests()).is_err());
+
+        let options: PostProcessing = serde_json::from_value(json!({
+            "bates": { "start": 1_000_000 },
+        }))
+        .unwrap();
+        assert!(options.validate(&Config::for_tests()).is_ok());
+    }
+
+    #[test]
+    fn watermark_opacity_must_be_between_0_and_1() {
+        for opacity in &[-0.1, 1.5, std::f64::NAN] {
+            let options = PostProcessing {
+                watermark: Some(Watermark {
+                    text: Some("DRAFT".to_owned()),
+                    image_url: None,
+                    opacity: *opacity,
+                    angle: default_angle(),
+                }),
+                ..Default::default()
+            };
+
+            assert!(options.validate(&Config::for_tests()).is_err());
+        }
+    }
+
+    #[test]
+    fn watermark_needs_exactly_one_source() {
+        let both: PostProcessing = serde_json::from_value(json!({
+            "watermark": { "text": "DRAFT", "image_url": "https://example.com/logo.png" },
+        }))
+        .unwrap();
+        assert!(both.validate(&Config::for_tests()).is_err());
+
+        let neither: PostProcessing = serde_json::from_value(json!({
+            "watermark": { "opacity": 0.5 },
+        }))
+        .unwrap();
+        assert!(neither.validate(&Config::for_tests()).is_err());
+
+        let text: PostProcessing = serde_json::from_value(json!({
+            "watermark": { "text": "DRAFT" },
+        }))
+        .unwrap();
+        assert!(text.validate(&Config::for_tests()).is_ok());
+    }
+
+    #[test]
+    fn archival_levels_deserialize() {
+        let options: PostProcessing = serde_json::from_value(json!({
+            "archival": "pdfa-3b",
+        }))
+        .unwrap();
+        assert_eq!(options.archival, Some(Archival::PdfA3b));
+
+        let invalid = serde_json::from_value::<PostProcessing>(json!({
+            "archival": "pdfa-1a",
+        }));
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn passwords_are_redacted() {
+        let options: PostProcessing = serde_json::from_value(json!({
+            "encryption": { "user_password": "hunter2", "owner_password": "hunter3" },
+        }))
+        .unwrap();
+
+        assert!(!format!("{:?}", options).contains("hunter"));
+        assert!(!serde_json::to_string(&options).unwrap().contains("hunter"));
+    }
+
+    #[test]
+    fn encryption_is_incompatible_with_archival() {
+        let options: PostProcessing = serde_json::from_value(json!({
+            "archival": "pdfa-2b",
+            "encryption": { "user_password": "hunter2" },
+        }))
+        .unwrap();
+
+        assert!(options.validate(&Config::for_tests()).is_err());
+    }
+
+    #[test]
+    fn passwords_cannot_contain_line_breaks() {
+        for password in &["hunter\n2", "hunter\r2"] {
+            let options: PostProcessing = serde_json::from_value(json!({
+                "encryption": { "user_password": "hunter2", "owner_password": password },
+            }))
+            .unwrap();
+
+            assert!(options.validate(&Config::for_tests()).is_err());
+        }
+    }
+
+    #[test]
+    fn optimize_profiles_deserialize() {
+        let options: PostProcessing = serde_json::from_value(json!({
+            "optimize": "ebook",
+        }))
+        .unwrap();
+        assert_eq!(options.optimize, Some(Optimize::Ebook));
+    }
+
+    #[test]
+    fn signing_needs_a_keystore() {
+        let options: PostProcessing = serde_json::from_value(json!({
+            "sign": { "reason": "Contract approval" },
+        }))
+        .unwrap();
+
+        assert!(options.validate(&Config::for_tests()).is_err());
+    }
+
+    #[test]
+    fn watermark_opacity_is_validated() {
+        let options: PostProcessing = serde_json::from_value(json!({
+            "watermark": { "text": "DRAFT", "opacity": 1.5 },
+        }))
+        .unwrap();
+        assert!(options.validate(&Config::for_tests()).is_err());
+    }
+
+    #[test]
+    fn signature_boxes_cannot_overflow() {
+        let signature_box: SignatureBox = serde_json::from_value(json!({
+            "x": 4_294_967_000u32, "y": 0, "width": 500, "height": 60,
+        }))
+        .unwrap();
+        assert!(signature_box.validate().is_err());
+
+        let signature_box: SignatureBox = serde_json::from_value(json!({
+            "x": 50, "y": 40, "width": 200, "height": 60,
+        }))
+        .unwrap();
+        assert!(signature_box.validate().is_ok());
+    }
+
+    #[test]
+    fn archival_documents_keep_their_metadata() {
+        let options: PostProcessing = serde_json::from_value(json!({
+            "metadata": invoice_metadata(),
+            "archival": "pdfa-2b",
+        }))
+        .unwrap();
+
+        let tags = post_processed_metadata(options);
+
+        assert_eq!(tags["PDF:Title"], "Invoice 2019-10");
+        assert_eq!(tags["XMP-dc:Title"], "Invoice 2019-10");
+        assert_eq!(tags["XMP-pdf:Keywords"], "invoice, october");
+        assert_eq!(tags["XMP-dc:Language"], "de-DE");
+        assert_eq!(tags["PDF:Language"], "de-DE");
+        assert_eq!(tags["XMP-pdfaid:Part"], 2);
+        assert_eq!(tags["XMP-pdfaid:Conformance"], "B");
+        // Stripped, although Ghostscript writes one.
+        assert_eq!(tags.get("PDF:Producer"), None);
+    }
+
+    #[test]
+    fn optimized_documents_keep_their_metadata() {
+        let options: PostProcessing = serde_json::from_value(json!({
+            "metadata": invoice_metadata(),
+            "optimize": "screen",
+        }))
+        .unwrap();
+
+        let tags = post_processed_metadata(options);
+
+        assert_eq!(tags["PDF:Title"], "Invoice 2019-10");
+        assert_eq!(tags["XMP-pdf:Keywords"], "invoice, october");
+        assert_eq!(tags["XMP-dc:Language"], "de-DE");
+        assert_eq!(tags["PDF:Language"], "de-DE");
+        assert_eq!(tags.get("PDF:Producer"), None);
+    }
+
+    #[test]
+    fn metadata_is_stripped_by_default() {
+        let tags = post_processed_metadata(PostProcessing::default());
+
+        assert_eq!(tags.get("PDF:Creator"), None);
+        assert_eq!(tags.get("PDF:Producer"), None);
+    }
+
+    #[test]
+    fn languages_must_be_language_tags() {
+        let config = Config::for_tests();
+        let with_language = |language: &str| -> PostProcessing {
+            serde_json::from_value(json!({ "metadata": { "language": language } })).unwrap()
+        };
+
+        assert!(with_language("de-DE").validate(&config).is_ok());
+        assert!(with_language("").validate(&config).is_err());
+        assert!(with_language("de) /A (x").validate(&config).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! Password protection with qpdf.
+//!
+//! The passwords are passed to qpdf through an arguments file outside of the workspace, so they
+//! neither show up in the process list nor in the workspace tar. The sandbox can't read that
+//! file, so this qpdf run is not sandboxed.
+
+use super::{qpdf_in_place, Encryption};
+use crate::prelude::*;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+/// Encrypt the PDF at `pdf_path` in place, with AES-256. The document is also linearized if
+/// `linearize` is true, since rewriting it afterwards would require the passwords.
+pub(super) async fn encrypt<'a>(
+    encryption: &'a Encryption,
+    linearize: bool,
+    pdf_path: &'a Path,
+    timeout: Duration,
+) -> Result<(), failure::Error> {
+    let args_file = mktemp::Temp::new_file().context("Could not create the qpdf arguments file")?;
+
+    std::fs::write(&args_file, qpdf_args(encryption).join("\n"))
+        .context("Error writing the qpdf arguments file")?;
+
+    let mut args = vec![format!("@{}", args_file.as_ref().display()).into()];
+
+    if linearize {
+        args.push("--linearize".into());
+    }
+
+    qpdf_in_place(Command::new("qpdf"), pdf_path, args, timeout).await
+}
+
+/// The arguments for `qpdf --encrypt`, one per line in the arguments file.
+fn qpdf_args(encryption: &Encryption) -> Vec<String> {
+    let owner_password = encryption
+        .owner_password
+        .as_ref()
+        .map(|password| password.0.clone())
+        // Nobody can lift the restrictions if there is no owner password.
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let permissions = &encryption.permissions;
+
+    vec![
+        "--encrypt".to_owned(),
+        encryption.user_password.0.clone(),
+        owner_password,
+        "256".to_owned(),
+        format!(
+            "--print={}",
+            if permissions.print { "full" } else { "none" }
+        ),
+        format!("--extract={}", if permissions.copy { "y" } else { "n" }),
+        format!(
+            "--modify={}",
+            if permissions.modify { "all" } else { "none" }
+        ),
+        "--".to_owned(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn qpdf_args_set_passwords_and_permissions() {
+        let encryption: Encryption = serde_json::from_value(json!({
+            "user_password": "employee",
+            "owner_password": "payroll",
+            "permissions": { "copy": false, "modify": false },
+        }))
+        .unwrap();
+
+        assert_eq!(
+            qpdf_args(&encryption),
+            vec![
+                "--encrypt",
+                "employee",
+                "payroll",
+                "256",
+                "--print=full",
+                "--extract=n",
+                "--modify=none",
+                "--",
+            ]
+        );
+    }
+
+    #[test]
+    fn qpdf_args_generate_an_owner_password() {
+        let encryption: Encryption = serde_json::from_value(json!({
+            "user_password": "employee",
+        }))
+        .unwrap();
+
+        let args = qpdf_args(&encryption);
+
+        assert_eq!(args[1], "employee");
+        assert!(!args[2].is_empty());
+        assert_ne!(args[2], "employee");
+    }
+}
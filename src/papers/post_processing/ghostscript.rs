@@ -0,0 +1,131 @@
+//! PDF/A conversion and size optimization with Ghostscript.
+//!
+//! Both are done in a single pass, since each Ghostscript run rewrites the whole document.
+//!
+//! For PDF/A, Ghostscript needs a PostScript prologue that declares the output intent with an
+//! embedded ICC profile. We write it to the workspace for every conversion, pointing to the
+//! profile from the configuration.
+
+use super::{Archival, Optimize};
+use crate::papers::Workspace;
+use crate::prelude::*;
+use std::path::Path;
+
+/// The file name of the PDF/A prologue in the workspace.
+const PDFA_DEF_FILENAME: &str = "papers-pdfa-def.ps";
+
+impl Archival {
+    /// The PDF/A part, as understood by Ghostscript's `-dPDFA`.
+    pub(super) fn part(self) -> u8 {
+        match self {
+            Archival::PdfA2b => 2,
+            Archival::PdfA3b => 3,
+        }
+    }
+}
+
+impl Optimize {
+    /// The Ghostscript distiller preset. Each of them downsamples images to a given resolution,
+    /// recompresses streams and subsets fonts.
+    fn pdf_settings(self) -> &'static str {
+        match self {
+            Optimize::Screen => "/screen",
+            Optimize::Ebook => "/ebook",
+            Optimize::Print => "/printer",
+        }
+    }
+}
+
+/// Rewrite the PDF at `pdf_path` in place, converting it to PDF/A and/or optimizing it.
+pub(super) async fn rewrite<'a>(
+    workspace: &'a Workspace,
+    archival: Option<Archival>,
+    optimize: Option<Optimize>,
+    pdf_path: &'a Path,
+) -> Result<(), failure::Error> {
+    let output_path = pdf_path.with_extension("gs.pdf");
+
+    let mut command = workspace.command("gs");
+    command
+        .arg("-dBATCH")
+        .arg("-dNOPAUSE")
+        .arg("-dNOOUTERSAVE")
+        .arg("-sDEVICE=pdfwrite");
+
+    if let Some(optimize) = optimize {
+        command
+            .arg(format!("-dPDFSETTINGS={}", optimize.pdf_settings()))
+            .arg("-dEmbedAllFonts=true")
+            .arg("-dSubsetFonts=true");
+    }
+
+    command.arg(format!("-sOutputFile={}", output_path.display()));
+
+    if let Some(archival) = archival {
+        let icc_profile = &workspace.config().icc_profile;
+        let def_path = workspace.temp_dir_path().join(PDFA_DEF_FILENAME);
+
+        std::fs::write(&def_path, pdfa_def(icc_profile))
+            .context("Error writing the PDF/A definition file")?;
+
+        command
+            .arg(format!("-dPDFA={}", archival.part()))
+            // Abort instead of silently producing a non-conforming file.
+            .arg("-dPDFACompatibilityPolicy=2")
+            .arg("-sColorConversionStrategy=RGB")
+            .arg(format!("--permit-file-read={}", icc_profile.display()))
+            .arg(&def_path);
+    }
+
+    command.arg(pdf_path);
+
+    crate::utils::process::run(command, "Ghostscript", workspace.config().step_timeout).await?;
+
+    std::fs::rename(&output_path, pdf_path).context("Error replacing the converted document")?;
+
+    Ok(())
+}
+
+/// The PostScript prologue declaring the sRGB output intent required by PDF/A.
+fn pdfa_def(icc_profile: &Path) -> String {
+    format!(
+        r"%!
+/ICCProfile ({}) def
+[/_objdef {{icc_PDFA}} /type /stream /OBJ pdfmark
+[{{icc_PDFA}} << /N 3 >> /PUT pdfmark
+[{{icc_PDFA}} ICCProfile (r) file /PUT pdfmark
+[/_objdef {{OutputIntent_PDFA}} /type /dict /OBJ pdfmark
+[{{OutputIntent_PDFA}} <<
+  /Type /OutputIntent
+  /S /GTS_PDFA1
+  /DestOutputProfile {{icc_PDFA}}
+  /OutputConditionIdentifier (sRGB)
+>> /PUT pdfmark
+[{{Catalog}} << /OutputIntents [ {{OutputIntent_PDFA}} ] >> /PUT pdfmark
+",
+        escape_ps_string(&icc_profile.to_string_lossy())
+    )
+}
+
+/// Escape the characters that are special inside a PostScript string literal.
+fn escape_ps_string(string: &str) -> String {
+    string
+        .replace('\\', r"\\")
+        .replace('(', r"\(")
+        .replace(')', r"\)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pdfa_def_references_the_icc_profile() {
+        let def = pdfa_def(Path::new("/usr/share/color/icc/sRGB (v2).icc"));
+
+        assert!(def.starts_with("%!\n/ICCProfile (/usr/share/color/icc/sRGB \\(v2\\).icc) def\n"));
+        assert!(
+            def.contains("[{Catalog} << /OutputIntents [ {OutputIntent_PDFA} ] >> /PUT pdfmark")
+        );
+    }
+}
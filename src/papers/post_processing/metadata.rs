@@ -0,0 +1,507 @@
+//! Document information dictionary, XMP metadata and document language.
+//!
+//! exiftool edits PDFs with incremental updates, so the previous values are still in the file
+//! after it runs. We rewrite the document with qpdf afterwards, which only keeps the objects that
+//! are still referenced.
+//!
+//! exiftool can't write the `/Lang` entry of the document catalog, and the qpdf of our base image
+//! can't edit objects. We append an incremental update with the new catalog ourselves, and let
+//! qpdf fold it into the document. qpdf prints the objects we need to copy, in a form we only
+//! have to split into dictionary entries.
+
+use super::{qpdf_in_place, Archival, Metadata};
+use crate::papers::Workspace;
+use crate::prelude::*;
+use std::path::Path;
+
+/// Set the metadata of the PDF at `pdf_path` in place. The PDF/A identification of `archival`
+/// documents is written again, since it lives in the XMP packet.
+pub(super) async fn write_metadata<'a>(
+    workspace: &'a Workspace,
+    metadata: &'a Metadata,
+    archival: Option<Archival>,
+    pdf_path: &'a Path,
+) -> Result<(), failure::Error> {
+    let timeout = workspace.config().step_timeout;
+
+    let mut command = workspace.command("exiftool");
+    command
+        .arg("-overwrite_original")
+        .args(exiftool_args(metadata, archival))
+        .arg(pdf_path);
+
+    crate::utils::process::run(command, "exiftool", timeout).await?;
+
+    // Our update needs a cross-reference table, which qpdf only writes without object streams.
+    let qpdf = workspace.command("qpdf");
+    qpdf_in_place(
+        qpdf,
+        pdf_path,
+        vec!["--object-streams=disable".into()],
+        timeout,
+    )
+    .await?;
+
+    if metadata.language.is_none() && metadata.keep_unset {
+        return Ok(());
+    }
+
+    let language = metadata.language.as_ref().map(String::as_str);
+    write_catalog_language(workspace, language, pdf_path).await
+}
+
+/// Set the `/Lang` entry of the document catalog of the PDF at `pdf_path` in place, or remove it
+/// if `language` is `None`. The document must have a cross-reference table.
+async fn write_catalog_language<'a>(
+    workspace: &'a Workspace,
+    language: Option<&'a str>,
+    pdf_path: &'a Path,
+) -> Result<(), failure::Error> {
+    let trailer = show_object(workspace, pdf_path, "trailer").await?;
+    let trailer = dict_entries(&trailer)?;
+
+    let root = trailer
+        .iter()
+        .find(|(key, _)| *key == "/Root")
+        .map(|(_, value)| parse_reference(value))
+        .ok_or_else(|| format_err!("The trailer has no /Root"))??;
+
+    let catalog = show_object(workspace, pdf_path, &format!("{},{}", root.0, root.1)).await?;
+    let catalog = dict_entries(&catalog)?;
+
+    if language.is_none() && catalog.iter().all(|(key, _)| *key != "/Lang") {
+        return Ok(());
+    }
+
+    let mut pdf = std::fs::read(pdf_path).context("Error reading the document")?;
+    let update = language_update(&pdf, &trailer, root, &catalog, language)?;
+    pdf.extend_from_slice(update.as_bytes());
+    std::fs::write(pdf_path, pdf).context("Error writing the document")?;
+
+    let timeout = workspace.config().step_timeout;
+    qpdf_in_place(workspace.command("qpdf"), pdf_path, Vec::new(), timeout).await
+}
+
+/// The output of `qpdf --show-object=<object>` for the PDF at `pdf_path`.
+async fn show_object<'a>(
+    workspace: &'a Workspace,
+    pdf_path: &'a Path,
+    object: &'a str,
+) -> Result<String, failure::Error> {
+    let mut command = workspace.command("qpdf");
+    command
+        .arg(format!("--show-object={}", object))
+        .arg(pdf_path);
+
+    let output =
+        crate::utils::process::output(command, "qpdf", workspace.config().step_timeout).await?;
+
+    // qpdf exits with 3 when it only has warnings.
+    if !output.status.success() && output.status.code() != Some(3) {
+        return Err(format_err!(
+            "qpdf could not show {}: {}",
+            object,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    String::from_utf8(output.stdout)
+        .context("qpdf output was not utf8")
+        .map_err(Into::into)
+}
+
+/// The incremental update that replaces the catalog, the object `root` of the document `pdf`,
+/// with one whose `/Lang` is `language`. `trailer` and `catalog` are the entries of the current
+/// trailer and catalog.
+fn language_update(
+    pdf: &[u8],
+    trailer: &[(&str, &str)],
+    root: (u32, u16),
+    catalog: &[(&str, &str)],
+    language: Option<&str>,
+) -> Result<String, failure::Error> {
+    let previous_xref = last_startxref(pdf)?;
+
+    let mut catalog: Vec<String> = catalog
+        .iter()
+        .filter(|(key, _)| *key != "/Lang")
+        .map(|(key, value)| format!("{} {}", key, value))
+        .collect();
+
+    if let Some(language) = language {
+        // The language is validated, so it needs no escaping.
+        catalog.push(format!("/Lang ({})", language));
+    }
+
+    let mut trailer: Vec<String> = trailer
+        .iter()
+        .filter(|(key, _)| *key != "/Prev")
+        .map(|(key, value)| format!("{} {}", key, value))
+        .collect();
+    trailer.push(format!("/Prev {}", previous_xref));
+
+    let object_offset = pdf.len() + 1;
+    let mut update = format!(
+        "\n{} {} obj\n<< {} >>\nendobj\n",
+        root.0,
+        root.1,
+        catalog.join(" ")
+    );
+    let xref_offset = pdf.len() + update.len();
+
+    update.push_str(&format!(
+        "xref\n{} 1\n{:010} {:05} n \ntrailer\n<< {} >>\nstartxref\n{}\n%%EOF\n",
+        root.0,
+        object_offset,
+        root.1,
+        trailer.join(" "),
+        xref_offset
+    ));
+
+    Ok(update)
+}
+
+/// The offset of the last cross-reference section of the document `pdf`.
+fn last_startxref(pdf: &[u8]) -> Result<u64, failure::Error> {
+    const KEYWORD: &[u8] = b"startxref";
+
+    let position = pdf
+        .windows(KEYWORD.len())
+        .rposition(|window| window == KEYWORD)
+        .ok_or_else(|| format_err!("The document has no startxref"))?;
+
+    let offset: String = pdf[position + KEYWORD.len()..]
+        .iter()
+        .map(|&byte| byte as char)
+        .skip_while(char::is_ascii_whitespace)
+        .take_while(char::is_ascii_digit)
+        .collect();
+
+    offset
+        .parse()
+        .map_err(|_| format_err!("The document has an invalid startxref"))
+}
+
+/// The object number and generation of an indirect reference like `12 0 R`.
+fn parse_reference(reference: &str) -> Result<(u32, u16), failure::Error> {
+    let mut parts = reference.split_whitespace();
+
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(number), Some(generation), Some("R"), None) => {
+            Ok((number.parse()?, generation.parse()?))
+        }
+        _ => Err(format_err!("Not a reference: {}", reference)),
+    }
+}
+
+/// Split a dictionary like `<< /Type /Catalog /Pages 3 0 R >>` into its keys and the source of
+/// their values.
+fn dict_entries(dict: &str) -> Result<Vec<(&str, &str)>, failure::Error> {
+    let dict = dict.trim();
+
+    if !dict.starts_with("<<") || !dict.ends_with(">>") || dict.len() < 4 {
+        return Err(format_err!("Not a dictionary: {}", dict));
+    }
+
+    let inner = &dict[2..dict.len() - 2];
+    let bytes = inner.as_bytes();
+    let mut entries = Vec::new();
+    let mut position = skip_whitespace(bytes, 0);
+
+    while position < bytes.len() {
+        if bytes[position] != b'/' {
+            return Err(format_err!("Expected a dictionary key in {}", dict));
+        }
+
+        let key_end = object_end(bytes, position)?;
+        let value_start = skip_whitespace(bytes, key_end);
+        let value_end = value_end(bytes, value_start)?;
+
+        entries.push((&inner[position..key_end], &inner[value_start..value_end]));
+        position = skip_whitespace(bytes, value_end);
+    }
+
+    Ok(entries)
+}
+
+fn skip_whitespace(bytes: &[u8], mut position: usize) -> usize {
+    while position < bytes.len() && is_whitespace(bytes[position]) {
+        position += 1;
+    }
+
+    position
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    b"\0\t\n\x0c\r ".contains(&byte)
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    is_whitespace(byte) || b"()<>[]{}/%".contains(&byte)
+}
+
+/// The end of the dictionary value starting at `start`. Unlike other objects, a reference is made
+/// of three tokens.
+fn value_end(bytes: &[u8], start: usize) -> Result<usize, failure::Error> {
+    let end = object_end(bytes, start)?;
+
+    if end == start || !bytes[start..end].iter().all(u8::is_ascii_digit) {
+        return Ok(end);
+    }
+
+    let generation_start = skip_whitespace(bytes, end);
+    let generation_end = match object_end(bytes, generation_start) {
+        Ok(generation_end) if generation_end > generation_start => generation_end,
+        _ => return Ok(end),
+    };
+    let r_start = skip_whitespace(bytes, generation_end);
+
+    if bytes[generation_start..generation_end]
+        .iter()
+        .all(u8::is_ascii_digit)
+        && bytes.get(r_start) == Some(&b'R')
+        && bytes
+            .get(r_start + 1)
+            .map_or(true, |&byte| is_delimiter(byte))
+    {
+        Ok(r_start + 1)
+    } else {
+        Ok(end)
+    }
+}
+
+/// The end of the object or token starting at `start`.
+fn object_end(bytes: &[u8], start: usize) -> Result<usize, failure::Error> {
+    let unterminated = || format_err!("Unterminated object in a dictionary");
+
+    match bytes.get(start) {
+        None => Err(unterminated()),
+        Some(b'(') => {
+            let mut depth = 0;
+            let mut position = start;
+
+            while position < bytes.len() {
+                match bytes[position] {
+                    b'\\' => position += 1,
+                    b'(' => depth += 1,
+                    b')' => {
+                        depth -= 1;
+
+                        if depth == 0 {
+                            return Ok(position + 1);
+                        }
+                    }
+                    _ => (),
+                }
+
+                position += 1;
+            }
+
+            Err(unterminated())
+        }
+        Some(b'<') if bytes.get(start + 1) == Some(&b'<') => {
+            container_end(bytes, start + 2, b">>", unterminated)
+        }
+        Some(b'<') => bytes[start..]
+            .iter()
+            .position(|&byte| byte == b'>')
+            .map(|end| start + end + 1)
+            .ok_or_else(unterminated),
+        Some(b'[') => container_end(bytes, start + 1, b"]", unterminated),
+        Some(b'/') => Ok(token_end(bytes, start + 1)),
+        Some(_) => Ok(token_end(bytes, start)),
+    }
+}
+
+/// The end of the array or dictionary whose entries start at `position`, and which is closed by
+/// `close`.
+fn container_end(
+    bytes: &[u8],
+    mut position: usize,
+    close: &[u8],
+    unterminated: impl Fn() -> failure::Error,
+) -> Result<usize, failure::Error> {
+    loop {
+        position = skip_whitespace(bytes, position);
+
+        if position >= bytes.len() {
+            return Err(unterminated());
+        }
+
+        if bytes[position..].starts_with(close) {
+            return Ok(position + close.len());
+        }
+
+        let end = object_end(bytes, position)?;
+
+        if end == position {
+            return Err(format_err!(
+                "Unexpected {:?} in a dictionary",
+                bytes[position] as char
+            ));
+        }
+
+        position = end;
+    }
+}
+
+/// The end of the regular characters starting at `start`.
+fn token_end(bytes: &[u8], start: usize) -> usize {
+    bytes[start..]
+        .iter()
+        .position(|&byte| is_delimiter(byte))
+        .map_or(bytes.len(), |end| start + end)
+}
+
+/// The tag assignments for exiftool. Every field is written to both the information dictionary
+/// and the XMP packet.
+fn exiftool_args(metadata: &Metadata, archival: Option<Archival>) -> Vec<String> {
+    let mut args = Vec::new();
+
+    if !metadata.keep_unset {
+        args.push("-all:all=".to_owned());
+    }
+
+    let mut set = |tags: &[&str], value: &str| {
+        for tag in tags {
+            args.push(format!("-{}={}", tag, value));
+        }
+    };
+
+    if let Some(title) = &metadata.title {
+        set(&["PDF:Title", "XMP-dc:Title"], title);
+    }
+
+    if let Some(author) = &metadata.author {
+        set(&["PDF:Author", "XMP-dc:Creator"], author);
+    }
+
+    if let Some(subject) = &metadata.subject {
+        set(&["PDF:Subject", "XMP-dc:Description"], subject);
+    }
+
+    if !metadata.keywords.is_empty() {
+        set(
+            &["PDF:Keywords", "XMP-pdf:Keywords"],
+            &metadata.keywords.join(", "),
+        );
+    }
+
+    if let Some(language) = &metadata.language {
+        set(&["XMP-dc:Language"], language);
+    }
+
+    if let Some(archival) = archival {
+        set(&["XMP-pdfaid:Part"], &archival.part().to_string());
+        set(&["XMP-pdfaid:Conformance"], "B");
+    }
+
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dict_entries_split_nested_values() {
+        let dict = "<< /Type /Catalog /Pages 3 0 R /Names << /Dests 7 0 R >> \
+                    /Kids [ 4 0 R 5 0 R ] /Lang (en \\) \\(US\\)) /ID <0a1B> /Count 12 >>\n";
+
+        assert_eq!(
+            dict_entries(dict).unwrap(),
+            vec![
+                ("/Type", "/Catalog"),
+                ("/Pages", "3 0 R"),
+                ("/Names", "<< /Dests 7 0 R >>"),
+                ("/Kids", "[ 4 0 R 5 0 R ]"),
+                ("/Lang", "(en \\) \\(US\\))"),
+                ("/ID", "<0a1B>"),
+                ("/Count", "12"),
+            ]
+        );
+
+        assert!(dict_entries("<< /Lang (en >>").is_err());
+        assert!(dict_entries("[ 1 2 ]").is_err());
+    }
+
+    #[test]
+    fn language_update_replaces_the_catalog() {
+        let pdf = b"%PDF-1.4\nxref\n0 1\nstartxref\n9\n%%EOF\n";
+        let trailer = dict_entries("<< /Root 1 0 R /Size 4 /Prev 3 >>").unwrap();
+        let catalog = dict_entries("<< /Lang (en-US) /Pages 2 0 R /Type /Catalog >>").unwrap();
+
+        let update = language_update(pdf, &trailer, (1, 0), &catalog, Some("de-DE")).unwrap();
+
+        assert_eq!(
+            update,
+            "\n1 0 obj\n<< /Pages 2 0 R /Type /Catalog /Lang (de-DE) >>\nendobj\n\
+             xref\n1 1\n0000000037 00000 n \n\
+             trailer\n<< /Root 1 0 R /Size 4 /Prev 9 >>\nstartxref\n100\n%%EOF\n"
+        );
+        assert_eq!(&update[37 - pdf.len()..][..7], "1 0 obj");
+        assert!(update[100 - pdf.len()..].starts_with("xref"));
+    }
+
+    #[test]
+    fn parse_reference_needs_an_indirect_reference() {
+        assert_eq!(parse_reference("12 0 R").unwrap(), (12, 0));
+        assert!(parse_reference("12 0").is_err());
+        assert!(parse_reference("/Catalog").is_err());
+    }
+
+    #[test]
+    fn exiftool_args_strip_unset_fields_by_default() {
+        let metadata = Metadata {
+            title: Some("Invoice 2019-10".to_owned()),
+            keywords: vec!["invoice".to_owned(), "october".to_owned()],
+            language: Some("de-DE".to_owned()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            exiftool_args(&metadata, None),
+            vec![
+                "-all:all=",
+                "-PDF:Title=Invoice 2019-10",
+                "-XMP-dc:Title=Invoice 2019-10",
+                "-PDF:Keywords=invoice, october",
+                "-XMP-pdf:Keywords=invoice, october",
+                "-XMP-dc:Language=de-DE",
+            ]
+        );
+    }
+
+    #[test]
+    fn exiftool_args_keep_the_pdfa_identification() {
+        let metadata = Metadata {
+            title: Some("Invoice 2019-10".to_owned()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            exiftool_args(&metadata, Some(Archival::PdfA3b)),
+            vec![
+                "-all:all=",
+                "-PDF:Title=Invoice 2019-10",
+                "-XMP-dc:Title=Invoice 2019-10",
+                "-XMP-pdfaid:Part=3",
+                "-XMP-pdfaid:Conformance=B",
+            ]
+        );
+    }
+
+    #[test]
+    fn exiftool_args_can_keep_unset_fields() {
+        let metadata = Metadata {
+            author: Some("store2be".to_owned()),
+            keep_unset: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            exiftool_args(&metadata, None),
+            vec!["-PDF:Author=store2be", "-XMP-dc:Creator=store2be"]
+        );
+    }
+}
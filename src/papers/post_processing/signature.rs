@@ -0,0 +1,131 @@
+//! PAdES signatures with pyHanko.
+//!
+//! The signature is added as an incremental update, so it has to be the very last step: any
+//! later rewrite of the document would invalidate it. The keystore password is passed to pyHanko
+//! through a file outside of the workspace, which the sandbox can't read, so pyHanko is not
+//! sandboxed.
+
+use super::{Sign, SignatureBox};
+use crate::config::SigningConfig;
+use crate::prelude::*;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+/// The name of the signature field we create.
+const SIGNATURE_FIELD_NAME: &str = "PapersSignature";
+
+/// Sign the PDF at `pdf_path` in place.
+pub(super) async fn sign<'a>(
+    signing: &'a SigningConfig,
+    sign: &'a Sign,
+    pdf_path: &'a Path,
+    timeout: Duration,
+) -> Result<(), failure::Error> {
+    let passfile = mktemp::Temp::new_file().context("Could not create the keystore passfile")?;
+
+    std::fs::write(&passfile, signing.password()).context("Error writing the keystore passfile")?;
+
+    let output_path = pdf_path.with_extension("signed.pdf");
+
+    let mut command = Command::new("pyhanko");
+    command
+        .arg("sign")
+        .arg("addsig")
+        .args(addsig_args(sign))
+        .arg("pkcs12")
+        .arg("--passfile")
+        .arg(passfile.as_ref())
+        .arg(pdf_path)
+        .arg(&output_path)
+        .arg(&signing.keystore);
+
+    crate::utils::process::run(command, "pyHanko", timeout).await?;
+
+    std::fs::rename(&output_path, pdf_path).context("Error replacing the signed document")?;
+
+    Ok(())
+}
+
+/// The options of `pyhanko sign addsig`, before the `pkcs12` subcommand.
+fn addsig_args(sign: &Sign) -> Vec<String> {
+    let mut args = vec![
+        "--use-pades".to_owned(),
+        "--field".to_owned(),
+        field_spec(sign.visible.as_ref()),
+    ];
+
+    if let Some(reason) = &sign.reason {
+        args.push("--reason".to_owned());
+        args.push(reason.clone());
+    }
+
+    if let Some(location) = &sign.location {
+        args.push("--location".to_owned());
+        args.push(location.clone());
+    }
+
+    args
+}
+
+/// pyHanko's field specification: `PAGE/X1,Y1,X2,Y2/NAME` for a visible signature, or just the
+/// field name for an invisible one.
+fn field_spec(visible: Option<&SignatureBox>) -> String {
+    match visible {
+        Some(signature_box) => format!(
+            "{}/{},{},{},{}/{}",
+            signature_box.page,
+            signature_box.x,
+            signature_box.y,
+            signature_box.x + signature_box.width,
+            signature_box.y + signature_box.height,
+            SIGNATURE_FIELD_NAME
+        ),
+        None => SIGNATURE_FIELD_NAME.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn addsig_args_for_an_invisible_signature() {
+        let sign: Sign = serde_json::from_value(json!({
+            "reason": "Contract approval",
+        }))
+        .unwrap();
+
+        assert_eq!(
+            addsig_args(&sign),
+            vec![
+                "--use-pades",
+                "--field",
+                "PapersSignature",
+                "--reason",
+                "Contract approval",
+            ]
+        );
+    }
+
+    #[test]
+    fn addsig_args_for_a_visible_signature() {
+        let sign: Sign = serde_json::from_value(json!({
+            "location": "Berlin",
+            "visible": { "page": -1, "x": 50, "y": 40, "width": 200, "height": 60 },
+        }))
+        .unwrap();
+
+        assert_eq!(
+            addsig_args(&sign),
+            vec![
+                "--use-pades",
+                "--field",
+                "-1/50,40,250,100/PapersSignature",
+                "--location",
+                "Berlin",
+            ]
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! Watermarks, headers, footers and Bates numbers.
+//!
+//! We render a transparent overlay with one page per page of the document through xelatex and
+//! TikZ, then stamp it on the document with `qpdf --overlay`. Going through qpdf instead of
+//! `pdfpages` preserves forms and links in the original document.
+
+use super::{qpdf_in_place, PostProcessing};
+use crate::latex::escape_tex_string;
+use crate::papers::renderer::{run_latex, write_rendered_template};
+use crate::papers::Workspace;
+use crate::prelude::*;
+use crate::utils::pdf::page_sizes;
+use std::fmt::Write;
+use std::path::Path;
+
+/// The file name of the rendered overlay template. The overlay PDF is produced next to it.
+const OVERLAY_TEX_FILENAME: &str = "papers-overlay.tex";
+
+/// The file name the watermark image is saved as, so it can be safely included from LaTeX.
+const WATERMARK_IMAGE_STEM: &str = "papers-watermark";
+
+/// Distance between the header/footer baselines and the edges of the page, in points.
+const MARGIN: f64 = 20.0;
+
+const OVERLAY_PREAMBLE: &str = r"\documentclass{article}
+\usepackage{tikz}
+\hoffset=-1in
+\voffset=-1in
+\begin{document}
+";
+
+const OVERLAY_POSTAMBLE: &str = r"\end{document}
+";
+
+/// Stamp the PDF at `pdf_path` in place.
+pub(super) async fn stamp<'a>(
+    workspace: &'a Workspace,
+    options: &'a PostProcessing,
+    pdf_path: &'a Path,
+) -> Result<(), failure::Error> {
+    let watermark_image = match options
+        .watermark
+        .as_ref()
+        .and_then(|watermark| watermark.image_url.as_ref())
+    {
+        Some(image_url) => Some(download_watermark_image(workspace, &image_url.0).await?),
+        None => None,
+    };
+
+    let timeout = workspace.config().step_timeout;
+    let sizes = page_sizes(pdf_path, timeout).await?;
+
+    let tex_path = workspace.temp_dir_path().join(OVERLAY_TEX_FILENAME);
+    let overlay_path = tex_path.with_extension("pdf");
+
+    write_rendered_template(
+        &tex_path,
+        overlay_tex(
+            options,
+            &sizes,
+            watermark_image.as_ref().map(String::as_str),
+        ),
+    )
+    .await?;
+    run_latex(workspace, &tex_path).await?;
+
+    qpdf_in_place(
+        pdf_path,
+        vec!["--overlay".into(), overlay_path.into(), "--".into()],
+        timeout,
+    )
+    .await
+}
+
+/// Download the watermark image and give it a name LaTeX can include without escaping. Returns
+/// the new file name, relative to the workspace.
+async fn download_watermark_image<'a>(
+    workspace: &'a Workspace,
+    image_url: &'a hyper::Uri,
+) -> Result<String, failure::Error> {
+    let downloaded_path = workspace
+        .download_file_with_prefix(image_url, WATERMARK_IMAGE_STEM.to_owned())
+        .await
+        .context("Error downloading the watermark image.")?;
+
+    let filename = match downloaded_path.extension() {
+        Some(extension) => format!("{}.{}", WATERMARK_IMAGE_STEM, extension.to_string_lossy()),
+        None => WATERMARK_IMAGE_STEM.to_owned(),
+    };
+
+    std::fs::rename(&downloaded_path, workspace.temp_dir_path().join(&filename))?;
+
+    Ok(filename)
+}
+
+/// Build the LaTeX source of the overlay: one page per entry in `page_sizes` (in points), with
+/// the same dimensions as the corresponding page of the document.
+fn overlay_tex(
+    options: &PostProcessing,
+    page_sizes: &[(f64, f64)],
+    watermark_image: Option<&str>,
+) -> String {
+    let mut tex = String::from(OVERLAY_PREAMBLE);
+    let pages = page_sizes.len();
+
+    for (index, &(width, height)) in page_sizes.iter().enumerate() {
+        let center_x = width / 2.0;
+        let placeholders = |text: &str| {
+            escape_tex_string(
+                &text
+                    .replace("{page}", &(index + 1).to_string())
+                    .replace("{pages}", &pages.to_string()),
+            )
+        };
+
+        writeln!(tex, r"\pdfpagewidth={:.2}bp\relax", width).unwrap();
+        writeln!(tex, r"\pdfpageheight={:.2}bp\relax", height).unwrap();
+        writeln!(tex, r"\shipout\hbox{{\begin{{tikzpicture}}[x=1bp,y=1bp]").unwrap();
+        writeln!(
+            tex,
+            r"\useasboundingbox (0,0) rectangle ({:.2},{:.2});",
+            width, height
+        )
+        .unwrap();
+
+        if let Some(watermark) = &options.watermark {
+            let center = format!("({:.2},{:.2})", center_x, height / 2.0);
+
+            match (watermark_image, &watermark.text) {
+                (Some(image), _) => writeln!(
+                    tex,
+                    r"\begin{{scope}}[opacity={:.2},transparency group]\node[rotate={:.2}] at {} {{\includegraphics[width={:.2}bp]{{{}}}}};\end{{scope}}",
+                    watermark.opacity,
+                    watermark.angle,
+                    center,
+                    width / 2.0,
+                    image
+                )
+                .unwrap(),
+                (None, Some(text)) => {
+                    let font_size = width.min(height) / 6.0;
+                    writeln!(
+                        tex,
+                        r"\node[opacity={:.2},rotate={:.2},font=\fontsize{{{:.2}}}{{{:.2}}}\selectfont] at {} {{{}}};",
+                        watermark.opacity,
+                        watermark.angle,
+                        font_size,
+                        font_size,
+                        center,
+                        escape_tex_string(text)
+                    )
+                    .unwrap()
+                }
+                (None, None) => (),
+            }
+        }
+
+        if let Some(header) = &options.header {
+            writeln!(
+                tex,
+                r"\node[anchor=north,font=\footnotesize] at ({:.2},{:.2}) {{{}}};",
+                center_x,
+                height - MARGIN,
+                placeholders(header)
+            )
+            .unwrap();
+        }
+
+        if let Some(footer) = &options.footer {
+            writeln!(
+                tex,
+                r"\node[anchor=south,font=\footnotesize] at ({:.2},{:.2}) {{{}}};",
+                center_x,
+                MARGIN,
+                placeholders(footer)
+            )
+            .unwrap();
+        }
+
+        if let Some(bates) = &options.bates {
+            writeln!(
+                tex,
+                r"\node[anchor=south east,font=\footnotesize] at ({:.2},{:.2}) {{{}}};",
+                width - MARGIN,
+                MARGIN,
+                escape_tex_string(&bates.number(index))
+            )
+            .unwrap();
+        }
+
+        writeln!(tex, r"\end{{tikzpicture}}}}").unwrap();
+    }
+
+    tex.push_str(OVERLAY_POSTAMBLE);
+    tex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn overlay_tex_has_one_page_per_page_with_placeholders() {
+        let options: PostProcessing = serde_json::from_value(json!({
+            "footer": "Page {page} of {pages} & more",
+            "bates": { "prefix": "ACME_", "start": 7, "digits": 3 },
+        }))
+        .unwrap();
+
+        let tex = overlay_tex(&options, &[(595.276, 841.89), (612.0, 792.0)], None);
+
+        assert_eq!(tex.matches(r"\shipout").count(), 2);
+        assert!(tex.contains(r"\pdfpagewidth=612.00bp\relax"));
+        assert!(tex.contains(r"{Page 1 of 2 \& more}"));
+        assert!(tex.contains(r"{Page 2 of 2 \& more}"));
+        assert!(tex.contains(r"{ACME\_007}"));
+        assert!(tex.contains(r"{ACME\_008}"));
+        assert!(!tex.contains("opacity"));
+    }
+
+    #[test]
+    fn overlay_tex_includes_the_watermark_image() {
+        let options: PostProcessing = serde_json::from_value(json!({
+            "watermark": {
+                "image_url": "https://example.com/logo.png",
+                "opacity": 0.5,
+                "angle": 30,
+            },
+        }))
+        .unwrap();
+
+        let tex = overlay_tex(&options, &[(600.0, 800.0)], Some("papers-watermark.png"));
+
+        assert!(tex.contains(r"[opacity=0.50,transparency group]\node[rotate=30.00]"));
+        assert!(tex.contains(r"\includegraphics[width=300.00bp]{papers-watermark.png}"));
+    }
+}
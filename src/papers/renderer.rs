@@ -1,10 +1,13 @@
-use crate::papers::{DocumentSpec, Workspace};
+use crate::auth::Identity;
+use crate::metrics::Step;
+use crate::papers::{post_process, DocumentSpec, Workspace};
 use crate::prelude::*;
+use crate::request_context::RequestContext;
+use crate::utils::timeout::with_timeout;
 use futures::{compat::*, StreamExt};
 use slog::{debug, error};
-use std::process::Command;
+use std::path::Path;
 use tokio::{fs::File, io::AsyncWrite};
-use tokio_process::CommandExt;
 
 /// The name of the downloaded template inside our Tera instance.
 const TEMPLATE_NAME: &str = "template";
@@ -23,8 +26,13 @@ pub struct Renderer {
 }
 
 impl Renderer {
-    pub fn new(config: Arc<Config>, document_spec: DocumentSpec) -> Result<Self, failure::Error> {
-        let workspace = Workspace::new(config.logger.clone(), config)?;
+    pub fn new(
+        config: Arc<Config>,
+        document_spec: DocumentSpec,
+        identity: &Identity,
+        context: RequestContext,
+    ) -> Result<Self, failure::Error> {
+        let workspace = Workspace::new(config.logger.clone(), config, identity, context)?;
 
         let output_path = workspace
             .temp_dir_path()
@@ -44,6 +52,15 @@ impl Renderer {
     }
 
     pub async fn preview(&mut self) -> Result<String, failure::Error> {
+        let result = self.preview_inner().await;
+
+        self.workspace
+            .export_trace("preview", result.as_ref().err().map(display_error));
+
+        result
+    }
+
+    async fn preview_inner(&mut self) -> Result<String, failure::Error> {
         self.download_and_register_template().await?;
 
         self.tera
@@ -62,7 +79,13 @@ impl Renderer {
             "Generating PDF with document spec: {:?}.", self.document_spec
         );
 
-        match self.render_inner().await {
+        let job_timeout = self.workspace.config().job_timeout;
+
+        let result = with_timeout(self.render_inner(), job_timeout, "the job").await;
+        crate::metrics::job_finished("submit", &result);
+        let error = result.as_ref().err().map(display_error);
+
+        match result {
             // it worked, move on
             Ok(()) => (),
             // it failed -> report it
@@ -72,29 +95,51 @@ impl Renderer {
         }
 
         self.workspace
-            .upload_workspace()
+            .upload_workspace(error.is_some())
             .await
             .map_err(|err| {
                 error!(
                     self.workspace.logger(),
-                    "Error uploading workspace.tar: {:?}.", err
+                    "Error uploading the workspace archive: {:?}.", err
                 )
             })
             .ok();
 
+        self.workspace.export_trace("submit", error);
+
         Ok(())
     }
 
     async fn render_inner(&mut self) -> Result<(), failure::Error> {
         // First download the template and populate it
         self.download_and_register_template().await?;
-        self.render_template().await?;
+        self.workspace
+            .traced("render_template", self.render_template())
+            .await?;
 
         // Download the assets and save them in the temporary directory
-        self.download_assets().await?;
+        let asset_paths = self.download_assets().await?;
+
+        // Reject shell escape, file writes and the like before they reach LaTeX
+        let sources: Vec<&Path> = std::iter::once(self.template_path())
+            .chain(asset_paths.iter().map(|path| path.as_path()))
+            .collect();
+        self.workspace.check_tex_sources(&sources)?;
 
         // Then run latex
-        self.run_latex().await?;
+        self.workspace.traced("latex", self.run_latex()).await?;
+
+        // Watermarks, stamps etc.
+        self.workspace
+            .traced(
+                "post_process",
+                post_process(
+                    &self.workspace,
+                    &self.document_spec.post_processing,
+                    &self.output_path,
+                ),
+            )
+            .await?;
 
         // Upload the resulting PDF and construct a presigned URL to it
         let presigned_url = self
@@ -155,6 +200,7 @@ impl Renderer {
     }
 
     async fn render_template(&self) -> Result<(), failure::Error> {
+        let _timer = crate::metrics::step_timer(Step::RenderTemplate);
         let rendered_template = self
             .tera
             .render(TEMPLATE_NAME, &self.document_spec.variables())
@@ -166,14 +212,7 @@ impl Renderer {
             &self.template_path()
         );
 
-        let mut file = File::create(self.template_path().to_owned())
-            .compat()
-            .await?;
-
-        futures01::future::poll_fn(|| file.poll_write(rendered_template.as_bytes()))
-            .compat()
-            .await
-            .context("Could not write latex file.")?;
+        write_rendered_template(self.template_path(), rendered_template).await?;
 
         debug!(
             self.workspace.logger(),
@@ -196,27 +235,7 @@ impl Renderer {
             self.template_path().exists()
         );
 
-        debug!(&self.workspace.logger(), "Spawning latex.");
-        let latex_out = Command::new("xelatex")
-            .current_dir(&self.workspace.temp_dir_path())
-            .arg("-interaction=nonstopmode")
-            .arg("-file-line-error")
-            .arg("-shell-restricted")
-            .arg(self.template_path())
-            .output_async()
-            .compat()
-            .await
-            .context("Error generating PDF")?;
-
-        let stdout = String::from_utf8(latex_out.stdout)?;
-
-        if !latex_out.status.success() {
-            return Err(format_err!("LaTeX failed. Stdout:\n{}", stdout));
-        }
-
-        debug!(&self.workspace.logger(), "LaTeX succeeded. Stdout:\n{}", stdout);
-
-        Ok(())
+        run_latex(&self.workspace, self.template_path()).await
     }
 
     /// Report failure and move on.
@@ -239,3 +258,50 @@ impl Renderer {
         Ok(())
     }
 }
+
+/// Write a rendered template to `path`, creating or truncating the file.
+pub(crate) async fn write_rendered_template(
+    path: &Path,
+    rendered_template: String,
+) -> Result<(), failure::Error> {
+    let mut file = File::create(path.to_owned()).compat().await?;
+
+    futures01::future::poll_fn(|| file.poll_write(rendered_template.as_bytes()))
+        .compat()
+        .await
+        .context("Could not write latex file.")?;
+
+    Ok(())
+}
+
+/// Run xelatex on the `.tex` file at `template_path`, inside the workspace's sandbox. The PDF
+/// ends up next to the template, with the same file stem.
+pub(crate) async fn run_latex<'a>(
+    workspace: &'a Workspace,
+    template_path: &'a Path,
+) -> Result<(), failure::Error> {
+    let logger = workspace.logger();
+    let _timer = crate::metrics::step_timer(Step::Latex);
+    debug!(logger, "Spawning latex.");
+    let mut command = workspace.command("xelatex");
+    command
+        .arg("-interaction=nonstopmode")
+        .arg("-file-line-error")
+        .arg("-shell-restricted")
+        .arg(template_path);
+
+    let latex_out =
+        crate::utils::process::output(command, "xelatex", workspace.config().step_timeout)
+            .await
+            .context("Error generating PDF")?;
+
+    let stdout = String::from_utf8(latex_out.stdout)?;
+
+    if !latex_out.status.success() {
+        return Err(format_err!("LaTeX failed. Stdout:\n{}", stdout));
+    }
+
+    debug!(logger, "LaTeX succeeded. Stdout:\n{}", stdout);
+
+    workspace.check_disk_usage()
+}
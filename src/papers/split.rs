@@ -0,0 +1,173 @@
+use crate::auth::Identity;
+use crate::papers::{SplitBy, SplitSpec, Workspace};
+use crate::prelude::*;
+use crate::request_context::RequestContext;
+use crate::utils::pdf::{page_count, top_level_bookmark_pages};
+use crate::utils::timeout::with_timeout;
+use slog::{debug, error};
+use std::path::*;
+use std::process::Command;
+
+pub struct Splitter {
+    /// The blueprint for the split.
+    split_spec: SplitSpec,
+    /// See the docs for [`Workspace`](crate::papers::Workspace).
+    workspace: Workspace,
+}
+
+impl Splitter {
+    pub fn new(
+        config: Arc<Config>,
+        split_spec: SplitSpec,
+        identity: &Identity,
+        context: RequestContext,
+    ) -> Result<Self, failure::Error> {
+        let logger = config.logger.clone();
+        let workspace = Workspace::new(logger, config, identity, context)?;
+
+        Ok(Splitter {
+            split_spec,
+            workspace,
+        })
+    }
+
+    /// This function does the whole splitting process from a
+    /// [`SplitSpec`](crate::papers::SplitSpec).
+    ///
+    /// It:
+    ///
+    /// - Downloads the document to split
+    /// - Resolves the page ranges of the parts, reading the bookmarks if needed
+    /// - Extracts every part with qpdf
+    /// - Uploads the parts to S3
+    /// - Reports to the `callback_url` from the `SplitSpec` with the error or the ordered
+    /// presigned urls of the parts.
+    /// - Uploads the debugging output to S3 as a tar file, if the retention policy keeps it.
+    ///
+    /// This method takes ownership because it is meant to be used to create futures to be
+    /// spawned in the background.
+    pub async fn split_document(self) -> Result<(), ()> {
+        let job_timeout = self.workspace.config().job_timeout;
+
+        let result = with_timeout(self.split_document_inner(), job_timeout, "the job").await;
+        crate::metrics::job_finished("split", &result);
+        let error = result.as_ref().err().map(display_error);
+
+        if let Err(err) = result {
+            self.report_failure(err).await.ok();
+        }
+
+        self.workspace
+            .upload_workspace(error.is_some())
+            .await
+            .map_err(|err| {
+                error!(
+                    self.workspace.logger(),
+                    "Error uploading the workspace archive: {:?}.", err
+                )
+            })
+            .ok();
+
+        self.workspace.export_trace("split", error);
+
+        Ok(())
+    }
+
+    async fn split_document_inner(&self) -> Result<(), failure::Error> {
+        // Download
+        let source_path = self
+            .workspace
+            .download_file_with_prefix(
+                self.split_spec.source_url(),
+                uuid::Uuid::new_v4().to_string(),
+            )
+            .await
+            .context("Error downloading the document to split.")?;
+
+        // Resolve the parts
+        let timeout = self.workspace.config().step_timeout;
+        let pages = page_count(&source_path, timeout).await?;
+
+        let bookmark_pages = match self.split_spec.split_by {
+            SplitBy::Bookmarks => top_level_bookmark_pages(&source_path, timeout)
+                .await
+                .context("Error reading the bookmarks.")?,
+            _ => Vec::new(),
+        };
+
+        let parts = self.split_spec.split_by.resolve(pages, &bookmark_pages)?;
+
+        debug!(
+            self.workspace.logger(),
+            "Splitting {} pages into {:?}.", pages, parts
+        );
+
+        // Split and upload, preserving the order of the parts
+        let mut presigned_urls = Vec::with_capacity(parts.len());
+
+        for (index, (start, end)) in parts.into_iter().enumerate() {
+            let part_path = self
+                .workspace
+                .temp_dir_path()
+                .join(self.split_spec.part_filename(index));
+
+            self.workspace
+                .traced(
+                    "extract_pages",
+                    self.extract_pages(&source_path, start, end, &part_path),
+                )
+                .await
+                .with_context(|_| format!("Error extracting pages {}-{}.", start, end))?;
+
+            presigned_urls.push(self.workspace.upload_to_s3(part_path).await?);
+        }
+
+        // Report success
+        let callback_url = self.split_spec.callback_url();
+
+        self.workspace
+            .report_files_success(presigned_urls, &callback_url)
+            .await
+    }
+
+    /// Write pages `start` to `end` (1-based, inclusive) of `source_path` to `part_path`.
+    async fn extract_pages<'a>(
+        &'a self,
+        source_path: &'a Path,
+        start: u32,
+        end: u32,
+        part_path: &'a Path,
+    ) -> Result<(), failure::Error> {
+        let mut command = Command::new("qpdf");
+        command
+            .current_dir(self.workspace.temp_dir_path())
+            .arg("--empty")
+            .arg("--pages")
+            .arg(source_path)
+            .arg(format!("{}-{}", start, end))
+            .arg("--")
+            .arg(part_path);
+
+        let output =
+            crate::utils::process::run(command, "qpdf", self.workspace.config().step_timeout)
+                .await?;
+
+        debug!(self.workspace.logger(), "qpdf output: {}.", output);
+
+        Ok(())
+    }
+
+    async fn report_failure(&self, error: failure::Error) -> Result<(), ()> {
+        error!(
+            self.workspace.logger(),
+            "Error splitting document: {:?}.", error
+        );
+        let callback_url = self.split_spec.callback_url();
+        match self.workspace.report_failure(error, callback_url).await {
+            Ok(()) => (),
+            Err(err) => error!(self.workspace.logger(), "Document split failed: {:?}.", err),
+        }
+
+        Ok(())
+    }
+}
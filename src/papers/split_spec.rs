@@ -0,0 +1,306 @@
+use crate::papers::uri::PapersUri;
+use crate::prelude::*;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SplitSpec {
+    source_url: PapersUri,
+    callback_url: PapersUri,
+    pub split_by: SplitBy,
+    /// The parts are named after this, with a number before the extension.
+    #[serde(default = "default_output_filename")]
+    pub output_filename: String,
+}
+
+/// How to cut the source document into parts.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitBy {
+    /// Explicit page ranges, like `"1-3"`, `"4"` or `"5-"` (until the last page).
+    Ranges(Vec<PageRange>),
+    /// Parts of a fixed number of pages. The last part may be shorter.
+    Every(u32),
+    /// One part per top-level bookmark. Pages before the first bookmark make up their own part.
+    Bookmarks,
+}
+
+/// An inclusive, 1-based range of pages. The end is the last page of the document if missing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageRange {
+    pub start: u32,
+    pub end: Option<u32>,
+}
+
+impl FromStr for PageRange {
+    type Err = failure::Error;
+
+    fn from_str(src: &str) -> Result<PageRange, failure::Error> {
+        let parse_page = |page: &str| -> Result<u32, failure::Error> {
+            match page.trim().parse() {
+                Ok(page) if page > 0 => Ok(page),
+                _ => Err(format_err!("Invalid page number in range \"{}\".", src)),
+            }
+        };
+
+        let mut bounds = src.splitn(2, '-');
+        let start = parse_page(bounds.next().unwrap_or(""))?;
+
+        let end = match bounds.next() {
+            None => Some(start),
+            Some(end) if end.trim().is_empty() => None,
+            Some(end) => Some(parse_page(end)?),
+        };
+
+        if end.map(|end| end < start).unwrap_or(false) {
+            return Err(format_err!(
+                "The page range \"{}\" ends before it starts.",
+                src
+            ));
+        }
+
+        Ok(PageRange { start, end })
+    }
+}
+
+impl std::fmt::Display for PageRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.end {
+            Some(end) if end == self.start => write!(f, "{}", self.start),
+            Some(end) => write!(f, "{}-{}", self.start, end),
+            None => write!(f, "{}-", self.start),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PageRange {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let src = String::deserialize(deserializer)?;
+        src.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for PageRange {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+fn default_output_filename() -> String {
+    format!("out_{}.pdf", Utc::now().to_rfc3339())
+}
+
+impl SplitSpec {
+    pub fn source_url(&self) -> &hyper::Uri {
+        &self.source_url.0
+    }
+
+    pub fn callback_url(&self) -> String {
+        self.callback_url.0.to_string()
+    }
+
+    /// The file name of the part at `index` (0-based).
+    pub fn part_filename(&self, index: usize) -> String {
+        let path = std::path::Path::new(&self.output_filename);
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default();
+        format!("{}_{}.pdf", stem, index + 1)
+    }
+
+    /// Validate that the specification is consistent, and that it can be expected to succeed.
+    ///
+    /// The error is intended for consumption by the client of the service.
+    pub fn validate(&self) -> Result<(), EndpointError> {
+        match &self.split_by {
+            SplitBy::Ranges(ranges) if ranges.is_empty() => {
+                Err(EndpointError::UnprocessableEntity {
+                    cause: format_err!("Cannot split with an empty ranges array."),
+                })
+            }
+            SplitBy::Every(0) => Err(EndpointError::UnprocessableEntity {
+                cause: format_err!("Cannot split every 0 pages."),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl SplitBy {
+    /// Resolve the parts into closed page ranges, for a document with `page_count` pages.
+    /// `bookmark_pages` are the (1-based) pages the top-level bookmarks point to; they are only
+    /// used when splitting by bookmarks.
+    pub fn resolve(
+        &self,
+        page_count: u32,
+        bookmark_pages: &[u32],
+    ) -> Result<Vec<(u32, u32)>, failure::Error> {
+        match self {
+            SplitBy::Ranges(ranges) => ranges
+                .iter()
+                .map(|range| {
+                    let end = range.end.unwrap_or(page_count);
+                    if range.start > page_count || end > page_count {
+                        Err(format_err!(
+                            "The page range \"{}\" is out of bounds, the document has {} pages.",
+                            range,
+                            page_count
+                        ))
+                    } else {
+                        Ok((range.start, end))
+                    }
+                })
+                .collect(),
+            SplitBy::Every(every) => Ok((0..page_count)
+                .step_by(*every as usize)
+                .map(|offset| (offset + 1, (offset + every).min(page_count)))
+                .collect()),
+            SplitBy::Bookmarks => {
+                let mut starts: Vec<u32> = bookmark_pages
+                    .iter()
+                    .cloned()
+                    .filter(|page| *page >= 1 && *page <= page_count)
+                    .collect();
+
+                if starts.is_empty() {
+                    return Err(format_err!("The document has no top-level bookmarks."));
+                }
+
+                starts.sort();
+                starts.dedup();
+
+                if starts[0] != 1 {
+                    starts.insert(0, 1);
+                }
+
+                let ends = starts
+                    .iter()
+                    .skip(1)
+                    .map(|start| start - 1)
+                    .chain(std::iter::once(page_count));
+
+                Ok(starts.iter().cloned().zip(ends).collect())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn page_ranges_parse() {
+        let range = |src: &str| src.parse::<PageRange>().ok();
+
+        assert_eq!(
+            range("1-3"),
+            Some(PageRange {
+                start: 1,
+                end: Some(3)
+            })
+        );
+        assert_eq!(
+            range(" 4 "),
+            Some(PageRange {
+                start: 4,
+                end: Some(4)
+            })
+        );
+        assert_eq!(
+            range("5-"),
+            Some(PageRange {
+                start: 5,
+                end: None
+            })
+        );
+        assert_eq!(range("3-1"), None);
+        assert_eq!(range("0-1"), None);
+        assert_eq!(range("a-b"), None);
+    }
+
+    #[test]
+    fn split_spec_deserializes_every_mode() {
+        let spec = |split_by| {
+            serde_json::from_value::<SplitSpec>(json!({
+                "source_url": "https://example.com/batch.pdf",
+                "callback_url": "https://example.com/callback",
+                "split_by": split_by,
+            }))
+            .unwrap()
+        };
+
+        match spec(json!({ "ranges": ["1-2", "3-"] })).split_by {
+            SplitBy::Ranges(ranges) => assert_eq!(ranges.len(), 2),
+            other => panic!("unexpected mode {:?}", other),
+        }
+
+        match spec(json!({ "every": 2 })).split_by {
+            SplitBy::Every(2) => (),
+            other => panic!("unexpected mode {:?}", other),
+        }
+
+        match spec(json!("bookmarks")).split_by {
+            SplitBy::Bookmarks => (),
+            other => panic!("unexpected mode {:?}", other),
+        }
+    }
+
+    #[test]
+    fn split_spec_validate() {
+        let spec: SplitSpec = serde_json::from_value(json!({
+            "source_url": "https://example.com/batch.pdf",
+            "callback_url": "https://example.com/callback",
+            "split_by": { "every": 0 },
+        }))
+        .unwrap();
+
+        assert!(spec.validate().is_err());
+    }
+
+    #[test]
+    fn part_filenames_are_numbered() {
+        let spec: SplitSpec = serde_json::from_value(json!({
+            "source_url": "https://example.com/batch.pdf",
+            "callback_url": "https://example.com/callback",
+            "split_by": "bookmarks",
+            "output_filename": "payslips.pdf",
+        }))
+        .unwrap();
+
+        assert_eq!(spec.part_filename(0), "payslips_1.pdf");
+        assert_eq!(spec.part_filename(11), "payslips_12.pdf");
+    }
+
+    #[test]
+    fn resolve_ranges() {
+        let ranges = SplitBy::Ranges(vec!["1-2".parse().unwrap(), "4-".parse().unwrap()]);
+
+        assert_eq!(ranges.resolve(5, &[]).unwrap(), vec![(1, 2), (4, 5)]);
+        assert!(ranges.resolve(3, &[]).is_err());
+    }
+
+    #[test]
+    fn resolve_every() {
+        assert_eq!(
+            SplitBy::Every(2).resolve(5, &[]).unwrap(),
+            vec![(1, 2), (3, 4), (5, 5)]
+        );
+    }
+
+    #[test]
+    fn resolve_bookmarks() {
+        assert_eq!(
+            SplitBy::Bookmarks.resolve(10, &[3, 7, 7]).unwrap(),
+            vec![(1, 2), (3, 6), (7, 10)]
+        );
+        assert_eq!(
+            SplitBy::Bookmarks.resolve(10, &[1, 5]).unwrap(),
+            vec![(1, 4), (5, 10)]
+        );
+        assert!(SplitBy::Bookmarks.resolve(10, &[]).is_err());
+    }
+}
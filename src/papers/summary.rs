@@ -6,11 +6,27 @@ pub enum Summary {
     File {
         file: String,
         s3_folder: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subject: Option<String>,
+        #[serde(default)]
+        request_id: String,
+    },
+    Files {
+        files: Vec<String>,
+        s3_folder: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subject: Option<String>,
+        #[serde(default)]
+        request_id: String,
     },
     Error {
         error: String,
         backtrace: String,
         s3_folder: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subject: Option<String>,
+        #[serde(default)]
+        request_id: String,
     },
 }
 
@@ -24,10 +40,12 @@ mod tests {
             backtrace: "".to_owned(),
             error: "meow".to_owned(),
             s3_folder: "/the/bucket/the/key".to_owned(),
+            subject: None,
+            request_id: "a1b2".to_owned(),
         };
         assert_eq!(
             &serde_json::to_string(&summary).unwrap(),
-            "{\"error\":\"meow\",\"backtrace\":\"\",\"s3_folder\":\"/the/bucket/the/key\"}"
+            "{\"error\":\"meow\",\"backtrace\":\"\",\"s3_folder\":\"/the/bucket/the/key\",\"request_id\":\"a1b2\"}"
         );
     }
 
@@ -36,10 +54,43 @@ mod tests {
         let summary = Summary::File {
             file: "https://example.com/the_file.pdf".to_owned(),
             s3_folder: "/my/bucket/my/key".to_owned(),
+            subject: None,
+            request_id: "a1b2".to_owned(),
+        };
+        assert_eq!(
+            &serde_json::to_string(&summary).unwrap(),
+            "{\"file\":\"https://example.com/the_file.pdf\",\"s3_folder\":\"/my/bucket/my/key\",\"request_id\":\"a1b2\"}"
+        );
+    }
+
+    #[test]
+    fn it_serializes_multiple_files_as_expected() {
+        let summary = Summary::Files {
+            files: vec![
+                "https://example.com/part_1.pdf".to_owned(),
+                "https://example.com/part_2.pdf".to_owned(),
+            ],
+            s3_folder: "/my/bucket/my/key".to_owned(),
+            subject: None,
+            request_id: "a1b2".to_owned(),
+        };
+        assert_eq!(
+            &serde_json::to_string(&summary).unwrap(),
+            "{\"files\":[\"https://example.com/part_1.pdf\",\"https://example.com/part_2.pdf\"],\"s3_folder\":\"/my/bucket/my/key\",\"request_id\":\"a1b2\"}"
+        );
+    }
+
+    #[test]
+    fn it_serializes_the_subject_when_there_is_one() {
+        let summary = Summary::File {
+            file: "https://example.com/the_file.pdf".to_owned(),
+            s3_folder: "/my/bucket/my/key".to_owned(),
+            subject: Some("user-42".to_owned()),
+            request_id: "a1b2".to_owned(),
         };
         assert_eq!(
             &serde_json::to_string(&summary).unwrap(),
-            "{\"file\":\"https://example.com/the_file.pdf\",\"s3_folder\":\"/my/bucket/my/key\"}"
+            "{\"file\":\"https://example.com/the_file.pdf\",\"s3_folder\":\"/my/bucket/my/key\",\"subject\":\"user-42\",\"request_id\":\"a1b2\"}"
         );
     }
 }
@@ -1,12 +1,17 @@
+use crate::auth::Identity;
+use crate::metrics::Step;
 use crate::prelude::*;
+use crate::request_context::{RequestContext, RequestId, X_REQUEST_ID};
 use crate::utils::http::{client_response_body_to_file, extract_filename_from_uri};
-use futures::compat::*;
-use slog::{debug, Logger};
+use crate::utils::quota::DiskQuota;
+use crate::utils::trace::{Span, Tracer, TRACEPARENT};
+use futures::{Future, FutureExt, TryFutureExt};
+use slog::{debug, o, warn, Logger};
 
 /// A wrapper around a temporary directory where we download and manipulate files.
 pub struct Workspace {
-    /// The HTTP client for the workspace.
-    client: reqwest::r#async::Client,
+    /// The HTTP client for the workspace. It enforces the egress policy from the config.
+    client: crate::utils::egress::Client,
     /// The app config.
     config: Arc<Config>,
     /// The temporary directory for the task.
@@ -19,20 +24,59 @@ pub struct Workspace {
     logger: Logger,
     /// The directory we will upload to inside the destination S3 bucket.
     s3_dir_name: String,
+    /// The ID of the request that started the job, sent with the callback.
+    request_id: RequestId,
+    /// The `sub` claim of the JWT the job was submitted with, reported to the callback URL.
+    subject: Option<String>,
+    /// The disk space the job may use in the temporary directory.
+    quota: DiskQuota,
+    /// Whether the TeX sources of the job can skip the check for dangerous primitives.
+    trusted_templates: bool,
+    /// The spans of the job.
+    tracer: Tracer,
 }
 
 impl Workspace {
     /// Construct a `Workspace`. The `base_logger` will be used as a base to construct the file +
-    /// stderr logger used in the `Workspace`.
-    pub fn new(base_logger: Logger, config: Arc<Config>) -> Result<Self, failure::Error> {
+    /// stderr logger used in the `Workspace`. The `identity` of the client, the ID of the request
+    /// and the ID of the trace of the job are attached to it. The job continues the trace of the
+    /// caller from the request `context`, if any.
+    pub fn new(
+        base_logger: Logger,
+        config: Arc<Config>,
+        identity: &Identity,
+        context: RequestContext,
+    ) -> Result<Self, failure::Error> {
         let temp_dir = mktemp::Temp::new_dir().context("Could not create a temporary directory")?;
-        let logger = crate::utils::logging::file_logger(base_logger, &temp_dir.to_path_buf());
+        let tracer = Tracer::new(config.otlp_endpoint.clone(), context.trace_parent);
+        let logger = identity
+            .job_logger(crate::utils::logging::file_logger(
+                base_logger,
+                &temp_dir.to_path_buf(),
+                config.workspace_log_format,
+            ))
+            .new(o!(
+                "request_id" => context.request_id.to_string(),
+                "trace_id" => tracer.context().trace_id(),
+            ));
+
+        // The sandboxed processes run as another user, and need to write their output.
+        if let Some(sandbox) = &config.sandbox {
+            crate::utils::sandbox::give_to_sandbox_user(sandbox, temp_dir.as_ref())
+                .context("Could not give the temporary directory to the sandbox user")?;
+        }
+
         Ok(Workspace {
             config: config.clone(),
-            client: reqwest::r#async::Client::new(),
+            client: crate::utils::egress::Client::new(config.egress.clone())?,
             logger,
             temp_dir,
-            s3_dir_name: crate::utils::s3::s3_dir_name(),
+            s3_dir_name: crate::utils::s3::s3_dir_name(&context.request_id),
+            request_id: context.request_id,
+            subject: identity.subject.clone(),
+            quota: DiskQuota::new(config.max_workspace_size),
+            trusted_templates: identity.trusted_templates,
+            tracer,
         })
     }
 
@@ -43,6 +87,139 @@ impl Workspace {
         self.logger.clone()
     }
 
+    /// Start a span for a step of the job. It ends when it is dropped.
+    pub fn span(&self, name: &'static str) -> Span {
+        self.tracer.span(name)
+    }
+
+    /// Run `step` in a span named `name`, which records whether it failed.
+    pub async fn traced<'a, T, F>(
+        &'a self,
+        name: &'static str,
+        step: F,
+    ) -> Result<T, failure::Error>
+    where
+        F: Future<Output = Result<T, failure::Error>> + 'a,
+    {
+        let mut span = self.span(name);
+        let result = step.await;
+        span.record(&result);
+        result
+    }
+
+    /// End the span of the whole job, named after the `endpoint` it was submitted to, and export
+    /// the spans of the job in the background, so a slow collector doesn't hold the job or the
+    /// response back. Failures to export are logged.
+    pub fn export_trace(&self, endpoint: &'static str, error: Option<String>) {
+        let export = self.tracer.export(endpoint, error);
+        let logger = self.logger.clone();
+
+        let export = async move {
+            if let Err(err) = export.await {
+                warn!(
+                    logger,
+                    "Could not export the trace: {}",
+                    display_error(&err)
+                );
+            }
+
+            Ok::<(), ()>(())
+        };
+
+        tokio::executor::spawn(export.boxed().compat());
+    }
+
+    /// The HTTP client for the requests made in `span`, which propagates its trace context.
+    fn client_in(&self, span: &Span) -> crate::utils::egress::Client {
+        let traceparent = http::header::HeaderValue::from_str(&span.context().traceparent())
+            .expect("a traceparent is a valid header value");
+
+        self.client.with_header(
+            http::header::HeaderName::from_static(TRACEPARENT),
+            traceparent,
+        )
+    }
+
+    /// The HTTP client for the callback made in `span`, which also sends the ID of the request.
+    fn callback_client(&self, span: &Span) -> crate::utils::egress::Client {
+        let request_id = http::header::HeaderValue::from_str(self.request_id.as_str())
+            .expect("a request ID is a valid header value");
+
+        self.client_in(span).with_header(
+            http::header::HeaderName::from_static(X_REQUEST_ID),
+            request_id,
+        )
+    }
+
+    /// The app config the workspace was created with.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// A command to run `program` in the workspace's temporary directory, inside the sandbox if
+    /// one is configured. In the sandbox, the files it writes can't be larger than the disk quota
+    /// left to the job. See [`utils::sandbox`](crate::utils::sandbox).
+    pub fn command(&self, program: &str) -> std::process::Command {
+        crate::utils::sandbox::command(
+            self.config.sandbox.as_ref(),
+            self.temp_dir_path(),
+            program,
+            Some(self.quota.remaining()),
+        )
+    }
+
+    /// Check the files among `paths` for dangerous primitives, unless the client has the
+    /// `trusted_templates` scope. Their names come from the URLs and the responses, so every file
+    /// is checked, except the ones whose content is a binary format like PNG or PDF. The checked
+    /// sources may not `\input` the other files of the workspace. See
+    /// [`latex::check_source`](crate::latex::check_source).
+    pub fn check_tex_sources(&self, paths: &[&std::path::Path]) -> Result<(), failure::Error> {
+        if self.trusted_templates {
+            return Ok(());
+        }
+
+        let mut sources = Vec::with_capacity(paths.len());
+
+        for path in paths {
+            let content =
+                std::fs::read(path).with_context(|_| format!("Could not read {:?}", path))?;
+
+            if !crate::latex::is_binary(&content) {
+                sources.push((path, content));
+            }
+        }
+
+        // TeX tries the name with `.tex` first.
+        let unscanned = |input: &str| {
+            let input = input.trim_start_matches("./");
+
+            [format!("{}.tex", input), input.to_owned()]
+                .iter()
+                .map(|name| self.temp_dir_path().join(name))
+                .find(|candidate| candidate.is_file())
+                .map(|found| sources.iter().all(|(path, _)| **path != found))
+                .unwrap_or(false)
+        };
+
+        for (path, content) in &sources {
+            let file = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            crate::latex::check_source(&file, &String::from_utf8_lossy(content), &unscanned)?;
+        }
+
+        Ok(())
+    }
+
+    /// Measure the files in the workspace, and fail with a
+    /// [`QuotaExceeded`](crate::utils::quota::QuotaExceeded) error if they are over the
+    /// `max_workspace_size` of the config. Steps that write files call this when they are done.
+    pub fn check_disk_usage(&self) -> Result<(), failure::Error> {
+        self.quota.update(self.temp_dir_path())
+    }
+
     /// The path to the workspace's temporary directory.
     pub fn temp_dir_path(&self) -> &std::path::Path {
         self.temp_dir.as_ref()
@@ -71,10 +248,26 @@ impl Workspace {
         &'a self,
         uri: &'a hyper::Uri,
         prefix: Option<String>,
+    ) -> Result<std::path::PathBuf, failure::Error> {
+        let mut span = self.span("download");
+        span.set_attribute("http.url", uri.to_string());
+
+        let result = self.download_file_in(&span, uri, prefix).await;
+        span.record(&result);
+        result
+    }
+
+    /// Download the file with the `uri` as a step of `span`.
+    async fn download_file_in<'a>(
+        &'a self,
+        span: &'a Span,
+        uri: &'a hyper::Uri,
+        prefix: Option<String>,
     ) -> Result<std::path::PathBuf, failure::Error> {
         let url = uri.to_string();
+        let _timer = crate::metrics::step_timer(Step::Download);
 
-        let response = self.client.get(&url).send().compat().await?;
+        let response = self.client_in(span).get(&url).await?;
 
         let filename: String = response
             .filename()
@@ -91,9 +284,14 @@ impl Workspace {
 
         debug!(self.logger, "Writing file {:?} as {:?}.", &uri, &dest_path);
 
-        client_response_body_to_file(response, dest_path.clone(), self.config.max_asset_size)
-            .await
-            .context("Error downloading asset")?;
+        client_response_body_to_file(
+            response,
+            dest_path.clone(),
+            self.config.max_asset_size,
+            &self.quota,
+        )
+        .await
+        .context("Error downloading asset")?;
 
         Ok(dest_path)
     }
@@ -103,13 +301,44 @@ impl Workspace {
         presigned_url: String,
         callback_url: &'a str,
     ) -> Result<(), failure::Error> {
-        crate::utils::callbacks::report_success(
+        let mut span = self.span("callback");
+
+        let result = crate::utils::callbacks::report_success(
             self.logger(),
+            &self.callback_client(&span),
             callback_url,
             self.s3_dir_name.clone(),
+            self.subject.clone(),
+            self.request_id.to_string(),
             presigned_url,
         )
-        .await
+        .await;
+
+        span.record(&result);
+        result
+    }
+
+    /// Report the presigned URLs of several documents to the callback URL, in order.
+    pub async fn report_files_success<'a>(
+        &'a self,
+        presigned_urls: Vec<String>,
+        callback_url: &'a str,
+    ) -> Result<(), failure::Error> {
+        let mut span = self.span("callback");
+
+        let result = crate::utils::callbacks::report_files_success(
+            self.logger(),
+            &self.callback_client(&span),
+            callback_url,
+            self.s3_dir_name.clone(),
+            self.subject.clone(),
+            self.request_id.to_string(),
+            presigned_urls,
+        )
+        .await;
+
+        span.record(&result);
+        result
     }
 
     /// Report errors to the callback URL.
@@ -118,13 +347,21 @@ impl Workspace {
         error: failure::Error,
         callback_url: String,
     ) -> Result<(), failure::Error> {
-        crate::utils::callbacks::report_failure(
+        let mut span = self.span("callback");
+
+        let result = crate::utils::callbacks::report_failure(
             self.logger(),
+            &self.callback_client(&span),
             error,
             self.s3_dir_name.to_owned(),
+            self.subject.clone(),
+            self.request_id.to_string(),
             &callback_url,
         )
-        .await
+        .await;
+
+        span.record(&result);
+        result
     }
 
     /// Returns a presigned URL to the uploaded file.
@@ -132,22 +369,36 @@ impl Workspace {
         &self,
         file_path: std::path::PathBuf,
     ) -> Result<String, failure::Error> {
+        self.check_disk_usage()?;
+
         let filename = file_path.file_name().ok_or_else(|| {
             format_err!("missing filename in \"{}\"", file_path.to_string_lossy())
         })?;
         let key = format!("{}/{}", &self.s3_dir_name, filename.to_string_lossy());
-        crate::utils::s3::upload_document(&self.config, self.logger(), file_path, key).await
+
+        self.traced(
+            "s3_upload",
+            crate::utils::s3::upload_document(&self.config, self.logger(), file_path, key),
+        )
+        .await
     }
 
-    /// Upload the whole workspace directory to the S3 directory as `workspace.tar`.
-    pub async fn upload_workspace(&self) -> Result<(), failure::Error> {
-        let workspace_tar_key = format!("{}/{}", &self.s3_dir_name, "workspace.tar");
+    /// Upload the workspace directory as a debug archive, if the retention policy keeps the
+    /// workspaces of jobs that `failed`, or not.
+    pub async fn upload_workspace(&self, failed: bool) -> Result<(), failure::Error> {
+        if !self.config.debug_archives.policy.keeps(failed) {
+            debug!(self.logger(), "The workspace is not archived.");
+            return Ok(());
+        }
 
-        crate::utils::s3::upload_workspace(
-            &self.config,
-            self.logger(),
-            self.temp_dir_path(),
-            workspace_tar_key,
+        self.traced(
+            "upload_workspace",
+            crate::utils::s3::upload_workspace(
+                &self.config,
+                self.logger(),
+                self.temp_dir_path(),
+                &self.s3_dir_name,
+            ),
         )
         .await
     }
@@ -1,7 +1,7 @@
 //! Common imports used throughout the App. We should strive to keep this minimal.
 
 pub(crate) use crate::config::Config;
-pub(crate) use crate::papers::{DocumentSpec, MergeSpec, PapersUri};
+pub(crate) use crate::papers::{DocumentSpec, MergeSpec, PapersUri, SplitSpec};
 pub(crate) use crate::utils::http::ReqwestResponseExt as _;
 use failure::Fail;
 pub use failure::{format_err, ResultExt as _};
@@ -27,6 +27,13 @@ pub enum EndpointError {
         #[fail(cause)]
         cause: failure::Error,
     },
+    #[fail(display = "Too Many Requests (429)")]
+    TooManyRequests {
+        #[fail(cause)]
+        cause: failure::Error,
+        /// The number of seconds the client should wait before retrying.
+        retry_after: u64,
+    },
 }
 
 impl From<serde_json::error::Error> for EndpointError {
@@ -93,6 +100,18 @@ impl EndpointError {
                 *response.status_mut() = http::StatusCode::UNPROCESSABLE_ENTITY;
                 response
             }
+            EndpointError::TooManyRequests { cause, retry_after } => {
+                let body = json!({
+                    "message": display_error(&cause),
+                });
+                let mut response = json_response(&body).expect("serialization error");
+                *response.status_mut() = http::StatusCode::TOO_MANY_REQUESTS;
+                response.headers_mut().insert(
+                    http::header::RETRY_AFTER,
+                    http::header::HeaderValue::from(*retry_after),
+                );
+                response
+            }
         }
     }
 }
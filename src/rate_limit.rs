@@ -0,0 +1,333 @@
+//! Per-client rate limits and concurrent job quotas.
+//!
+//! Clients are identified by their API key name, the `sub` claim of their JWT, the subject of
+//! their client certificate, or their IP address when authentication is disabled. Requests are
+//! limited with a token bucket per client, and every request holds a [`JobSlot`](JobSlot) until
+//! it is answered, or until the job it started is done.
+
+use crate::auth::Identity;
+use crate::config::{RateLimits, SharedConfig};
+use crate::prelude::*;
+use crate::tls::Connection;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use warp::{filters::BoxedFilter, Filter};
+
+/// We can't know when a job will be done, so clients over their job quota are asked to retry
+/// after this many seconds.
+const JOB_QUOTA_RETRY_AFTER_SECONDS: u64 = 10;
+
+/// Above this number of clients, the buckets that are full again are forgotten.
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+/// The state of the limits of every client.
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    jobs: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+/// A token bucket. It holds at most `burst` tokens and is refilled at `per_minute` tokens per
+/// minute.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    per_minute: u32,
+    burst: u32,
+}
+
+impl Bucket {
+    fn full(per_minute: u32, burst: u32, now: Instant) -> Bucket {
+        Bucket {
+            tokens: f64::from(burst),
+            updated: now,
+            per_minute,
+            burst,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let refilled = elapsed.as_secs_f64() * f64::from(self.per_minute) / 60.0;
+
+        self.tokens = (self.tokens + refilled).min(f64::from(self.burst));
+        self.updated = now;
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= f64::from(self.burst)
+    }
+
+    /// Take a token, or return how long until there is one. The limits may have changed since
+    /// the bucket was created.
+    fn take(&mut self, per_minute: u32, burst: u32, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        self.per_minute = per_minute;
+        self.burst = burst;
+        self.tokens = self.tokens.min(f64::from(burst));
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - self.tokens;
+            Err(Duration::from_secs_f64(
+                missing * 60.0 / f64::from(per_minute),
+            ))
+        }
+    }
+}
+
+/// A request or a job in flight. The job quota of the client is released when it is dropped.
+pub(crate) struct JobSlot {
+    jobs: Arc<Mutex<HashMap<String, u32>>>,
+    client: String,
+}
+
+impl Drop for JobSlot {
+    fn drop(&mut self) {
+        let mut jobs = self.jobs.lock().expect("poisoned lock");
+
+        if let Some(count) = jobs.get_mut(&self.client) {
+            *count -= 1;
+
+            if *count == 0 {
+                jobs.remove(&self.client);
+            }
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Check the limits of `client`. If it is within them, take a token from its bucket and
+    /// return a slot from its job quota.
+    fn acquire(
+        &self,
+        client: String,
+        limits: RateLimits,
+        now: Instant,
+    ) -> Result<JobSlot, EndpointError> {
+        let mut jobs = self.jobs.lock().expect("poisoned lock");
+        let in_flight = jobs.get(&client).cloned().unwrap_or(0);
+
+        if let Some(max) = limits.max_concurrent_jobs {
+            if in_flight >= max {
+                return Err(EndpointError::TooManyRequests {
+                    cause: format_err!("There are already {} jobs in flight for this client", max),
+                    retry_after: JOB_QUOTA_RETRY_AFTER_SECONDS,
+                });
+            }
+        }
+
+        if let Some(per_minute) = limits.requests_per_minute {
+            let burst = limits.burst.unwrap_or(per_minute);
+
+            // The configuration rejects these, but a bucket that never refills must not divide
+            // by zero: the client is simply denied.
+            if per_minute == 0 || burst == 0 {
+                return Err(EndpointError::TooManyRequests {
+                    cause: format_err!("This client may not send requests"),
+                    retry_after: JOB_QUOTA_RETRY_AFTER_SECONDS,
+                });
+            }
+
+            self.take_token(&client, per_minute, burst, now)?;
+        }
+
+        jobs.insert(client.clone(), in_flight + 1);
+
+        Ok(JobSlot {
+            jobs: Arc::clone(&self.jobs),
+            client,
+        })
+    }
+
+    fn take_token(
+        &self,
+        client: &str,
+        per_minute: u32,
+        burst: u32,
+        now: Instant,
+    ) -> Result<(), EndpointError> {
+        let mut buckets = self.buckets.lock().expect("poisoned lock");
+
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+
+        buckets
+            .entry(client.to_owned())
+            .or_insert_with(|| Bucket::full(per_minute, burst, now))
+            .take(per_minute, burst, now)
+            .map_err(|wait| EndpointError::TooManyRequests {
+                cause: format_err!("The limit is {} requests per minute", per_minute),
+                retry_after: wait.as_secs_f64().ceil().max(1.0) as u64,
+            })
+    }
+}
+
+/// The key the limits of a client are tracked under.
+fn client_id(identity: &Identity, remote: Option<SocketAddr>) -> String {
+    if let Some(name) = &identity.name {
+        return format!("key:{}", name);
+    }
+
+    if let Some(subject) = &identity.subject {
+        return format!("sub:{}", subject);
+    }
+
+    if let Some(subject) = &identity.client_certificate {
+        return format!("cert:{}", subject);
+    }
+
+    match remote {
+        Some(remote) => format!("ip:{}", remote.ip()),
+        None => "ip:unknown".to_owned(),
+    }
+}
+
+/// The limits of the API key the client authenticated with, falling back to the global ones.
+fn limits_for(config: &Config, identity: &Identity) -> RateLimits {
+    let key_limits = identity
+        .name
+        .as_ref()
+        .and_then(|name| {
+            config
+                .auth
+                .as_ref()?
+                .keys
+                .iter()
+                .find(|key| &key.name == name)
+        })
+        .map(|key| key.limits)
+        .unwrap_or_default();
+
+    key_limits.or(config.rate_limits)
+}
+
+/// A filter that applies the limits of the client identified by `authenticated`, rejecting
+/// requests over them with a 429. It extracts the identity of the client and a job slot, that
+/// should be held until the job is done.
+pub(crate) fn rate_limit_filter(
+    limiter: Arc<RateLimiter>,
+    config: SharedConfig,
+    authenticated: BoxedFilter<(Identity,)>,
+) -> BoxedFilter<(Identity, JobSlot)> {
+    authenticated
+        .and(warp::addr::remote())
+        .and(crate::tls::connection())
+        .and_then(
+            move |identity: Identity,
+                  remote: Option<SocketAddr>,
+                  connection: Option<Connection>| {
+                // Warp doesn't know the address of the clients of the TLS server.
+                let remote = remote.or_else(|| connection.and_then(|connection| connection.remote));
+                let limits = limits_for(&config.current(), &identity);
+
+                limiter
+                    .acquire(client_id(&identity, remote), limits, Instant::now())
+                    .map(|slot| (identity, slot))
+                    .map_err(EndpointError::into_rejection)
+            },
+        )
+        .untuple_one()
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(requests_per_minute: u32, burst: u32, max_concurrent_jobs: u32) -> RateLimits {
+        RateLimits {
+            requests_per_minute: Some(requests_per_minute),
+            burst: Some(burst),
+            max_concurrent_jobs: Some(max_concurrent_jobs),
+        }
+    }
+
+    fn retry_after(result: Result<JobSlot, EndpointError>) -> u64 {
+        match result {
+            Err(EndpointError::TooManyRequests { retry_after, .. }) => retry_after,
+            _ => panic!("expected a TooManyRequests error"),
+        }
+    }
+
+    #[test]
+    fn buckets_allow_bursts_then_refill_at_the_rate() {
+        let limiter = RateLimiter::default();
+        let limits = limits(60, 2, 100);
+        let start = Instant::now();
+
+        assert!(limiter.acquire("a".into(), limits, start).is_ok());
+        assert!(limiter.acquire("a".into(), limits, start).is_ok());
+        assert_eq!(retry_after(limiter.acquire("a".into(), limits, start)), 1);
+
+        // Other clients have their own bucket.
+        assert!(limiter.acquire("b".into(), limits, start).is_ok());
+
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.acquire("a".into(), limits, later).is_ok());
+        assert!(limiter.acquire("a".into(), limits, later).is_err());
+    }
+
+    #[test]
+    fn job_slots_are_released_when_dropped() {
+        let limiter = RateLimiter::default();
+        let limits = RateLimits {
+            max_concurrent_jobs: Some(1),
+            ..RateLimits::default()
+        };
+        let now = Instant::now();
+
+        let slot = limiter.acquire("a".into(), limits, now).unwrap();
+        assert_eq!(
+            retry_after(limiter.acquire("a".into(), limits, now)),
+            JOB_QUOTA_RETRY_AFTER_SECONDS
+        );
+
+        drop(slot);
+        assert!(limiter.acquire("a".into(), limits, now).is_ok());
+    }
+
+    #[test]
+    fn zero_rates_deny_without_poisoning_the_limiter() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        for zero in &[limits(0, 0, 100), limits(60, 0, 100)] {
+            assert_eq!(
+                retry_after(limiter.acquire("a".into(), *zero, now)),
+                JOB_QUOTA_RETRY_AFTER_SECONDS
+            );
+        }
+
+        assert!(limiter.acquire("b".into(), limits(60, 2, 100), now).is_ok());
+    }
+
+    #[test]
+    fn clients_are_identified_by_their_credentials_then_their_address() {
+        let remote = Some(([10, 0, 0, 1], 4567).into());
+        let key = Identity {
+            name: Some("billing".into()),
+            ..Identity::default()
+        };
+        let token = Identity {
+            subject: Some("user-42".into()),
+            ..Identity::default()
+        };
+
+        assert_eq!(client_id(&key, remote), "key:billing");
+        let certificate = Identity {
+            client_certificate: Some("CN=billing".into()),
+            ..Identity::default()
+        };
+
+        assert_eq!(client_id(&token, remote), "sub:user-42");
+        assert_eq!(client_id(&certificate, remote), "cert:CN=billing");
+        assert_eq!(client_id(&Identity::default(), remote), "ip:10.0.0.1");
+    }
+}
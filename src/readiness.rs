@@ -0,0 +1,278 @@
+//! Readiness checks, served on `GET /readyz`.
+//!
+//! Unlike `/healthz`, which only shows that the server answers, these checks make sure that jobs
+//! can run: the external tools are installed, the temporary directory is usable and S3 is
+//! reachable with the current credentials.
+
+use crate::prelude::*;
+use crate::utils::process::whole_output;
+use crate::utils::timeout::with_timeout;
+use futures::compat::*;
+use rusoto_s3::S3;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The time limit of each check. Probes are frequent, so this is much shorter than the step
+/// timeout.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the outcome of the checks is reused. `/readyz` doesn't require authentication, so
+/// it must not start a few processes and an S3 request for every probe.
+const CACHE_DURATION: Duration = Duration::from_secs(5);
+
+/// The external tools jobs run, with the flag printing their version.
+const TOOLS: &[(&str, &str)] = &[
+    ("xelatex", "--version"),
+    ("pdfunite", "-v"),
+    ("convert", "-version"),
+];
+
+/// The outcome of every check.
+#[derive(Debug, Serialize)]
+pub(crate) struct Readiness {
+    /// Whether every check passed.
+    pub(crate) ready: bool,
+    checks: BTreeMap<&'static str, Value>,
+}
+
+impl Readiness {
+    fn add(&mut self, name: &'static str, result: Result<Value, failure::Error>) {
+        let check = match result {
+            Ok(mut details) => {
+                details["ok"] = true.into();
+                details
+            }
+            Err(err) => {
+                self.ready = false;
+                json!({ "ok": false, "error": display_error(&err).trim_end() })
+            }
+        };
+
+        self.checks.insert(name, check);
+    }
+
+    /// The outcome without the details of the checks, which show the versions of the tools,
+    /// the temporary directory and the bucket, for requests without the metrics token.
+    pub(crate) fn summary(&self) -> Value {
+        let checks: BTreeMap<_, _> = self
+            .checks
+            .iter()
+            .map(|(name, check)| (*name, json!({ "ok": check["ok"] })))
+            .collect();
+
+        json!({ "ready": self.ready, "checks": checks })
+    }
+}
+
+/// The last outcome of the checks, shared by every request to `/readyz`.
+#[derive(Default)]
+pub(crate) struct ReadinessCache {
+    last: futures::lock::Mutex<Option<(Instant, Arc<Readiness>)>>,
+}
+
+impl ReadinessCache {
+    /// The outcome of the checks with `config`, run again if the last one is older than
+    /// `CACHE_DURATION`. Requests that arrive while the checks run wait for their outcome
+    /// instead of running their own.
+    pub(crate) async fn check<'a>(&'a self, config: &'a Config) -> Arc<Readiness> {
+        let mut last = self.last.lock().await;
+
+        if let Some((checked, readiness)) = &*last {
+            if checked.elapsed() < CACHE_DURATION {
+                return Arc::clone(readiness);
+            }
+        }
+
+        let readiness = Arc::new(check(config).await);
+        *last = Some((Instant::now(), Arc::clone(&readiness)));
+        readiness
+    }
+}
+
+/// Run every check with `config`. The checks run concurrently.
+pub(crate) async fn check(config: &Config) -> Readiness {
+    let mut readiness = Readiness {
+        ready: true,
+        checks: BTreeMap::new(),
+    };
+
+    let (tools, storage) = futures::future::join(
+        futures::future::join_all(
+            TOOLS
+                .iter()
+                .map(|(program, flag)| check_tool(config, program, flag)),
+        ),
+        check_storage(config),
+    )
+    .await;
+
+    for ((program, _), result) in TOOLS.iter().zip(tools) {
+        readiness.add(program, result);
+    }
+
+    readiness.add("temp_dir", check_temp_dir(config));
+    readiness.add("storage", storage);
+
+    readiness
+}
+
+/// Run `program` with `flag`, in the sandbox if one is configured, and return the first line of
+/// its output as its version.
+async fn check_tool<'a>(
+    config: &'a Config,
+    program: &'a str,
+    flag: &'a str,
+) -> Result<Value, failure::Error> {
+    let mut command = crate::utils::sandbox::command(
+        config.sandbox.as_ref(),
+        &std::env::temp_dir(),
+        program,
+        None,
+    );
+    command.arg(flag);
+
+    let output = crate::utils::process::output(command, program, CHECK_TIMEOUT).await?;
+    let whole_output = whole_output(&output)?;
+
+    if !output.status.success() {
+        return Err(format_err!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            whole_output.trim()
+        ));
+    }
+
+    Ok(json!({ "version": first_line(&whole_output) }))
+}
+
+/// The first non-empty line of `output`.
+fn first_line(output: &str) -> &str {
+    output
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or_default()
+}
+
+/// Create a file in the temporary directory, and make sure there is room for a whole workspace.
+fn check_temp_dir(config: &Config) -> Result<Value, failure::Error> {
+    let temp_dir = std::env::temp_dir();
+
+    let probe = mktemp::Temp::new_file().context("Could not create a temporary file")?;
+    std::fs::File::create(&probe)
+        .and_then(|mut file| file.write_all(b"papers"))
+        .context("Could not write to a temporary file")?;
+
+    let free_bytes = free_space(&temp_dir)
+        .with_context(|_| format!("Could not read the free space of {:?}", temp_dir))?;
+
+    if free_bytes < config.max_workspace_size {
+        return Err(format_err!(
+            "{:?} has {} free, less than the maximum workspace size of {}",
+            temp_dir,
+            crate::human_size::Bytes(free_bytes),
+            crate::human_size::Bytes(config.max_workspace_size)
+        ));
+    }
+
+    Ok(json!({ "path": temp_dir, "free_bytes": free_bytes }))
+}
+
+/// The space available to unprivileged users on the filesystem of `path`, in bytes.
+fn free_space(path: &Path) -> Result<u64, std::io::Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Make sure the bucket exists and the credentials give access to it.
+async fn check_storage(config: &Config) -> Result<Value, failure::Error> {
+    let request = rusoto_s3::HeadBucketRequest {
+        bucket: config.s3.bucket.clone(),
+    };
+    let head_bucket = config.s3.client().head_bucket(request);
+
+    let head_bucket = async move {
+        head_bucket
+            .compat()
+            .await
+            .context("Error reaching the S3 bucket")?;
+        Ok(())
+    };
+
+    with_timeout(head_bucket, CHECK_TIMEOUT, "S3").await?;
+
+    Ok(json!({ "bucket": config.s3.bucket }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_checks_make_the_service_unready() {
+        let mut readiness = Readiness {
+            ready: true,
+            checks: BTreeMap::new(),
+        };
+
+        readiness.add(
+            "pdfunite",
+            Ok(json!({ "version": first_line("\npdfunite version 0.71.0\nCopyright") })),
+        );
+        assert!(readiness.ready);
+
+        readiness.add("storage", Err(format_err!("Access Denied")));
+        assert!(!readiness.ready);
+
+        assert_eq!(
+            serde_json::to_value(&readiness).unwrap(),
+            json!({
+                "ready": false,
+                "checks": {
+                    "pdfunite": { "ok": true, "version": "pdfunite version 0.71.0" },
+                    "storage": { "ok": false, "error": "Access Denied" },
+                },
+            })
+        );
+
+        assert_eq!(
+            readiness.summary(),
+            json!({
+                "ready": false,
+                "checks": {
+                    "pdfunite": { "ok": true },
+                    "storage": { "ok": false },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn recent_checks_are_reused() {
+        let readiness = Arc::new(Readiness {
+            ready: false,
+            checks: BTreeMap::new(),
+        });
+        let cache = ReadinessCache {
+            last: futures::lock::Mutex::new(Some((Instant::now(), Arc::clone(&readiness)))),
+        };
+
+        let config = Config::for_tests();
+        let cached = futures::executor::block_on(cache.check(&config));
+
+        assert!(Arc::ptr_eq(&cached, &readiness));
+    }
+}
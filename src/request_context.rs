@@ -0,0 +1,112 @@
+//! The context of requests that is carried over to the jobs they start: the request ID and the
+//! trace context of the caller.
+
+use crate::utils::trace::{TraceContext, TRACEPARENT};
+use warp::{
+    filters::{header::optional, BoxedFilter},
+    Filter,
+};
+
+/// The header carrying the ID of a request, in requests and responses.
+pub(crate) const X_REQUEST_ID: &str = "x-request-id";
+
+/// The longest request ID we accept from callers.
+const MAX_LENGTH: usize = 128;
+
+/// The ID of a request, tying its response, the logs and the S3 folder of its job, and the
+/// callback together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// The ID from the `X-Request-Id` header of the caller if it is valid, or a new one. Valid
+    /// IDs are made of at most 128 ASCII letters, digits, `-`, `_` and `.`, so they can be used
+    /// in S3 keys.
+    pub fn from_header(header: Option<&str>) -> RequestId {
+        match header {
+            Some(id) if is_valid(id) => RequestId(id.to_owned()),
+            _ => RequestId::generate(),
+        }
+    }
+
+    /// A new, random, request ID.
+    pub fn generate() -> RequestId {
+        RequestId(uuid::Uuid::new_v4().to_string())
+    }
+
+    /// The ID as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LENGTH
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte))
+}
+
+/// What a job knows about the request that started it.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    /// The ID of the request.
+    pub request_id: RequestId,
+    /// The trace context of the caller, from a valid `traceparent` header.
+    pub trace_parent: Option<TraceContext>,
+}
+
+/// A filter that reads or generates the ID of the request, and stores it in the extensions of
+/// the request for [`request_context`](request_context).
+pub(crate) fn assign_request_id() -> BoxedFilter<(RequestId,)> {
+    optional::<String>(X_REQUEST_ID)
+        .map(|header: Option<String>| {
+            let request_id = RequestId::from_header(header.as_ref().map(String::as_str));
+            warp::ext::set(request_id.clone());
+            request_id
+        })
+        .boxed()
+}
+
+/// A filter that extracts the context of the request, with the ID assigned by
+/// [`assign_request_id`](assign_request_id).
+pub(crate) fn request_context() -> BoxedFilter<(RequestContext,)> {
+    warp::ext::get::<RequestId>()
+        .and(optional::<String>(TRACEPARENT))
+        .map(|request_id, traceparent: Option<String>| RequestContext {
+            request_id,
+            trace_parent: traceparent.and_then(|header| TraceContext::parse(&header)),
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_ids_are_kept() {
+        let id = RequestId::from_header(Some("a1b2-c3_d4.e5"));
+        assert_eq!(id.as_str(), "a1b2-c3_d4.e5");
+    }
+
+    #[test]
+    fn invalid_ids_are_replaced() {
+        let too_long = "a".repeat(MAX_LENGTH + 1);
+
+        for invalid in &["", "with space", "../etc", "ünïcode", too_long.as_str()] {
+            let id = RequestId::from_header(Some(invalid));
+            assert_ne!(id.as_str(), *invalid);
+            assert!(is_valid(id.as_str()));
+        }
+
+        assert!(is_valid(RequestId::from_header(None).as_str()));
+    }
+}
@@ -0,0 +1,252 @@
+//! TLS termination with rustls, for deployments without a proxy or a mesh in front of the
+//! service.
+//!
+//! The certificates are loaded again on SIGHUP and when their files change, without dropping
+//! open connections. With a client CA bundle, clients are asked for a certificate (mutual TLS),
+//! and the subject of a verified certificate can be used to authenticate them.
+
+use crate::config::{SharedConfig, TlsConfig};
+use crate::prelude::*;
+use futures01::{Future, Stream};
+use rustls::internal::pemfile;
+use slog::{debug, error, info, Logger};
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::util::FutureExt as _;
+use tokio_rustls::TlsAcceptor;
+use warp::{filters::BoxedFilter, Filter};
+
+/// How often the certificate files are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The time limit of the TLS handshake. Clients that open connections without completing it
+/// would otherwise hold them forever.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What we know about the connection a request was received on. Warp can't see the peer of the
+/// connections we accept ourselves, so this is passed in the request extensions.
+#[derive(Debug, Clone)]
+pub(crate) struct Connection {
+    /// The address of the client.
+    pub(crate) remote: Option<SocketAddr>,
+    /// The subject of the verified client certificate, with mutual TLS.
+    pub(crate) client_certificate: Option<String>,
+}
+
+/// A filter that extracts the connection of requests received over TLS, and `None` for plain
+/// HTTP.
+pub(crate) fn connection() -> BoxedFilter<(Option<Connection>,)> {
+    warp::ext::get::<Connection>()
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify()
+        .boxed()
+}
+
+/// A server for the app on `addr` over TLS, to run on a tokio runtime. The TLS configuration is
+/// checked before the server is returned. Reloading the configuration doesn't change the TLS
+/// settings, only the certificate files are watched.
+pub fn serve(
+    config: SharedConfig,
+    addr: SocketAddr,
+) -> Result<impl Future<Item = (), Error = ()>, failure::Error> {
+    let current = config.current();
+    let tls = current
+        .tls
+        .clone()
+        .ok_or_else(|| format_err!("TLS is not configured"))?;
+    let acceptor = Arc::new(RwLock::new(TlsAcceptor::from(Arc::new(load(&tls)?))));
+    let listener = tokio::net::TcpListener::bind(&addr)?;
+    let logger = current.logger.clone();
+    let app = crate::app(config);
+
+    info!(logger, "Server started on https://{}", addr);
+
+    let reload = reload_on_change(tls, Arc::clone(&acceptor), logger.clone());
+
+    let accept_logger = logger.clone();
+    let server = listener
+        .incoming()
+        .then(move |stream| {
+            if let Err(err) = &stream {
+                error!(accept_logger, "Error accepting a connection: {}", err);
+            }
+            Ok::<_, ()>(stream.ok())
+        })
+        .filter_map(|stream| stream)
+        .for_each(move |stream| {
+            let remote = stream.peer_addr().ok();
+            let acceptor = acceptor.read().expect("poisoned lock").clone();
+            let app = app.clone();
+            let logger = logger.clone();
+
+            let connection = acceptor
+                .accept(stream)
+                .timeout(HANDSHAKE_TIMEOUT)
+                .map_err(move |err| {
+                    if err.is_elapsed() {
+                        debug!(logger, "TLS handshake timed out");
+                    } else if let Some(err) = err.into_inner() {
+                        debug!(logger, "TLS handshake failed: {}", err);
+                    } else {
+                        error!(logger, "Timer error during a TLS handshake");
+                    }
+                })
+                .and_then(move |stream| {
+                    let connection = Connection {
+                        remote,
+                        client_certificate: client_certificate(stream.get_ref().1),
+                    };
+                    let app = warp::any()
+                        .map(move || warp::ext::set(connection.clone()))
+                        .untuple_one()
+                        .and(app);
+
+                    warp::serve(app)
+                        .serve_incoming(futures01::stream::once::<_, std::io::Error>(Ok(stream)))
+                });
+
+            tokio::spawn(connection);
+            Ok(())
+        });
+
+    Ok(futures01::future::lazy(move || {
+        tokio::spawn(reload);
+        server
+    }))
+}
+
+/// Swap the certificates of `acceptor` for new ones on SIGHUP and when the files change. New
+/// connections use the new certificates, open ones are not affected. Invalid certificates are
+/// logged, and the current ones are kept.
+fn reload_on_change(
+    tls: TlsConfig,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+    logger: Logger,
+) -> impl Future<Item = (), Error = ()> {
+    let mut paths = vec![tls.cert.clone(), tls.key.clone()];
+    paths.extend(tls.client_ca.clone());
+
+    let error_logger = logger.clone();
+
+    crate::utils::watch::changes(paths, WATCH_INTERVAL)
+        .for_each(move |()| {
+            match load(&tls) {
+                Ok(server_config) => {
+                    *acceptor.write().expect("poisoned lock") =
+                        TlsAcceptor::from(Arc::new(server_config));
+                    info!(logger, "Reloaded the TLS certificates.");
+                }
+                Err(err) => error!(
+                    logger,
+                    "Could not reload the TLS certificates, keeping the current ones: {}",
+                    display_error(&err)
+                ),
+            }
+            Ok(())
+        })
+        .map_err(move |err| {
+            error!(
+                error_logger,
+                "Stopped watching the TLS certificates: {}", err
+            )
+        })
+}
+
+/// Build the rustls configuration: the certificate chain and the private key of the server, and
+/// the client CA bundle with mutual TLS.
+fn load(tls: &TlsConfig) -> Result<rustls::ServerConfig, failure::Error> {
+    let open = |path: &Path| -> Result<BufReader<std::fs::File>, failure::Error> {
+        let file =
+            std::fs::File::open(path).with_context(|_| format!("Could not open {:?}", path))?;
+        Ok(BufReader::new(file))
+    };
+
+    let certs = pemfile::certs(&mut open(&tls.cert)?)
+        .map_err(|()| format_err!("Invalid certificate chain in {:?}", tls.cert))?;
+
+    if certs.is_empty() {
+        return Err(format_err!("No certificate in {:?}", tls.cert));
+    }
+
+    let invalid_key = |()| format_err!("Invalid private key in {:?}", tls.key);
+    let key = pemfile::pkcs8_private_keys(&mut open(&tls.key)?)
+        .map_err(invalid_key)?
+        .into_iter()
+        .chain(pemfile::rsa_private_keys(&mut open(&tls.key)?).map_err(invalid_key)?)
+        .next()
+        .ok_or_else(|| format_err!("No private key in {:?}", tls.key))?;
+
+    let verifier = match &tls.client_ca {
+        Some(client_ca) => {
+            let mut roots = rustls::RootCertStore::empty();
+            let (added, _) = roots
+                .add_pem_file(&mut open(client_ca)?)
+                .map_err(|()| format_err!("Invalid client CA bundle {:?}", client_ca))?;
+
+            if added == 0 {
+                return Err(format_err!("No certificate in {:?}", client_ca));
+            }
+
+            if tls.client_auth_optional {
+                rustls::AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+            } else {
+                rustls::AllowAnyAuthenticatedClient::new(roots)
+            }
+        }
+        None => rustls::NoClientAuth::new(),
+    };
+
+    let mut server_config = rustls::ServerConfig::new(verifier);
+    server_config
+        .set_single_cert(certs, key)
+        .context("The private key does not match the certificate")?;
+    server_config.set_protocols(&[b"http/1.1".to_vec()]);
+
+    Ok(server_config)
+}
+
+/// The subject of the client certificate of a connection. rustls verified it during the
+/// handshake.
+fn client_certificate(session: &rustls::ServerSession) -> Option<String> {
+    use rustls::Session;
+
+    let certificate = session.get_peer_certificates()?.into_iter().next()?;
+    let x509 = openssl::x509::X509::from_der(&certificate.0).ok()?;
+
+    Some(distinguished_name(x509.subject_name()))
+}
+
+/// Format a name like `CN=billing,O=Example`, in the order of the certificate.
+fn distinguished_name(name: &openssl::x509::X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|value| value.to_string())
+                .unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distinguished_names_are_formatted_in_order() {
+        let mut name = openssl::x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("O", "Example").unwrap();
+        name.append_entry_by_text("CN", "billing").unwrap();
+        let name = name.build();
+
+        assert_eq!(distinguished_name(&name), "O=Example,CN=billing");
+    }
+}
@@ -1,31 +1,64 @@
+use crate::metrics::Step;
 use crate::papers::Summary;
 use crate::prelude::*;
-use futures::compat::*;
-use reqwest::r#async::Client;
+use crate::utils::egress::Client;
+use reqwest::r#async::Response;
 use sentry;
 use slog::{debug, error, info, Logger};
 
 /// This reports to the provided callback url with the presigned URL of the generated PDF and the
-/// location of the debugging output. It returns the response from the callback url as a future.
-pub async fn report_success(
+/// location of the debugging output. The `subject` of the JWT the job was submitted with, if
+/// any, and the ID of the request are included. It returns the response from the callback url as a future.
+pub async fn report_success<'a>(
     logger: Logger,
-    callback_url: &str,
+    client: &'a Client,
+    callback_url: &'a str,
     s3_prefix: String,
+    subject: Option<String>,
+    request_id: String,
     presigned_url: String,
 ) -> Result<(), failure::Error> {
-    let client = Client::new();
     let outcome = Summary::File {
         file: presigned_url,
         s3_folder: s3_prefix,
+        subject,
+        request_id,
     };
 
+    post_success(logger, client, callback_url, outcome).await
+}
+
+/// Same as [`report_success`](report_success), for jobs that produce several documents. The
+/// presigned URLs are reported in order.
+pub async fn report_files_success<'a>(
+    logger: Logger,
+    client: &'a Client,
+    callback_url: &'a str,
+    s3_prefix: String,
+    subject: Option<String>,
+    request_id: String,
+    presigned_urls: Vec<String>,
+) -> Result<(), failure::Error> {
+    let outcome = Summary::Files {
+        files: presigned_urls,
+        s3_folder: s3_prefix,
+        subject,
+        request_id,
+    };
+
+    post_success(logger, client, callback_url, outcome).await
+}
+
+/// Post a successful `Summary` to the callback URL and log the response.
+async fn post_success<'a>(
+    logger: Logger,
+    client: &'a Client,
+    callback_url: &'a str,
+    outcome: Summary,
+) -> Result<(), failure::Error> {
     debug!(logger, "Summary sent to callback: {:?}", outcome);
 
-    let callback_response = client
-        .post(&callback_url.to_string())
-        .json(&outcome)
-        .send()
-        .compat()
+    let callback_response = post_summary(client, callback_url, &outcome)
         .await
         .context("Error posting to callback URL")?;
 
@@ -49,13 +82,15 @@ pub async fn report_success(
 /// When an error occurs during the generation process, it is reported with this function. It calls
 /// the `callback_url` from the document spec, posting a `Summary` object with the error and the
 /// key where the debug output can be found.
-pub async fn report_failure(
+pub async fn report_failure<'a>(
     logger: Logger,
+    client: &'a Client,
     error: failure::Error,
     s3_prefix: String,
-    callback_url: &str,
+    subject: Option<String>,
+    request_id: String,
+    callback_url: &'a str,
 ) -> Result<(), failure::Error> {
-    let client = Client::new();
 
     // For the logs and sentry, we want the most detailed (but less readable) version of the
     // error, so we use the [`Debug`](std::fmt::Debug) implementation.
@@ -69,16 +104,26 @@ pub async fn report_failure(
         backtrace: error.backtrace().to_string(),
         error: err_msg,
         s3_folder: s3_prefix,
+        subject,
+        request_id,
     };
 
     debug!(logger, "Summary sent to callback: {:?}.", outcome);
 
-    client
-        .post(callback_url)
-        .json(&outcome)
-        .send()
-        .compat()
-        .await?;
+    post_summary(client, callback_url, &outcome).await?;
 
     Ok(())
 }
+
+/// Post `summary` to the callback URL, and record the duration and the status of the response.
+async fn post_summary<'a>(
+    client: &'a Client,
+    callback_url: &'a str,
+    summary: &'a Summary,
+) -> Result<Response, failure::Error> {
+    let _timer = crate::metrics::step_timer(Step::Callback);
+    let response = client.post_json(callback_url, summary).await;
+
+    crate::metrics::callback_response(response.as_ref().ok().map(Response::status));
+    response
+}
@@ -0,0 +1,418 @@
+use crate::config::EgressConfig;
+use crate::utils::timeout::with_timeout;
+use failure::format_err;
+use futures::compat::*;
+use futures::TryFutureExt;
+use futures01::{Future as _, Stream};
+use hyper::client::connect::dns::{GaiResolver, Name, Resolve};
+use hyper::client::HttpConnector;
+use hyper_tls::HttpsConnector;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, LOCATION};
+use reqwest::r#async::Response;
+use reqwest::{Method, StatusCode, Url};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+/// The time limit to open a connection, per address.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The time limit of every request and every redirect, until the headers of the response are
+/// received. Reading the body is only limited by the job timeout.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An HTTP client for the URLs provided by clients of the service: templates, assets and
+/// callbacks. Every request, and every redirect, is checked against the egress policy before it
+/// is sent.
+///
+/// The addresses are checked by the resolver of the connector, so they are the addresses the
+/// client connects to, even if the DNS answers change between requests.
+#[derive(Clone)]
+pub struct Client {
+    /// The underlying client. It doesn't follow redirects, we do.
+    client: hyper::Client<HttpsConnector<HttpConnector<PolicyResolver>>>,
+    /// The policy to enforce.
+    policy: EgressConfig,
+    /// Headers sent with every request, like the trace context.
+    headers: HeaderMap,
+}
+
+impl Client {
+    /// Construct a client that enforces `policy`.
+    pub fn new(policy: EgressConfig) -> Result<Client, failure::Error> {
+        let resolver = PolicyResolver {
+            resolver: GaiResolver::new(1),
+            allow_private_addresses: policy.allow_private_addresses,
+        };
+
+        let mut http = HttpConnector::new_with_resolver(resolver);
+        http.enforce_http(false);
+        http.set_connect_timeout(Some(CONNECT_TIMEOUT));
+        let tls = native_tls::TlsConnector::new()?;
+        let client = hyper::Client::builder().build(HttpsConnector::from((http, tls)));
+
+        Ok(Client {
+            client,
+            policy,
+            headers: HeaderMap::new(),
+        })
+    }
+
+    /// A client that sends the `name` header with every request, and redirect.
+    pub fn with_header(&self, name: HeaderName, value: HeaderValue) -> Client {
+        let mut client = self.clone();
+        client.headers.insert(name, value);
+        client
+    }
+
+    /// Send a GET request to `url`.
+    pub async fn get<'a>(&'a self, url: &'a str) -> Result<Response, failure::Error> {
+        self.send(Method::GET, url, None).await
+    }
+
+    /// POST `body` as JSON to `url`.
+    pub async fn post_json<'a, T: serde::Serialize>(
+        &'a self,
+        url: &'a str,
+        body: &'a T,
+    ) -> Result<Response, failure::Error> {
+        let body = serde_json::to_vec(body)?;
+        self.send(Method::POST, url, Some(body)).await
+    }
+
+    /// Send the request, following at most `max_redirects` redirects. Like browsers, we only
+    /// keep the method and the body on 307 and 308 redirects.
+    async fn send<'a>(
+        &'a self,
+        mut method: Method,
+        url: &'a str,
+        mut body: Option<Vec<u8>>,
+    ) -> Result<Response, failure::Error> {
+        let mut url = Url::parse(url)?;
+
+        for _ in 0..=self.policy.max_redirects {
+            self.check(&url)?;
+
+            // Fragments are not sent.
+            let mut target = url.clone();
+            target.set_fragment(None);
+
+            let mut request = http::Request::builder();
+            request.method(method.clone()).uri(target.as_str());
+
+            for (name, value) in &self.headers {
+                request.header(name, value.clone());
+            }
+
+            let request = match &body {
+                Some(body) => request
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(hyper::Body::from(body.clone()))?,
+                None => request.body(hyper::Body::empty())?,
+            };
+
+            let response = self
+                .client
+                .request(request)
+                .compat()
+                .map_err(failure::Error::from);
+            let step = format!("the request to {}", url.host_str().unwrap_or_default());
+            let response = into_response(with_timeout(response, REQUEST_TIMEOUT, &step).await?);
+
+            match response.status() {
+                StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER => {
+                    method = Method::GET;
+                    body = None;
+                }
+                StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => (),
+                _ => return Ok(response),
+            }
+
+            let location = match response.headers().get(LOCATION) {
+                Some(location) => location.to_str()?,
+                None => return Ok(response),
+            };
+
+            url = url.join(location)?;
+        }
+
+        Err(format_err!(
+            "Too many redirects, the limit is {}.",
+            self.policy.max_redirects
+        ))
+    }
+
+    /// Check the scheme and the host of `url`, and the address if the host is one. The
+    /// addresses of names are checked when the client connects, by the
+    /// [`PolicyResolver`](PolicyResolver).
+    fn check(&self, url: &Url) -> Result<(), failure::Error> {
+        check_scheme(&self.policy, url.scheme())?;
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| format_err!("The URL {} has no host.", url))?;
+
+        check_host(&self.policy, host)?;
+
+        match parse_ip(host) {
+            Some(address) if !self.policy.allow_private_addresses && !is_public(address) => {
+                Err(format_err!(
+                    "The address {} is not public, which is not allowed.",
+                    address
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Wrap a hyper response in a reqwest response, which the callers use.
+fn into_response(response: hyper::Response<hyper::Body>) -> Response {
+    response
+        .map(|body| {
+            let body: Box<dyn Stream<Item = hyper::Chunk, Error = hyper::Error> + Send> =
+                Box::new(body);
+            reqwest::r#async::Body::from(body)
+        })
+        .into()
+}
+
+/// Resolves hosts with `getaddrinfo`, and drops the non-public addresses unless the policy
+/// allows them. The connector only connects to the addresses it returns, and fails if there are
+/// none left. Hosts that are IP addresses are not resolved, see [`Client::check`](Client::check).
+#[derive(Clone)]
+struct PolicyResolver {
+    resolver: GaiResolver,
+    allow_private_addresses: bool,
+}
+
+impl Resolve for PolicyResolver {
+    type Addrs = std::vec::IntoIter<IpAddr>;
+    type Future = Box<dyn futures01::Future<Item = Self::Addrs, Error = std::io::Error> + Send>;
+
+    fn resolve(&self, name: Name) -> Self::Future {
+        let host = name.as_str().to_owned();
+        let allow_private_addresses = self.allow_private_addresses;
+
+        let addresses = self.resolver.resolve(name).and_then(move |addresses| {
+            let addresses: Vec<IpAddr> = addresses
+                .filter(|address| allow_private_addresses || is_public(*address))
+                .collect();
+
+            if addresses.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!(
+                        "The host {} only resolves to non-public addresses, which are not allowed.",
+                        host
+                    ),
+                ));
+            }
+
+            Ok(addresses.into_iter())
+        });
+
+        Box::new(addresses)
+    }
+}
+
+fn check_scheme(policy: &EgressConfig, scheme: &str) -> Result<(), failure::Error> {
+    if policy
+        .allowed_schemes
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(scheme))
+    {
+        Ok(())
+    } else {
+        Err(format_err!("The URL scheme {} is not allowed.", scheme))
+    }
+}
+
+/// The denylist wins over the allowlist. An empty allowlist allows every host.
+fn check_host(policy: &EgressConfig, host: &str) -> Result<(), failure::Error> {
+    let denied = policy
+        .denied_hosts
+        .iter()
+        .any(|pattern| host_matches(pattern, host));
+
+    let allowed = policy.allowed_hosts.is_empty()
+        || policy
+            .allowed_hosts
+            .iter()
+            .any(|pattern| host_matches(pattern, host));
+
+    if denied || !allowed {
+        Err(format_err!("The host {} is not allowed.", host))
+    } else {
+        Ok(())
+    }
+}
+
+/// Match `host` against `pattern`, case-insensitively. `*` matches any sequence of characters,
+/// so `*.example.com` matches every subdomain of example.com, but not example.com itself.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+
+    if !host.starts_with(first) {
+        return false;
+    }
+
+    let mut rest = &host[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        None => return rest.is_empty(),
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// Parse IP address hosts, with or without the brackets around IPv6 addresses.
+fn parse_ip(host: &str) -> Option<IpAddr> {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+/// Whether `address` is a public unicast address: not private, loopback, link-local (this
+/// includes cloud metadata endpoints), shared, unspecified, broadcast or multicast.
+fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => is_public_v4(address),
+        IpAddr::V6(address) => is_public_v6(address),
+    }
+}
+
+fn is_public_v4(address: Ipv4Addr) -> bool {
+    let octets = address.octets();
+    // 100.64.0.0/10, carrier-grade NAT.
+    let shared = octets[0] == 100 && (octets[1] & 0b1100_0000) == 64;
+
+    !(address.is_private()
+        || address.is_loopback()
+        || address.is_link_local()
+        || address.is_unspecified()
+        || address.is_broadcast()
+        || address.is_multicast()
+        || shared
+        || octets[0] == 0)
+}
+
+fn is_public_v6(address: Ipv6Addr) -> bool {
+    let segments = address.segments();
+
+    // ::a.b.c.d (IPv4-compatible) and ::ffff:a.b.c.d (IPv4-mapped) addresses can reach IPv4
+    // addresses, and so can 64:ff9b::a.b.c.d through a NAT64 gateway. They are never used by
+    // public hosts.
+    let embeds_ipv4 = segments[..5].iter().all(|segment| *segment == 0)
+        && (segments[5] == 0 || segments[5] == 0xffff);
+    let nat64 = segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0];
+    // fc00::/7, unique local addresses.
+    let unique_local = (segments[0] & 0xfe00) == 0xfc00;
+    // fe80::/10, link-local unicast.
+    let link_local = (segments[0] & 0xffc0) == 0xfe80;
+
+    !(address.is_loopback()
+        || address.is_unspecified()
+        || address.is_multicast()
+        || embeds_ipv4
+        || nat64
+        || unique_local
+        || link_local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures01::Future;
+
+    fn policy() -> EgressConfig {
+        EgressConfig {
+            allowed_schemes: vec!["https".to_owned()],
+            allowed_hosts: vec!["*.example.com".to_owned(), "example.org".to_owned()],
+            denied_hosts: vec!["internal.example.com".to_owned()],
+            allow_private_addresses: false,
+            max_redirects: 5,
+        }
+    }
+
+    #[test]
+    fn host_patterns_match_with_wildcards() {
+        assert!(host_matches("example.com", "EXAMPLE.com"));
+        assert!(host_matches("*.example.com", "assets.example.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "example.com.evil.net"));
+        assert!(host_matches(
+            "assets-*.example.com",
+            "assets-eu.example.com"
+        ));
+        assert!(host_matches("*", "anything"));
+    }
+
+    #[test]
+    fn hosts_are_checked_against_the_lists() {
+        let policy = policy();
+
+        assert!(check_host(&policy, "templates.example.com").is_ok());
+        assert!(check_host(&policy, "example.org").is_ok());
+        assert!(check_host(&policy, "internal.example.com").is_err());
+        assert!(check_host(&policy, "example.net").is_err());
+    }
+
+    #[test]
+    fn schemes_are_checked_against_the_allowlist() {
+        let policy = policy();
+
+        assert!(check_scheme(&policy, "https").is_ok());
+        assert!(check_scheme(&policy, "http").is_err());
+        assert!(check_scheme(&policy, "file").is_err());
+    }
+
+    #[test]
+    fn non_public_addresses_are_detected() {
+        let public = |address: &str| is_public(parse_ip(address).unwrap());
+
+        assert!(public("93.184.216.34"));
+        assert!(public("[2606:2800:220:1:248:1893:25c8:1946]"));
+
+        assert!(!public("127.0.0.1"));
+        assert!(!public("10.1.2.3"));
+        assert!(!public("172.16.0.1"));
+        assert!(!public("192.168.1.1"));
+        assert!(!public("169.254.169.254"));
+        assert!(!public("100.64.0.1"));
+        assert!(!public("0.0.0.0"));
+        assert!(!public("[::1]"));
+        assert!(!public("[fd00::1]"));
+        assert!(!public("[fe80::1]"));
+        assert!(!public("[::ffff:169.254.169.254]"));
+        assert!(!public("[::ffff:93.184.216.34]"));
+        assert!(!public("[::10.0.0.1]"));
+        assert!(!public("[64:ff9b::a9fe:a9fe]"));
+    }
+
+    #[test]
+    fn resolved_non_public_addresses_are_dropped() {
+        let resolver = |allow_private_addresses| PolicyResolver {
+            resolver: GaiResolver::new(1),
+            allow_private_addresses,
+        };
+        let localhost = || "localhost".parse::<Name>().unwrap();
+
+        assert!(resolver(false).resolve(localhost()).wait().is_err());
+        assert!(resolver(true).resolve(localhost()).wait().unwrap().count() > 0);
+    }
+}
@@ -1,3 +1,6 @@
+use crate::human_size::Bytes;
+use crate::utils::quota::DiskQuota;
+
 pub(crate) trait ReqwestResponseExt {
     fn filename(&self) -> Option<String>;
 }
@@ -26,27 +29,51 @@ pub(crate) fn extract_filename_from_uri(uri: &hyper::Uri) -> Option<&str> {
 }
 
 /// For templates and assets. Stream an HTTP response's body directly to a file, without allocating
-/// it all in program memory.
+/// it all in program memory. The file may be at most `size_limit` bytes, and its bytes are
+/// reserved from the `quota` of the workspace. Responses that announce a larger body in their
+/// `Content-Length` are rejected before downloading anything.
 pub(crate) async fn client_response_body_to_file(
     mut response: reqwest::r#async::Response,
     path: std::path::PathBuf,
-    size_limit: u32,
+    size_limit: u64,
+    quota: &DiskQuota,
 ) -> Result<(), failure::Error> {
 use futures::compat::*;
     use futures::stream::StreamExt;
     use tokio::{fs::File, io::AsyncWrite};
 
+    // The body of the response is a stream, which has no length, so read the header.
+    let content_length = response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    if let Some(content_length) = content_length {
+        if content_length > size_limit {
+            return Err(failure::format_err!(
+                "File exceeded max asset size: it is {} bytes, the limit is {}",
+                content_length,
+                Bytes(size_limit)
+            ));
+        }
+
+        quota.check_available(content_length)?;
+    }
+
     let mut file = File::create(path).compat().await?;
     let mut body = response.body_mut().compat();
 
     // Running count of the downloaded file's size in bytes.
-    let mut bytes_size: u32 = 0;
+    let mut bytes_size: u64 = 0;
 
     while let Some(chunk) = body.next().await.transpose()? {
-        bytes_size += chunk.len() as u32;
+        bytes_size += chunk.len() as u64;
         if bytes_size > size_limit {
             return Err(failure::err_msg("File exceeded max asset size"));
         }
+        quota.reserve(chunk.len() as u64)?;
+        crate::metrics::downloaded(chunk.len() as u64);
 
         futures01::future::poll_fn(|| file.poll_write(&chunk))
             .compat()
@@ -1,20 +1,135 @@
-use slog::{o, Drain, Duplicate, Logger};
+use slog::{o, Drain, Duplicate, FnValue, Logger, PushFnValue, Record};
 use sloggers::file::FileLoggerBuilder;
 use sloggers::types::Severity;
 use sloggers::Build;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// The format of the log lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line, for log pipelines.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(src: &str) -> Result<LogFormat, ()> {
+        match src {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// A drain that writes one JSON object per line to `writer`, with the `level`, the `timestamp`
+/// and the `message` of the record, and every key-value of the record and of the logger.
+pub fn json_drain<W>(writer: W) -> slog::Fuse<Mutex<slog_json::Json<W>>>
+where
+    W: std::io::Write + Send + 'static,
+{
+    let json = slog_json::Json::new(writer)
+        .set_newlines(true)
+        .add_key_value(o!(
+            "timestamp" => FnValue(|_: &Record<'_>| chrono::Utc::now().to_rfc3339()),
+            "level" => FnValue(|record: &Record<'_>| record.level().as_str()),
+            "message" => PushFnValue(|record: &Record<'_>, serializer| serializer.emit(record.msg())),
+        ))
+        .build();
+
+    Mutex::new(json).fuse()
+}
 
 /// This returns a logger that also logs to the file pointed by the path parameter on top of the
-/// provided logger. The returned logger logs to both outputs.
+/// provided logger, in `format`. The returned logger logs to both outputs.
 ///
 /// The file logger has the debug level since this is what we want for debugging.
-pub fn file_logger(logger: Logger, path: &Path) -> Logger {
+pub fn file_logger(logger: Logger, path: &Path, format: LogFormat) -> Logger {
     let mut dest = path.to_path_buf();
     dest.push("logs.txt");
-    let file_drain = FileLoggerBuilder::new(dest)
-        .level(Severity::Debug)
-        .build()
-        .expect("Could not create a file logger");
-    let drain = Duplicate::new(logger, file_drain).fuse();
-    Logger::root(drain, o!())
+
+    match format {
+        LogFormat::Text => {
+            let file_drain = FileLoggerBuilder::new(dest)
+                .level(Severity::Debug)
+                .build()
+                .expect("Could not create a file logger");
+            let drain = Duplicate::new(logger, file_drain).fuse();
+            Logger::root(drain, o!())
+        }
+        LogFormat::Json => {
+            let file = std::fs::File::create(dest).expect("Could not create a file logger");
+            let drain = Duplicate::new(logger, json_drain(file)).fuse();
+            Logger::root(drain, o!())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::info;
+    use std::sync::Arc;
+
+    /// A writer whose output can be read while the drain owns it.
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines_have_every_key_value() {
+        let output = Output::default();
+        let logger = Logger::root(json_drain(output.clone()), o!("version" => "1.0"))
+            .new(o!("api_key" => "billing"));
+
+        info!(logger, "Uploading {} files.", 2; "bucket" => "walrus");
+        info!(logger, "Done.");
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["message"], "Uploading 2 files.");
+        assert_eq!(lines[0]["version"], "1.0");
+        assert_eq!(lines[0]["api_key"], "billing");
+        assert_eq!(lines[0]["bucket"], "walrus");
+        assert!(lines[0]["timestamp"].is_string());
+        assert_eq!(lines[1]["message"], "Done.");
+    }
+
+    #[test]
+    fn log_formats_roundtrip() {
+        for format in &[LogFormat::Text, LogFormat::Json] {
+            assert_eq!(format.to_string().parse(), Ok(*format));
+        }
+
+        assert_eq!("yaml".parse::<LogFormat>(), Err(()));
+    }
 }
@@ -1,12 +1,26 @@
 /// Utility-functions for the callbacks of asynchronous jobs.
 pub mod callbacks;
+/// The egress policy for client-provided URLs.
+pub mod egress;
 /// HTTP client related utilities.
 pub mod http;
 /// Logging utilities.
 pub mod logging;
+/// PDF inspection utilities.
+pub mod pdf;
 /// Unix process utilities.
 pub mod process;
+/// Disk quotas for workspaces.
+pub mod quota;
+/// Sandboxing for external tools.
+pub mod sandbox;
 /// Amazon S3 utilities.
 pub mod s3;
 /// Templating utilities.
 pub mod templating;
+/// Deadlines for jobs and external processes.
+pub mod timeout;
+/// Distributed tracing of jobs.
+pub mod trace;
+/// Reload notifications for files on disk.
+pub mod watch;
@@ -0,0 +1,165 @@
+use serde::Deserialize;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+/// The parts of `qpdf --json` output we care about.
+#[derive(Deserialize, Debug)]
+struct QpdfJson {
+    #[serde(default)]
+    outlines: Vec<QpdfOutline>,
+}
+
+#[derive(Deserialize, Debug)]
+struct QpdfOutline {
+    /// The 1-based page the bookmark points to, if it points to a page of the document.
+    #[serde(default)]
+    destpageposfrom1: Option<u32>,
+}
+
+/// Returns the number of pages of the PDF at `path`, as reported by poppler's `pdfinfo`.
+/// Like the other functions in this module, it fails if the process runs longer than `timeout`.
+pub async fn page_count(path: &Path, timeout: Duration) -> Result<u32, failure::Error> {
+    let mut command = Command::new("pdfinfo");
+    command.arg(path);
+
+    let output = crate::utils::process::run(command, "pdfinfo", timeout).await?;
+
+    parse_pages(&output).ok_or_else(|| {
+        failure::format_err!(
+            "Could not find the page count in pdfinfo output:\n{}",
+            output
+        )
+    })
+}
+
+/// Returns the size of every page of the PDF at `path` in PostScript points, as reported by
+/// poppler's `pdfinfo`.
+pub async fn page_sizes(path: &Path, timeout: Duration) -> Result<Vec<(f64, f64)>, failure::Error> {
+    let pages = page_count(path, timeout).await?;
+
+    let mut command = Command::new("pdfinfo");
+    command
+        .arg("-f")
+        .arg("1")
+        .arg("-l")
+        .arg(pages.to_string())
+        .arg(path);
+
+    let output = crate::utils::process::run(command, "pdfinfo", timeout).await?;
+
+    Ok(parse_page_sizes(&output))
+}
+
+/// Returns the (1-based) pages the top-level bookmarks of the PDF at `path` point to, in outline
+/// order. Bookmarks that don't point to a page are skipped.
+pub async fn top_level_bookmark_pages(
+    path: &Path,
+    timeout: Duration,
+) -> Result<Vec<u32>, failure::Error> {
+    let mut command = Command::new("qpdf");
+    command.arg("--json").arg("--json-key=outlines").arg(path);
+
+    let output = crate::utils::process::output(command, "qpdf", timeout).await?;
+
+    // qpdf exits with 3 when the document only has warnings.
+    match output.status.code() {
+        Some(0) | Some(3) => (),
+        _ => {
+            return Err(failure::format_err!(
+                "qpdf failed. Output:\n{}",
+                crate::utils::process::whole_output(&output)?
+            ))
+        }
+    }
+
+    parse_top_level_bookmark_pages(std::str::from_utf8(&output.stdout)?)
+}
+
+/// Extract the pages of the top-level bookmarks from the output of `qpdf --json`.
+fn parse_top_level_bookmark_pages(qpdf_json: &str) -> Result<Vec<u32>, failure::Error> {
+    let parsed: QpdfJson = serde_json::from_str(qpdf_json)?;
+
+    Ok(parsed
+        .outlines
+        .into_iter()
+        .filter_map(|outline| outline.destpageposfrom1)
+        .collect())
+}
+
+/// Extract the value of the `Pages:` line from the output of `pdfinfo`.
+fn parse_pages(pdfinfo_output: &str) -> Option<u32> {
+    pdfinfo_output
+        .lines()
+        .find(|line| line.starts_with("Pages:"))
+        .and_then(|line| line["Pages:".len()..].trim().parse().ok())
+}
+
+/// Extract the `Page    N size: W x H pts` lines from the output of `pdfinfo -f 1 -l N`.
+fn parse_page_sizes(pdfinfo_output: &str) -> Vec<(f64, f64)> {
+    let page_size_re = regex::Regex::new(r"^Page\s+\d+ size:\s+([\d.]+) x ([\d.]+) pts").unwrap();
+
+    pdfinfo_output
+        .lines()
+        .filter_map(|line| page_size_re.captures(line))
+        .filter_map(|captures| {
+            let width = captures.get(1)?.as_str().parse().ok()?;
+            let height = captures.get(2)?.as_str().parse().ok()?;
+            Some((width, height))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PDFINFO_OUTPUT: &str = "Producer:       xdvipdfmx (20170318)
+CreationDate:   Tue Oct 22 10:44:18 2019 CEST
+Tagged:         no
+Pages:          12
+Encrypted:      no
+Page size:      595.276 x 841.89 pts (A4)
+";
+
+    const PDFINFO_PAGES_OUTPUT: &str = "Pages:          2
+Page    1 size: 595.276 x 841.89 pts (A4)
+Page    1 rot:  0
+Page    2 size: 612 x 792 pts (letter)
+Page    2 rot:  0
+";
+
+    #[test]
+    fn parse_pages_works() {
+        assert_eq!(parse_pages(PDFINFO_OUTPUT), Some(12));
+        assert_eq!(parse_pages("Producer: nothing\n"), None);
+    }
+
+    #[test]
+    fn parse_top_level_bookmark_pages_works() {
+        let qpdf_json = r#"{
+            "version": 1,
+            "outlines": [
+                { "dest": [], "destpageposfrom1": 1, "kids": [
+                    { "dest": [], "destpageposfrom1": 2, "kids": [], "title": "Nested" }
+                ], "title": "First" },
+                { "dest": [], "destpageposfrom1": null, "kids": [], "title": "Dangling" },
+                { "dest": [], "destpageposfrom1": 4, "kids": [], "title": "Second" }
+            ]
+        }"#;
+
+        assert_eq!(
+            parse_top_level_bookmark_pages(qpdf_json).unwrap(),
+            vec![1, 4]
+        );
+    }
+
+    #[test]
+    fn parse_page_sizes_works() {
+        assert_eq!(
+            parse_page_sizes(PDFINFO_PAGES_OUTPUT),
+            vec![(595.276, 841.89), (612.0, 792.0)]
+        );
+        assert_eq!(parse_page_sizes(PDFINFO_OUTPUT), vec![]);
+    }
+}
@@ -1,5 +1,33 @@
-use std::process::Output;
+use crate::utils::timeout::Timeout;
+use failure::{format_err, Fail, ResultExt};
+use futures::compat::*;
+use std::os::unix::process::CommandExt as _;
+use std::process::{Command, Output, Stdio};
 use std::str;
+use std::time::Duration;
+use tokio::util::FutureExt as _;
+use tokio_process::CommandExt;
+
+/// Kills the process group of a child process when dropped, unless the child exited.
+///
+/// This covers the child future being dropped before the child exits, for example when the
+/// whole job times out, along with the rest of its process group. Once the child was reaped, its
+/// ID can be reused, so the group is left alone. Processes sandboxed with bubblewrap start a new
+/// session, but they live in a PID namespace that dies with bwrap.
+struct ProcessGroup {
+    id: libc::pid_t,
+    exited: bool,
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if !self.exited {
+            unsafe {
+                libc::kill(-self.id, libc::SIGKILL);
+            }
+        }
+    }
+}
 
 /// Merges the stdout and stderr of a process into a `String`.
 pub fn whole_output(output: &Output) -> Result<String, str::Utf8Error> {
@@ -8,6 +36,75 @@ pub fn whole_output(output: &Output) -> Result<String, str::Utf8Error> {
     Ok(format!("{}\n{}", stdout_str, stderr_str))
 }
 
+/// Run `command` to completion in its own process group and return its output, whatever the
+/// exit status. `program` names the step in errors.
+///
+/// The whole process group is killed if the process is still running after `timeout`, which
+/// fails with a [`Timeout`](crate::utils::timeout::Timeout) error.
+pub async fn output(
+    mut command: Command,
+    program: &str,
+    timeout: Duration,
+) -> Result<Output, failure::Error> {
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpgid(0, 0) == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            }
+        });
+    }
+
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn_async()
+        .with_context(|_| format!("Error running {}", program))?;
+
+    let mut group = ProcessGroup {
+        id: child.id() as libc::pid_t,
+        exited: false,
+    };
+
+    match child.wait_with_output().timeout(timeout).compat().await {
+        Ok(output) => {
+            group.exited = true;
+            Ok(output)
+        }
+        Err(ref err) if err.is_elapsed() => Err(Timeout::new(program, timeout).into()),
+        Err(err) => match err.into_inner() {
+            Some(err) => Err(err.context(format!("Error running {}", program)).into()),
+            None => Err(format_err!("Timer error while running {}.", program)),
+        },
+    }
+}
+
+/// Run `command` to completion and return its whole output. `program` is only used to make error
+/// messages readable. Fails with the whole output if the process exits unsuccessfully, and with a
+/// [`Timeout`](crate::utils::timeout::Timeout) error if it runs longer than `timeout`.
+pub async fn run(
+    command: Command,
+    program: &str,
+    timeout: Duration,
+) -> Result<String, failure::Error> {
+    let output = output(command, program, timeout).await?;
+
+    let stdout_and_err =
+        whole_output(&output).with_context(|_| format!("{} output was not utf8", program))?;
+
+    if !output.status.success() {
+        return Err(format_err!(
+            "{} failed. Output:\n{}",
+            program,
+            stdout_and_err
+        ));
+    }
+
+    Ok(stdout_and_err)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -25,4 +122,25 @@ mod tests {
         assert!(result.contains("nonexistent.exe"));
         assert!(result.contains("No such file"));
     }
+
+    #[test]
+    fn run_fails_with_a_timeout_error() {
+        use futures::FutureExt;
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut command = Command::new("sleep");
+        command.arg("10");
+
+        let started = std::time::Instant::now();
+        let result = runtime.block_on(
+            run(command, "sleep", Duration::from_millis(100))
+                .boxed()
+                .compat(),
+        );
+
+        let err = result.expect_err("sleep should time out");
+        let timeout = err.downcast_ref::<Timeout>().expect("a timeout error");
+        assert_eq!(timeout.step, "sleep");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
 }
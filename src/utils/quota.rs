@@ -0,0 +1,123 @@
+use crate::human_size::Bytes;
+use failure::Fail;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A job used more disk space than its workspace allows.
+#[derive(Debug, Fail)]
+#[fail(
+    display = "Disk quota exceeded: the job needs more than {} of disk space.",
+    limit
+)]
+pub struct QuotaExceeded {
+    /// The quota of the workspace.
+    pub limit: Bytes,
+}
+
+/// The disk space a workspace may use, for downloads, intermediate files and outputs.
+///
+/// Downloads reserve their bytes as they are written, so concurrent downloads share the budget.
+/// The usage is measured again from the files of the workspace after the other steps.
+/// Sandboxed processes can't write files larger than the [`remaining`](DiskQuota::remaining)
+/// space when they start, but their total is only checked after the step.
+#[derive(Debug)]
+pub struct DiskQuota {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl DiskQuota {
+    /// A quota of `limit` bytes, with nothing used yet.
+    pub fn new(limit: u64) -> DiskQuota {
+        DiskQuota {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of bytes that can still be used.
+    pub fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.used.load(Ordering::SeqCst))
+    }
+
+    /// Account for `bytes` more bytes on disk.
+    pub fn reserve(&self, bytes: u64) -> Result<(), QuotaExceeded> {
+        let used = self
+            .used
+            .fetch_add(bytes, Ordering::SeqCst)
+            .saturating_add(bytes);
+        self.check(used)
+    }
+
+    /// Check that `bytes` more bytes would fit, without accounting for them.
+    pub fn check_available(&self, bytes: u64) -> Result<(), QuotaExceeded> {
+        self.check(self.used.load(Ordering::SeqCst).saturating_add(bytes))
+    }
+
+    /// Measure the size of the files in `dir`, and check it against the quota.
+    pub fn update(&self, dir: &Path) -> Result<(), failure::Error> {
+        let used = directory_size(dir)?;
+        self.used.store(used, Ordering::SeqCst);
+        Ok(self.check(used)?)
+    }
+
+    fn check(&self, used: u64) -> Result<(), QuotaExceeded> {
+        if used > self.limit {
+            Err(QuotaExceeded {
+                limit: Bytes(self.limit),
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The total size of the files in `dir` and its subdirectories. Symbolic links are not followed.
+fn directory_size(dir: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.path().symlink_metadata()?;
+
+        size += if metadata.is_dir() {
+            directory_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_share_the_quota() {
+        let quota = DiskQuota::new(100);
+
+        assert!(quota.reserve(60).is_ok());
+        assert_eq!(quota.remaining(), 40);
+        assert!(quota.check_available(41).is_err());
+        assert_eq!(quota.remaining(), 40);
+        assert!(quota.reserve(40).is_ok());
+        assert_eq!(quota.reserve(1).unwrap_err().limit, Bytes(100));
+    }
+
+    #[test]
+    fn updates_measure_the_directory() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        std::fs::create_dir(dir.as_ref().join("nested")).unwrap();
+        std::fs::write(dir.as_ref().join("a.pdf"), vec![0u8; 30]).unwrap();
+        std::fs::write(dir.as_ref().join("nested/b.pdf"), vec![0u8; 50]).unwrap();
+
+        let quota = DiskQuota::new(100);
+        quota.update(dir.as_ref()).unwrap();
+        assert_eq!(quota.remaining(), 20);
+
+        std::fs::write(dir.as_ref().join("c.pdf"), vec![0u8; 21]).unwrap();
+        assert!(quota.update(dir.as_ref()).is_err());
+    }
+}
@@ -1,22 +1,31 @@
 use chrono::Utc;
+use flate2::{write::GzEncoder, Compression};
 use futures::compat::*;
+use futures01::Stream;
 use rusoto_s3::S3;
 use slog::{debug, Logger};
 use std::default::Default;
-use std::path::PathBuf;
-use tokio::{fs::File, io::AsyncWrite};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tokio::codec::{BytesCodec, FramedRead};
+use tokio::fs::File;
 
+use crate::config::DebugArchiveConfig;
+use crate::metrics::Step;
 use crate::prelude::*;
+use crate::request_context::RequestId;
 
-/// Generate a unique s3 bucket directory name. This currently returns a simple timestamp.
-pub fn s3_dir_name() -> String {
-    format!("{}", Utc::now())
+/// Generate a unique s3 bucket directory name: a timestamp, which keeps the directories in
+/// chronological order, followed by the ID of the request. Request IDs are valid in S3 keys.
+pub fn s3_dir_name(request_id: &RequestId) -> String {
+    format!("{}-{}", Utc::now().format("%Y%m%dT%H%M%S%.3fZ"), request_id)
 }
 
-/// Posts the file to the given key in S3 with the default S3 configuration.
+/// Posts the file to the given key in `bucket`. The file is streamed, not read in memory.
 pub async fn post_to_s3(
     config: &Config,
     path: PathBuf,
+    bucket: String,
     key: String,
 ) -> Result<(), failure::Error> {
     use futures::compat::*;
@@ -25,22 +34,27 @@ pub async fn post_to_s3(
 
     debug!(config.logger, "Uploading {:?} to {:?}.", path, key);
     let file = File::open(path).compat().await?;
-    let bytes = Vec::new();
-    let (_, bytes) = tokio::io::read_to_end(file, bytes).compat().await?;
+    let (file, metadata) = file.metadata().compat().await?;
+    let size = metadata.len();
+    let body = FramedRead::new(file, BytesCodec::new()).map(|chunk| chunk.freeze());
 
     let request = rusoto_s3::PutObjectRequest {
-        body: Some(bytes.into()),
-        bucket: config.s3.bucket.clone(),
+        body: Some(rusoto_s3::StreamingBody::new(body)),
+        bucket,
+        content_length: Some(size as i64),
         key,
         ..Default::default()
     };
 
+    let timer = crate::metrics::step_timer(Step::S3Upload);
     client
         .put_object(request)
         .compat()
         .await
         .context("Error during S3 upload")?;
+    drop(timer);
 
+    crate::metrics::uploaded(size);
     Ok(())
 }
 
@@ -65,44 +79,104 @@ pub fn get_presigned_url(config: &Config, key: String) -> String {
     request.get_presigned_url(&config.s3.region, &config.s3.credentials, &options)
 }
 
-/// This function is responsible for uploading a tar file with the contents from the workspace (the
-/// temporary directory where we generated the PDF) to S3 under the given key.
+/// Upload a tar file with the contents of the workspace (the temporary directory where we
+/// generated the PDF) to S3, as configured in
+/// [`DebugArchiveConfig`](crate::config::DebugArchiveConfig): only the files it includes, gzipped
+/// if enabled, under its prefix and in its bucket. `dir_name` is the S3 directory of the job.
 pub async fn upload_workspace<'a>(
     config: &'a Config,
     logger: Logger,
-    workspace: &'a std::path::Path,
-    key: String,
+    workspace: &'a Path,
+    dir_name: &'a str,
 ) -> Result<(), failure::Error> {
-    debug!(logger, "Tarring {:?}.", workspace);
-    let mut tarred_workspace: Vec<u8> = Vec::new();
-
-    let dir_name: PathBuf = workspace
-        .components()
-        .last()
-        .unwrap()
-        .as_os_str()
-        .into();
-    debug!(logger, "Tarring {:?} as {:?}.", &workspace, &dir_name);
-
-    {
-        let mut tarrer = tar::Builder::new(&mut tarred_workspace);
-        tarrer.append_dir_all(&dir_name, &workspace)?;
-        debug!(logger, "Tar was successful.");
-        tarrer.finish()?;
+    let archives = &config.debug_archives;
+
+    let mut files = Vec::new();
+    archived_files(archives, workspace, workspace, &mut files)?;
+    files.sort();
+    debug!(
+        logger,
+        "Archiving {} files of {:?}.",
+        files.len(),
+        workspace
+    );
+
+    let key = archives.key(dir_name);
+    let archive_path = workspace.join(archives.filename());
+    write_archive(workspace, &files, &archive_path, archives.gzip)?;
+
+    let bucket = archives
+        .bucket
+        .clone()
+        .unwrap_or_else(|| config.s3.bucket.clone());
+
+    post_to_s3(config, archive_path, bucket, key).await
+}
+
+/// Collect the files under `dir` that `archives` includes, with their paths relative to
+/// `workspace`. Symbolic links are skipped.
+fn archived_files(
+    archives: &DebugArchiveConfig,
+    workspace: &Path,
+    dir: &Path,
+    files: &mut Vec<PathBuf>,
+) -> Result<(), std::io::Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+
+        if file_type.is_dir() {
+            archived_files(archives, workspace, &path, files)?;
+        } else if file_type.is_file() {
+            let relative = path
+                .strip_prefix(workspace)
+                .expect("paths under the workspace");
+
+            if archives.includes(relative) {
+                files.push(relative.to_owned());
+            }
+        }
     }
 
-    // Write the tarred workspace to disk
-    let mut tar_file_path = workspace.to_path_buf();
-    tar_file_path.push("workspace.tar");
+    Ok(())
+}
 
-    let mut output_file = File::create(tar_file_path.clone()).compat().await?;
+/// Write `files`, relative to `workspace`, to a tar file at `archive_path`, gzipped if `gzip`.
+fn write_archive(
+    workspace: &Path,
+    files: &[PathBuf],
+    archive_path: &Path,
+    gzip: bool,
+) -> Result<(), failure::Error> {
+    let archive = BufWriter::new(std::fs::File::create(archive_path)?);
 
-    futures01::future::poll_fn(|| output_file.poll_write(&tarred_workspace))
-        .compat()
-        .await?;
+    if gzip {
+        let encoder = GzEncoder::new(archive, Compression::default());
+        append_files(encoder, workspace, files)?.finish()?.flush()?;
+    } else {
+        append_files(archive, workspace, files)?.flush()?;
+    }
 
-    // Upload the tarred workspace to S3
-    post_to_s3(config, tar_file_path, key).await
+    Ok(())
+}
+
+/// Write a tar archive of `files` to `writer`, and return it. The entries are in a directory
+/// named like the workspace.
+fn append_files<W: Write>(
+    writer: W,
+    workspace: &Path,
+    files: &[PathBuf],
+) -> Result<W, failure::Error> {
+    let dir_name: PathBuf = workspace.components().last().unwrap().as_os_str().into();
+
+    let mut tarrer = tar::Builder::new(writer);
+
+    for file in files {
+        tarrer.append_path_with_name(workspace.join(file), dir_name.join(file))?;
+    }
+
+    Ok(tarrer.into_inner()?)
 }
 
 /// Takes the path to a generated pdf and a key, returns the presigned url to the uploaded document
@@ -113,6 +187,47 @@ pub async fn upload_document(
     key: String,
 ) -> Result<String, failure::Error> {
     debug!(logger, "Uploading to {:?} / {:?}.", config.s3.bucket, key);
-    post_to_s3(config, local_path, key.clone()).await?;
+    post_to_s3(config, local_path, config.s3.bucket.clone(), key.clone()).await?;
     Ok(get_presigned_url(config, key))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archives_contain_the_included_files() {
+        let workspace = mktemp::Temp::new_dir().unwrap();
+        let workspace: &Path = workspace.as_ref();
+        std::fs::create_dir(workspace.join("assets")).unwrap();
+
+        for file in &["logs.txt", "template.tex", "out.pdf", "assets/chapter.tex"] {
+            std::fs::write(workspace.join(file), file).unwrap();
+        }
+
+        let archives = DebugArchiveConfig {
+            include: vec![glob::Pattern::new("*.tex").unwrap()],
+            exclude: vec![glob::Pattern::new("assets/*").unwrap()],
+            gzip: true,
+            ..DebugArchiveConfig::default()
+        };
+
+        let mut files = Vec::new();
+        archived_files(&archives, workspace, workspace, &mut files).unwrap();
+        assert_eq!(files, vec![PathBuf::from("template.tex")]);
+
+        let archive_path = workspace.join(archives.filename());
+        write_archive(workspace, &files, &archive_path, archives.gzip).unwrap();
+
+        let archive = std::fs::File::open(archive_path).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(archive));
+        let entries: Vec<PathBuf> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect();
+
+        let dir_name = workspace.file_name().unwrap();
+        assert_eq!(entries, vec![Path::new(dir_name).join("template.tex")]);
+    }
+}
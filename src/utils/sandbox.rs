@@ -0,0 +1,204 @@
+use crate::config::SandboxConfig;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+/// The `PATH` inside the sandbox. The environment is cleared so secrets from the service
+/// configuration can't leak to the external tools.
+const SANDBOX_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// Build a [`Command`](std::process::Command) for `program`, running in `working_dir`.
+///
+/// If a sandbox is configured, the program runs inside bubblewrap with no network access, with
+/// a read-only view of the configured system paths, write access to `working_dir` only, and the
+/// resource limits applied by `prlimit`. `working_dir` is mounted at the same path, so absolute
+/// paths to files in it keep working. The size of the files it writes is also limited to
+/// `disk_quota` bytes, the space left to the job, if any.
+pub fn command(
+    sandbox: Option<&SandboxConfig>,
+    working_dir: &Path,
+    program: &str,
+    disk_quota: Option<u64>,
+) -> Command {
+    let sandbox = match sandbox {
+        Some(sandbox) => sandbox,
+        None => {
+            let mut command = Command::new(program);
+            command.current_dir(working_dir);
+            return command;
+        }
+    };
+
+    let mut command = Command::new(&sandbox.bwrap);
+    command
+        .current_dir(working_dir)
+        .env_clear()
+        .env("PATH", SANDBOX_PATH)
+        .env("HOME", "/tmp")
+        .args(bwrap_args(sandbox, working_dir, disk_quota))
+        .arg(program);
+
+    if let Some(uid) = sandbox.uid {
+        std::os::unix::process::CommandExt::uid(&mut command, uid);
+    }
+
+    if let Some(gid) = sandbox.gid {
+        std::os::unix::process::CommandExt::gid(&mut command, gid);
+    }
+
+    command
+}
+
+/// Make `dir` writable by the sandbox user, if the sandbox has one, and by nobody else. The
+/// directory belongs to the sandbox user and group, with mode `0o770`, or `0o700` without a
+/// sandbox group. The service keeps access as root.
+pub fn give_to_sandbox_user(sandbox: &SandboxConfig, dir: &Path) -> Result<(), std::io::Error> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let uid = match sandbox.uid {
+        Some(uid) => uid,
+        None => return Ok(()),
+    };
+    // -1 leaves the group unchanged.
+    let gid = sandbox.gid.unwrap_or(!0);
+    let mode = if sandbox.gid.is_some() { 0o770 } else { 0o700 };
+
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+
+    if unsafe { libc::chown(path.as_ptr(), uid, gid) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(mode))
+}
+
+/// The arguments to bubblewrap and prlimit, up to the wrapped program.
+fn bwrap_args(
+    sandbox: &SandboxConfig,
+    working_dir: &Path,
+    disk_quota: Option<u64>,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![
+        "--unshare-all".into(),
+        "--die-with-parent".into(),
+        "--new-session".into(),
+        "--proc".into(),
+        "/proc".into(),
+        "--dev".into(),
+        "/dev".into(),
+        "--tmpfs".into(),
+        "/tmp".into(),
+    ];
+
+    for path in &sandbox.read_only_paths {
+        args.push("--ro-bind-try".into());
+        args.push(path.into());
+        args.push(path.into());
+    }
+
+    args.push("--bind".into());
+    args.push(working_dir.into());
+    args.push(working_dir.into());
+    args.push("--chdir".into());
+    args.push(working_dir.into());
+
+    let limits = &sandbox.limits;
+    // A single file can't take more than the space left in the workspace.
+    let file_size = disk_quota.map_or(limits.file_size, |quota| quota.min(limits.file_size));
+
+    args.push("prlimit".into());
+    args.push(format!("--cpu={}", limits.cpu_seconds).into());
+    args.push(format!("--as={}", limits.memory).into());
+    args.push(format!("--fsize={}", file_size).into());
+    args.push(format!("--nproc={}", limits.processes).into());
+    args.push("--".into());
+
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ResourceLimits, SandboxConfig};
+
+    #[test]
+    fn bwrap_args_bind_the_workspace_and_apply_the_limits() {
+        let sandbox = SandboxConfig {
+            bwrap: "bwrap".into(),
+            read_only_paths: vec!["/usr".into()],
+            uid: None,
+            gid: None,
+            limits: ResourceLimits {
+                cpu_seconds: 30,
+                memory: 500_000_000,
+                file_size: 50_000_000,
+                processes: 16,
+            },
+        };
+
+        let args: Vec<String> = bwrap_args(&sandbox, Path::new("/tmp/workspace"), None)
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect();
+
+        assert_eq!(
+            args,
+            vec![
+                "--unshare-all",
+                "--die-with-parent",
+                "--new-session",
+                "--proc",
+                "/proc",
+                "--dev",
+                "/dev",
+                "--tmpfs",
+                "/tmp",
+                "--ro-bind-try",
+                "/usr",
+                "/usr",
+                "--bind",
+                "/tmp/workspace",
+                "/tmp/workspace",
+                "--chdir",
+                "/tmp/workspace",
+                "prlimit",
+                "--cpu=30",
+                "--as=500000000",
+                "--fsize=50000000",
+                "--nproc=16",
+                "--",
+            ]
+        );
+    }
+
+    #[test]
+    fn bwrap_args_limit_the_file_size_to_the_disk_quota() {
+        let mut sandbox = SandboxConfig {
+            bwrap: "bwrap".into(),
+            read_only_paths: vec![],
+            uid: None,
+            gid: None,
+            limits: ResourceLimits {
+                cpu_seconds: 30,
+                memory: 500_000_000,
+                file_size: 50_000_000,
+                processes: 16,
+            },
+        };
+
+        let fsize = |sandbox: &SandboxConfig, disk_quota| {
+            bwrap_args(sandbox, Path::new("/tmp/workspace"), disk_quota)
+                .into_iter()
+                .map(|arg| arg.into_string().unwrap())
+                .find(|arg| arg.starts_with("--fsize="))
+                .unwrap()
+        };
+
+        assert_eq!(fsize(&sandbox, Some(1_000_000)), "--fsize=1000000");
+        assert_eq!(fsize(&sandbox, Some(0)), "--fsize=0");
+
+        sandbox.limits.file_size = 10;
+        assert_eq!(fsize(&sandbox, Some(1_000_000)), "--fsize=10");
+    }
+}
@@ -0,0 +1,49 @@
+use failure::Fail;
+use futures::compat::*;
+use futures::{Future, FutureExt};
+use std::time::Duration;
+use tokio::util::FutureExt as _;
+
+/// A step of a job, or the whole job, did not finish in time.
+#[derive(Debug, Fail)]
+#[fail(
+    display = "Timeout: {} did not finish within {} seconds.",
+    step, seconds
+)]
+pub struct Timeout {
+    /// The step that exceeded its budget, like `"xelatex"` or `"the job"`.
+    pub step: String,
+    /// The budget of the step.
+    pub seconds: u64,
+}
+
+impl Timeout {
+    /// A timeout for `step`, after `timeout`.
+    pub fn new(step: &str, timeout: Duration) -> Timeout {
+        Timeout {
+            step: step.to_owned(),
+            seconds: timeout.as_secs(),
+        }
+    }
+}
+
+/// Run `future` to completion, or fail with a [`Timeout`](Timeout) error for `step` after
+/// `timeout`. The future is dropped on timeout, so it must clean up after itself on drop.
+pub async fn with_timeout<'a, T, F>(
+    future: F,
+    timeout: Duration,
+    step: &'a str,
+) -> Result<T, failure::Error>
+where
+    F: Future<Output = Result<T, failure::Error>> + Send + 'a,
+    T: 'a,
+{
+    match future.boxed().compat().timeout(timeout).compat().await {
+        Ok(value) => Ok(value),
+        Err(ref err) if err.is_elapsed() => Err(Timeout::new(step, timeout).into()),
+        Err(err) => match err.into_inner() {
+            Some(err) => Err(err),
+            None => Err(failure::format_err!("Timer error while running {}.", step)),
+        },
+    }
+}
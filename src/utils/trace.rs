@@ -0,0 +1,403 @@
+//! Distributed tracing of jobs. The trace context is read from and propagated with the W3C
+//! `traceparent` header, and the spans are exported to an OpenTelemetry collector with OTLP over
+//! HTTP, in the JSON encoding.
+
+use crate::prelude::*;
+use futures::compat::*;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The name of the header carrying the trace context.
+pub const TRACEPARENT: &str = "traceparent";
+
+/// The `service.name` of the exported spans.
+const SERVICE_NAME: &str = "papers";
+
+/// The time limit of an export to the collector.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The position of a span in a trace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    sampled: bool,
+}
+
+impl TraceContext {
+    /// Parse a `traceparent` header. Invalid headers are ignored, and a new trace is started, as
+    /// the specification requires.
+    pub fn parse(traceparent: &str) -> Option<TraceContext> {
+        let fields: Vec<&str> = traceparent.trim().split('-').collect();
+
+        let mut version = [0; 1];
+        let mut trace_id = [0; 16];
+        let mut span_id = [0; 8];
+        let mut flags = [0; 1];
+
+        decode_hex(fields.get(0)?, &mut version)?;
+        decode_hex(fields.get(1)?, &mut trace_id)?;
+        decode_hex(fields.get(2)?, &mut span_id)?;
+        decode_hex(fields.get(3)?, &mut flags)?;
+
+        // Later versions may add fields, version 00 has exactly four.
+        let invalid_version = version[0] == 0xff || (version[0] == 0 && fields.len() != 4);
+
+        if invalid_version || trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            sampled: flags[0] & 1 == 1,
+        })
+    }
+
+    /// The context of the first span of a new trace.
+    pub fn new_trace() -> TraceContext {
+        TraceContext {
+            trace_id: random(),
+            span_id: random(),
+            sampled: true,
+        }
+    }
+
+    /// The context of a new span, child of this one.
+    pub fn child(&self) -> TraceContext {
+        TraceContext {
+            span_id: random(),
+            ..*self
+        }
+    }
+
+    /// The ID of the trace, in hexadecimal.
+    pub fn trace_id(&self) -> String {
+        encode_hex(&self.trace_id)
+    }
+
+    /// The `traceparent` header of the requests made in this span.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            encode_hex(&self.trace_id),
+            encode_hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+/// The finished spans of a job.
+type Spans = Arc<Mutex<Vec<Value>>>;
+
+/// The spans of a job. They are kept in memory, and exported together when the job is done.
+///
+/// Nothing is recorded when no OTLP endpoint is configured, or when the caller's trace is not
+/// sampled.
+#[derive(Debug)]
+pub struct Tracer {
+    /// The OTLP/HTTP endpoint of the collector, like `http://localhost:4318`.
+    endpoint: Option<String>,
+    /// The span of the whole job.
+    job: TraceContext,
+    /// The span of the caller, if the request had a `traceparent` header.
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    spans: Spans,
+}
+
+impl Tracer {
+    /// A tracer exporting to `endpoint`, continuing the trace of `parent` if any.
+    pub fn new(endpoint: Option<String>, parent: Option<TraceContext>) -> Tracer {
+        let job = match parent {
+            Some(parent) => parent.child(),
+            None => TraceContext::new_trace(),
+        };
+
+        Tracer {
+            endpoint: endpoint.filter(|_| job.sampled),
+            job,
+            parent_span_id: parent.map(|parent| parent.span_id),
+            start: SystemTime::now(),
+            spans: Spans::default(),
+        }
+    }
+
+    /// The context of the span of the whole job.
+    pub fn context(&self) -> TraceContext {
+        self.job
+    }
+
+    /// Start a span for a step of the job. It ends when it is dropped.
+    pub fn span(&self, name: &'static str) -> Span {
+        Span {
+            name,
+            context: self.job.child(),
+            parent_span_id: self.job.span_id,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            error: None,
+            spans: self.endpoint.as_ref().map(|_| Arc::clone(&self.spans)),
+        }
+    }
+
+    /// End the span of the whole job, named `name`, with the `error` it failed with if any. The
+    /// returned future sends every span to the collector. It doesn't borrow the tracer, so it
+    /// can run in the background.
+    pub fn export(
+        &self,
+        name: &'static str,
+        error: Option<String>,
+    ) -> impl std::future::Future<Output = Result<(), failure::Error>> + Send + 'static {
+        let request = self.endpoint.as_ref().map(|endpoint| {
+            let job_span = span_json(
+                name,
+                &self.job,
+                self.parent_span_id,
+                self.start,
+                &[],
+                error.as_ref(),
+            );
+
+            let spans: Vec<Value> = {
+                let mut spans = self.spans.lock().expect("poisoned lock");
+                spans.drain(..).chain(std::iter::once(job_span)).collect()
+            };
+
+            let body = json!({
+                "resourceSpans": [{
+                    "resource": {
+                        "attributes": [attribute_json("service.name", SERVICE_NAME)],
+                    },
+                    "scopeSpans": [{
+                        "scope": { "name": SERVICE_NAME, "version": env!("CARGO_PKG_VERSION") },
+                        "spans": spans,
+                    }],
+                }],
+            });
+
+            (
+                format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+                body,
+            )
+        });
+
+        async move {
+            let (url, body) = match request {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+
+            let response = reqwest::r#async::Client::builder()
+                .timeout(EXPORT_TIMEOUT)
+                .build()?
+                .post(&url)
+                .json(&body)
+                .send()
+                .compat()
+                .await
+                .context("Error sending the spans to the collector")?;
+
+            if !response.status().is_success() {
+                return Err(format_err!(
+                    "The collector rejected the spans with {}",
+                    response.status()
+                ));
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// A step of a job. It is recorded when it is dropped.
+#[derive(Debug)]
+pub struct Span {
+    name: &'static str,
+    context: TraceContext,
+    parent_span_id: [u8; 8],
+    start: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    /// The error the step failed with.
+    error: Option<String>,
+    /// Where the span goes when it ends, if it is recorded.
+    spans: Option<Spans>,
+}
+
+impl Span {
+    /// The context of the span, to propagate to the requests made in it.
+    pub fn context(&self) -> TraceContext {
+        self.context
+    }
+
+    /// Add an attribute, like the URL of a download.
+    pub fn set_attribute(&mut self, key: &'static str, value: String) {
+        self.attributes.push((key, value));
+    }
+
+    /// Mark the span as failed if `result` is an error.
+    pub fn record<T>(&mut self, result: &Result<T, failure::Error>) {
+        if let Err(err) = result {
+            self.error = Some(display_error(err));
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(spans) = &self.spans {
+            let span = span_json(
+                self.name,
+                &self.context,
+                Some(self.parent_span_id),
+                self.start,
+                &self.attributes,
+                self.error.as_ref(),
+            );
+
+            spans.lock().expect("poisoned lock").push(span);
+        }
+    }
+}
+
+/// A span in the OTLP JSON encoding, ending now.
+fn span_json(
+    name: &str,
+    context: &TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    attributes: &[(&'static str, String)],
+    error: Option<&String>,
+) -> Value {
+    let status = match error {
+        Some(message) => json!({ "code": 2, "message": message }),
+        None => json!({ "code": 1 }),
+    };
+
+    json!({
+        "traceId": encode_hex(&context.trace_id),
+        "spanId": encode_hex(&context.span_id),
+        "parentSpanId": parent_span_id.map(|id| encode_hex(&id)).unwrap_or_default(),
+        "name": name,
+        // SPAN_KIND_INTERNAL
+        "kind": 1,
+        "startTimeUnixNano": unix_nanos(start),
+        "endTimeUnixNano": unix_nanos(SystemTime::now()),
+        "attributes": attributes
+            .iter()
+            .map(|(key, value)| attribute_json(key, value))
+            .collect::<Vec<_>>(),
+        "status": status,
+    })
+}
+
+fn attribute_json(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// The nanoseconds since the epoch, as a string since they don't fit in JSON numbers.
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn random<T: AsMut<[u8]> + Default>() -> T {
+    let mut bytes = T::default();
+    openssl::rand::rand_bytes(bytes.as_mut()).expect("The system has no source of randomness");
+    bytes
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decode the lowercase hexadecimal `src` into `out`, which it must fill exactly.
+fn decode_hex(src: &str, out: &mut [u8]) -> Option<()> {
+    let lowercase_hex = src
+        .bytes()
+        .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte));
+
+    if src.len() != out.len() * 2 || !lowercase_hex {
+        return None;
+    }
+
+    for (index, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&src[index * 2..index * 2 + 2], 16).ok()?;
+    }
+
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT_EXAMPLE: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn traceparent_roundtrips() {
+        let context = TraceContext::parse(TRACEPARENT_EXAMPLE).unwrap();
+
+        assert_eq!(context.trace_id(), "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(context.traceparent(), TRACEPARENT_EXAMPLE);
+        assert_eq!(context.child().trace_id(), context.trace_id());
+        assert_ne!(context.child().traceparent(), TRACEPARENT_EXAMPLE);
+    }
+
+    #[test]
+    fn invalid_traceparents_are_ignored() {
+        let invalid = [
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "00-0af7651916cd43dd8448eb211c8031+c-b7ad6b7169203331-01",
+        ];
+
+        for traceparent in &invalid {
+            assert_eq!(TraceContext::parse(traceparent), None, "{}", traceparent);
+        }
+
+        assert!(TraceContext::parse(
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra"
+        )
+        .is_some());
+    }
+
+    #[test]
+    fn spans_are_recorded_with_an_endpoint_and_a_sampled_parent() {
+        let unsampled = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00";
+        let tracers = vec![
+            (Tracer::new(Some("http://localhost:4318".into()), None), 1),
+            (Tracer::new(None, None), 0),
+            (
+                Tracer::new(
+                    Some("http://localhost:4318".into()),
+                    TraceContext::parse(unsampled),
+                ),
+                0,
+            ),
+        ];
+
+        for (tracer, recorded) in tracers {
+            let mut span = tracer.span("latex");
+            span.record::<()>(&Err(format_err!("LaTeX failed")));
+            drop(span);
+
+            let spans = tracer.spans.lock().unwrap();
+            assert_eq!(spans.len(), recorded);
+
+            if let Some(span) = spans.first() {
+                assert_eq!(span["name"], "latex");
+                assert_eq!(span["traceId"], tracer.context().trace_id());
+                assert_eq!(span["status"]["message"], "LaTeX failed");
+            }
+        }
+    }
+}
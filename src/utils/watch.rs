@@ -0,0 +1,59 @@
+use futures01::{Future, Stream};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// A stream that yields every time the process receives SIGHUP, or one of the files at `paths`
+/// changes. Files are checked every `interval`, by modification time, so this also catches
+/// mounted secrets and config maps whose symlinks are swapped.
+pub fn changes(
+    paths: Vec<PathBuf>,
+    interval: Duration,
+) -> impl Stream<Item = (), Error = failure::Error> {
+    let sighup = tokio_signal::unix::Signal::new(tokio_signal::unix::SIGHUP)
+        .flatten_stream()
+        .map(|_| ())
+        .map_err(failure::Error::from);
+
+    let mut last_modified = modification_times(&paths);
+
+    let file_changes = tokio::timer::Interval::new_interval(interval)
+        .map_err(failure::Error::from)
+        .filter_map(move |_| {
+            let modified = modification_times(&paths);
+
+            if modified == last_modified {
+                None
+            } else {
+                last_modified = modified;
+                Some(())
+            }
+        });
+
+    sighup.select(file_changes)
+}
+
+/// The modification time of every file, `None` for the ones we can't stat.
+fn modification_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_files_have_no_modification_time() {
+        let file = mktemp::Temp::new_file().unwrap();
+        let times = modification_times(&[file.to_path_buf(), "/does/not/exist".into()]);
+
+        assert!(times[0].is_some());
+        assert!(times[1].is_none());
+    }
+}
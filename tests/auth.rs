@@ -130,3 +130,58 @@ fn test_preview_succeeds_if_auth_header_matches_env_var() {
     );
     assert_eq!(response.status(), 400);
 }
+
+fn config_with_api_keys() -> Config {
+    use papers::config::{ApiKey, Scope};
+
+    Config::for_tests().with_api_keys(vec![
+        ApiKey::from_secret("old".to_string(), "old-secret", vec![Scope::Submit]),
+        ApiKey::from_secret("new".to_string(), "new-secret", vec![Scope::Submit]),
+        ApiKey::from_secret(
+            "previews".to_string(),
+            "preview-secret",
+            vec![Scope::Preview],
+        ),
+    ])
+}
+
+#[test]
+fn test_submit_accepts_every_configured_key() {
+    let mut test_setup_config = TestSetupConfig::default();
+    test_setup_config.set_config(config_with_api_keys());
+    let test_setup = TestSetup::start(test_setup_config);
+
+    for secret in &["old-secret", "new-secret"] {
+        let response = test_setup
+            .client()
+            .post(&test_setup.papers_url("submit"))
+            .header("Authorization", format!("Bearer {}", secret))
+            .json(&json!({}))
+            .send()
+            .unwrap();
+
+        // 400 error code here because the posted DocumentSpec is invalid
+        assert_eq!(response.status(), 400);
+    }
+}
+
+#[test]
+fn test_submit_fails_if_the_key_does_not_have_the_scope() {
+    let mut test_setup_config = TestSetupConfig::default();
+    test_setup_config.set_config(config_with_api_keys());
+    let test_setup = TestSetup::start(test_setup_config);
+
+    let mut response = test_setup
+        .client()
+        .post(&test_setup.papers_url("submit"))
+        .header("Authorization", "Bearer preview-secret")
+        .json(&json!({}))
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), 403);
+    assert!(response
+        .text()
+        .unwrap()
+        .contains("does not have the submit scope"));
+}
@@ -0,0 +1,52 @@
+mod toolbox;
+
+use papers::Config;
+use toolbox::*;
+
+#[test]
+fn test_metrics_are_not_behind_the_api_keys() {
+    let mut config = TestSetupConfig::default();
+    config.set_config(Config::for_tests().with_auth("secret-string".to_string()));
+    let test_setup = TestSetup::start(config);
+
+    let mut response = test_setup
+        .client()
+        .get(&test_setup.papers_url("metrics"))
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    assert!(response.text().unwrap().contains("papers_jobs_active"));
+}
+
+#[test]
+fn test_metrics_token_is_required_if_configured() {
+    let mut config = TestSetupConfig::default();
+    config.set_config(
+        Config::for_tests()
+            .with_auth("secret-string".to_string())
+            .with_metrics_token("metrics-string".to_string()),
+    );
+    let test_setup = TestSetup::start(config);
+
+    let get = |auth_header: &str| {
+        test_setup
+            .client()
+            .get(&test_setup.papers_url("metrics"))
+            .header("Authorization", auth_header)
+            .send()
+            .unwrap()
+            .status()
+    };
+
+    assert_eq!(get("Bearer metrics-string"), 200);
+    assert_eq!(get("Bearer secret-string"), 403);
+
+    let response = test_setup
+        .client()
+        .get(&test_setup.papers_url("metrics"))
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), 403);
+}
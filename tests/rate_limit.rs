@@ -0,0 +1,66 @@
+mod toolbox;
+
+use papers::config::{ApiKey, RateLimits, Scope};
+use papers::Config;
+use serde_json::json;
+use toolbox::*;
+
+fn one_request_per_minute() -> RateLimits {
+    RateLimits {
+        requests_per_minute: Some(1),
+        burst: Some(1),
+        max_concurrent_jobs: None,
+    }
+}
+
+#[test]
+fn test_requests_over_the_rate_limit_are_rejected() {
+    let mut test_setup_config = TestSetupConfig::default();
+    test_setup_config.set_config(Config::for_tests().with_rate_limits(one_request_per_minute()));
+    let test_setup = TestSetup::start(test_setup_config);
+
+    let submit = || {
+        test_setup
+            .client()
+            .post(&test_setup.papers_url("submit"))
+            .json(&json!({}))
+            .send()
+            .unwrap()
+    };
+
+    // 400 error code here because the posted DocumentSpec is invalid
+    assert_eq!(submit().status(), 400);
+
+    let response = submit();
+    assert_eq!(response.status(), 429);
+    assert!(response.headers().get("Retry-After").is_some());
+}
+
+#[test]
+fn test_api_keys_override_the_global_rate_limit() {
+    let mut limited = ApiKey::from_secret("limited".to_string(), "limited-secret", Scope::all());
+    limited.limits = one_request_per_minute();
+    let unlimited = ApiKey::from_secret("unlimited".to_string(), "unlimited-secret", Scope::all());
+
+    let mut test_setup_config = TestSetupConfig::default();
+    test_setup_config.set_config(Config::for_tests().with_api_keys(vec![limited, unlimited]));
+    let test_setup = TestSetup::start(test_setup_config);
+
+    let submit = |secret: &str| {
+        test_setup
+            .client()
+            .post(&test_setup.papers_url("submit"))
+            .header("Authorization", format!("Bearer {}", secret))
+            .json(&json!({}))
+            .send()
+            .unwrap()
+            .status()
+    };
+
+    assert_eq!(submit("limited-secret"), 400);
+    assert_eq!(submit("limited-secret"), 429);
+
+    for _ in 0..3 {
+        assert_eq!(submit("unlimited-secret"), 400);
+    }
+}
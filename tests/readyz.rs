@@ -0,0 +1,61 @@
+mod toolbox;
+
+use papers::Config;
+use toolbox::*;
+
+#[test]
+fn test_readyz_reports_every_check() {
+    // The S3 endpoint of the test configuration doesn't exist.
+    let mut config = TestSetupConfig::default();
+    config.set_config(Config::for_tests().with_auth("secret-string".to_string()));
+    let test_setup = TestSetup::start(config);
+
+    let mut response = test_setup
+        .client()
+        .get(&test_setup.papers_url("readyz"))
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), 503);
+
+    let body: serde_json::Value = response.json().unwrap();
+    assert_eq!(body["ready"], false);
+    assert_eq!(body["checks"]["storage"]["ok"], false);
+
+    for check in &["xelatex", "pdfunite", "convert", "temp_dir"] {
+        assert!(body["checks"][check]["ok"].is_boolean(), "{}", check);
+    }
+
+    // The details of the checks are not public.
+    assert_eq!(body["checks"]["storage"].get("error"), None);
+    assert_eq!(body["checks"]["temp_dir"].get("path"), None);
+}
+
+#[test]
+fn test_readyz_details_require_the_metrics_token() {
+    let mut config = TestSetupConfig::default();
+    config.set_config(
+        Config::for_tests()
+            .with_auth("secret-string".to_string())
+            .with_metrics_token("metrics-string".to_string()),
+    );
+    let test_setup = TestSetup::start(config);
+
+    let get = |auth_header: &str| -> serde_json::Value {
+        test_setup
+            .client()
+            .get(&test_setup.papers_url("readyz"))
+            .header("Authorization", auth_header)
+            .send()
+            .unwrap()
+            .json()
+            .unwrap()
+    };
+
+    let body = get("Bearer metrics-string");
+    assert!(body["checks"]["storage"]["error"].is_string());
+    assert!(body["checks"]["temp_dir"].get("ok").is_some());
+
+    let body = get("Bearer secret-string");
+    assert_eq!(body["checks"]["storage"].get("error"), None);
+}
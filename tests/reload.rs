@@ -0,0 +1,35 @@
+mod toolbox;
+
+use papers::Config;
+use serde_json::json;
+use toolbox::*;
+
+#[test]
+fn test_reloaded_config_applies_to_new_requests() {
+    let test_setup = TestSetup::start(TestSetupConfig::default());
+
+    let submit = || {
+        test_setup
+            .client()
+            .post(&test_setup.papers_url("submit"))
+            .json(&json!({}))
+            .send()
+            .unwrap()
+            .status()
+    };
+
+    // 400 error code here because the posted DocumentSpec is invalid
+    assert_eq!(submit(), 400);
+
+    test_setup.reload_config(Config::for_tests().with_auth("secret-string".to_string()));
+
+    let response = test_setup
+        .client()
+        .post(&test_setup.papers_url("submit"))
+        .header("Authorization", "Bearer other-string")
+        .json(&json!({}))
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), 403);
+}
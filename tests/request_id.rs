@@ -0,0 +1,39 @@
+mod toolbox;
+
+use papers::Config;
+use toolbox::*;
+
+#[test]
+fn test_request_id_is_echoed() {
+    let test_setup = TestSetup::start(TestSetupConfig::default());
+
+    let response = test_setup
+        .client()
+        .get(&test_setup.papers_url("healthz"))
+        .header("X-Request-Id", "checkout-42")
+        .send()
+        .unwrap();
+
+    assert_eq!(response.headers()["x-request-id"], "checkout-42");
+}
+
+#[test]
+fn test_request_id_is_generated_when_missing_or_invalid() {
+    let mut config = TestSetupConfig::default();
+    config.set_config(Config::for_tests().with_auth("secret-string".to_string()));
+    let test_setup = TestSetup::start(config);
+
+    let response = test_setup
+        .client()
+        .post(&test_setup.papers_url("submit"))
+        .header("Authorization", "Bearer other-string")
+        .header("X-Request-Id", "not a valid id")
+        .send()
+        .unwrap();
+
+    // Error responses have a request ID too.
+    assert_eq!(response.status(), 403);
+    let request_id = response.headers()["x-request-id"].to_str().unwrap();
+    assert_ne!(request_id, "not a valid id");
+    assert_eq!(request_id.len(), 36);
+}
@@ -0,0 +1,101 @@
+use crate::toolbox::*;
+use papers::Config;
+
+pub fn test_end_to_end() {
+    let mut test_config = TestSetupConfig::default();
+    test_config.serve_files();
+    test_config.enable_callback_server();
+    let mut test_setup = TestSetup::start(test_config);
+
+    let origin = std::path::Path::new("tests/assets").join("doc.pdf");
+    let destination = test_setup.files_dir().join("doc.pdf");
+    std::fs::copy(origin, destination).unwrap();
+
+    let split_spec = serde_json::json!({
+        "source_url": test_setup.files_server_url("doc.pdf"),
+        "callback_url": test_setup.callback_server_url("done"),
+        "split_by": { "every": 1 },
+    });
+
+    let response = test_setup
+        .client()
+        .post(&test_setup.papers_url("split"))
+        .json(&split_spec)
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    // Leave one second to the background job to finish.
+    std::thread::sleep(std::time::Duration::from_secs(1));
+
+    assert_eq!(
+        test_setup.callback_requests(),
+        vec![(http::Method::POST, "/done".to_owned())],
+    );
+
+    assert_eq!(
+        test_setup.files_requests(),
+        vec![(http::Method::GET, "/doc.pdf".to_owned())],
+    );
+}
+
+pub fn test_rejection() {
+    let mut test_config = TestSetupConfig::default();
+    test_config.enable_callback_server();
+    let test_setup = TestSetup::start(test_config);
+
+    let split_spec = serde_json::json!({
+        "source_url": "http://127.0.0.1:8733/doc.pdf",
+        "callback_url": test_setup.callback_server_url("done"),
+        "split_by": { "ranges": [] },
+    });
+
+    let response = test_setup
+        .client()
+        .post(&test_setup.papers_url("split"))
+        .json(&split_spec)
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), 422);
+}
+
+pub fn test_stalled_download_times_out() {
+    // Accepts connections but never answers.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let stalled_url = format!("http://{}/doc.pdf", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let _connections: Vec<_> = listener.incoming().collect();
+    });
+
+    let mut test_config = TestSetupConfig::default();
+    test_config.set_config(Config::for_tests().with_job_timeout(std::time::Duration::from_secs(1)));
+    test_config.enable_callback_server();
+    let mut test_setup = TestSetup::start(test_config);
+
+    let split_spec = serde_json::json!({
+        "source_url": stalled_url,
+        "callback_url": test_setup.callback_server_url("done"),
+        "split_by": { "every": 1 },
+    });
+
+    let response = test_setup
+        .client()
+        .post(&test_setup.papers_url("split"))
+        .json(&split_spec)
+        .send()
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+
+    // Leave the job one second past its deadline.
+    std::thread::sleep(std::time::Duration::from_secs(2));
+
+    let bodies = test_setup.callback_bodies();
+    assert_eq!(bodies.len(), 1);
+    assert_eq!(
+        bodies[0]["error"],
+        "Timeout: the job did not finish within 1 seconds.\n"
+    );
+}
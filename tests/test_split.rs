@@ -0,0 +1,17 @@
+mod split;
+mod toolbox;
+
+#[test]
+fn test_split() {
+    split::end_to_end::test_end_to_end();
+}
+
+#[test]
+fn test_split_rejection() {
+    split::end_to_end::test_rejection();
+}
+
+#[test]
+fn test_split_stalled_download() {
+    split::end_to_end::test_stalled_download_times_out();
+}
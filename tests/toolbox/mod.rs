@@ -3,8 +3,8 @@
 //! Private utilities for testing Papers.
 
 use futures::channel::mpsc::Sender;
+use papers::config::SharedConfig;
 use rand::{thread_rng, Rng};
-use std::sync::Arc;
 use warp::{filters::BoxedFilter, Filter};
 
 const PRIVATE_PORTS_MIN: u16 = 49_152;
@@ -24,11 +24,12 @@ pub struct FilesServer {
 pub struct CallbackServer {
     _thread: std::thread::JoinHandle<()>,
     receiver: futures::channel::mpsc::Receiver<(http::method::Method, String)>,
+    bodies: futures::channel::mpsc::Receiver<serde_json::Value>,
     port: u16,
 }
 
 struct PapersServer {
-    config: Arc<papers::Config>,
+    config: SharedConfig,
     port: u16,
     _thread: std::thread::JoinHandle<()>,
 }
@@ -55,7 +56,7 @@ impl TestSetup {
         } = config;
 
         let papers_port = random_port();
-        let config = Arc::new(config.unwrap_or_else(|| papers::Config::for_tests()));
+        let config = SharedConfig::from(config.unwrap_or_else(|| papers::Config::for_tests()));
         let config_handle = config.clone();
 
         let papers_thread_handle = std::thread::spawn(move || {
@@ -86,13 +87,24 @@ impl TestSetup {
         let callback_server = if enable_callback_server {
             let port = random_port();
             let (sender, receiver) = futures::channel::mpsc::channel(20);
+            let (body_sender, bodies) = futures::channel::mpsc::channel(20);
+            let body_sender = std::sync::Arc::new(std::sync::Mutex::new(body_sender));
             let handle = std::thread::spawn(move || {
                 let report = reporter(sender);
-                warp::serve(report().map(|| "OK")).run(([0, 0, 0, 0], port));
+                let record_body = warp::body::json().map(move |body: serde_json::Value| {
+                    body_sender
+                        .lock()
+                        .expect("acquiring sender lock")
+                        .try_send(body)
+                        .expect("sending body in test context");
+                });
+                let body = record_body.or(warp::any().map(|| ())).unify().untuple_one();
+                warp::serve(report().and(body).map(|| "OK")).run(([0, 0, 0, 0], port));
             });
             Some(CallbackServer {
                 _thread: handle,
                 receiver,
+                bodies,
                 port,
             })
         } else {
@@ -138,6 +150,11 @@ impl TestSetup {
         self.papers_server.port
     }
 
+    /// Switch the papers server over to `config`, like a configuration reload.
+    pub fn reload_config(&self, config: papers::Config) {
+        self.papers_server.config.swap(config)
+    }
+
     /// Build a URL to the papers server local to this [`TestSetup`](TestSetup).
     pub fn papers_url(&self, path: &str) -> String {
         format!("http://localhost:{}/{}", self.papers_port(), path)
@@ -177,6 +194,25 @@ impl TestSetup {
         msgs
     }
 
+    /// The JSON bodies posted to the callback server, in order.
+    pub fn callback_bodies(&mut self) -> Vec<serde_json::Value> {
+        let mut bodies = Vec::new();
+
+        while let Some(body) = self
+            .callback_server
+            .as_mut()
+            .unwrap()
+            .bodies
+            .try_next()
+            .ok()
+            .and_then(|o| o)
+        {
+            bodies.push(body);
+        }
+
+        bodies
+    }
+
     pub fn files_requests(&mut self) -> Vec<(http::Method, String)> {
         let mut msgs = Vec::new();
 
@@ -235,7 +271,7 @@ impl std::default::Default for TestSetupConfig {
 }
 
 fn reporter(sender: Sender<(http::Method, String)>) -> impl Fn() -> BoxedFilter<()> {
-    let sender = Arc::new(std::sync::Mutex::new(sender));
+    let sender = std::sync::Arc::new(std::sync::Mutex::new(sender));
     move || {
         let sender = sender.clone();
         warp::filters::method::method()